@@ -0,0 +1,94 @@
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderMap, HeaderName, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::sync::Arc;
+use tracing::debug;
+
+use crate::csrf::eq_const_time;
+
+/// Keys accepted by `require_api_key` and where requests carry them
+#[derive(Debug)]
+pub struct ApiKeys {
+    pub header: HeaderName,
+    pub keys: Vec<String>,
+    /// Paths served without a key, e.g. the health check
+    pub exempt: Vec<String>,
+}
+
+impl ApiKeys {
+    fn accepts(&self, headers: &HeaderMap) -> bool {
+        let Some(given) = headers.get(&self.header).and_then(|v| v.to_str().ok()) else {
+            return false;
+        };
+        // Compare with every key, so timing doesn't reveal which one matched
+        self.keys
+            .iter()
+            .fold(false, |found, key| eq_const_time(key, given) | found)
+    }
+}
+
+/// Respond 401 to requests without a valid API key. CORS preflights and the
+/// exempt paths pass through, as clients can't attach keys to them.
+pub async fn require_api_key(
+    State(keys): State<Arc<ApiKeys>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let preflight = request.method() == Method::OPTIONS
+        && request.headers().contains_key(header::ACCESS_CONTROL_REQUEST_METHOD);
+    let exempt = keys.exempt.iter().any(|path| path == request.uri().path());
+    if preflight || exempt || keys.accepts(request.headers()) {
+        return next.run(request).await;
+    }
+    debug!("No valid API key for {} {}", request.method(), request.uri().path());
+    (StatusCode::UNAUTHORIZED, "Unauthorized").into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, middleware, routing::get, Router};
+    use tower::ServiceExt;
+
+    fn app() -> Router {
+        let keys = ApiKeys {
+            header: HeaderName::from_static("x-api-key"),
+            keys: vec!["first".to_string(), "second".to_string()],
+            exempt: vec!["/health".to_string()],
+        };
+        Router::new()
+            .route("/", get(|| async { "ok" }).options(|| async { "preflight" }))
+            .route("/health", get(|| async { "ok" }))
+            .layer(middleware::from_fn_with_state(Arc::new(keys), require_api_key))
+    }
+
+    async fn status(request: axum::http::request::Builder) -> StatusCode {
+        let request = request.body(Body::empty()).unwrap();
+        app().oneshot(request).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn test_api_key_required() {
+        let get = || Request::builder().uri("/");
+        assert_eq!(status(get()).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(status(get().header("x-api-key", "wrong")).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(status(get().header("x-api-key", "first")).await, StatusCode::OK);
+        assert_eq!(status(get().header("x-api-key", "second")).await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_health_and_preflight_are_exempt() {
+        assert_eq!(status(Request::builder().uri("/health")).await, StatusCode::OK);
+
+        let preflight = Request::builder()
+            .method("OPTIONS")
+            .uri("/")
+            .header("access-control-request-method", "POST");
+        assert_eq!(status(preflight).await, StatusCode::OK);
+        let options = Request::builder().method("OPTIONS").uri("/");
+        assert_eq!(status(options).await, StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,74 @@
+use std::{
+    fs::OpenOptions,
+    io::{self, Write},
+    path::{Component, Path, PathBuf},
+};
+use tokio::process::Command;
+
+/// Root of the unified (v2) cgroup hierarchy
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// Resolve a cgroup name like "sherut/jobs" to its `cgroup.procs` file
+pub fn procs_path(name: &str) -> Result<PathBuf, String> {
+    let relative = Path::new(name.trim_matches('/'));
+    let is_plain = relative
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)));
+    if name.trim_matches('/').is_empty() || !is_plain {
+        return Err(format!("invalid cgroup name '{}'", name));
+    }
+    Ok(Path::new(CGROUP_ROOT).join(relative).join("cgroup.procs"))
+}
+
+/// Move the spawned child into the cgroup owning `procs` before it execs.
+/// The file is opened up front so the child only has to write to it.
+pub fn attach(cmd: &mut Command, procs: &Path) -> io::Result<()> {
+    let file = OpenOptions::new().write(true).open(procs)?;
+    // SAFETY: the hook only issues a write(2) on an already open descriptor,
+    // which is async-signal-safe. Writing "0" moves the calling process.
+    unsafe {
+        cmd.pre_exec(move || (&file).write_all(b"0"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::{run, shell_command};
+    use crate::shell::ShellType;
+
+    #[test]
+    fn test_procs_path() {
+        assert_eq!(
+            procs_path("sherut/jobs"),
+            Ok(PathBuf::from("/sys/fs/cgroup/sherut/jobs/cgroup.procs"))
+        );
+        assert_eq!(
+            procs_path("/sherut/"),
+            Ok(PathBuf::from("/sys/fs/cgroup/sherut/cgroup.procs"))
+        );
+    }
+
+    #[test]
+    fn test_procs_path_rejects_escapes() {
+        assert!(procs_path("../etc").is_err());
+        assert!(procs_path("sherut/../../etc").is_err());
+        assert!(procs_path("").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_attach_writes_to_procs_before_exec() {
+        let procs = std::env::temp_dir().join(format!("sherut_cgroup_{}", std::process::id()));
+        std::fs::write(&procs, "").unwrap();
+
+        let mut cmd = shell_command(&ShellType::Sh, "true");
+        attach(&mut cmd, &procs).unwrap();
+        let output = run(&mut cmd, b"").await.unwrap();
+
+        let written = std::fs::read_to_string(&procs).unwrap();
+        let _ = std::fs::remove_file(&procs);
+        assert!(output.status.success());
+        assert_eq!(written, "0");
+    }
+}
@@ -0,0 +1,69 @@
+use axum::http::HeaderMap;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use md5::Md5;
+use sha2::{Digest, Sha256};
+
+/// Base64 MD5 digest of the body (RFC 1864)
+pub const CONTENT_MD5: &str = "content-md5";
+/// SHA-256 digest of the body, hex or base64
+pub const CHECKSUM_SHA256: &str = "x-checksum-sha256";
+
+/// Check the body against any checksum headers the client sent
+pub fn verify(headers: &HeaderMap, body: &[u8]) -> Result<(), String> {
+    if let Some(expected) = header_str(headers, CONTENT_MD5)? {
+        let actual = Md5::digest(body);
+        if BASE64.encode(actual) != expected {
+            return Err("Content-MD5 does not match the body".to_string());
+        }
+    }
+    if let Some(expected) = header_str(headers, CHECKSUM_SHA256)? {
+        let actual = Sha256::digest(body);
+        if !expected.eq_ignore_ascii_case(&hex(&actual)) && BASE64.encode(actual) != expected {
+            return Err("X-Checksum-SHA256 does not match the body".to_string());
+        }
+    }
+    Ok(())
+}
+
+fn header_str<'a>(headers: &'a HeaderMap, name: &str) -> Result<Option<&'a str>, String> {
+    headers
+        .get(name)
+        .map(|v| v.to_str().map(str::trim).map_err(|_| format!("Invalid {} header", name)))
+        .transpose()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(name: &'static str, value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, value.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_verify_content_md5() {
+        let headers = headers(CONTENT_MD5, "XUFAKrxLKna5cZ2REBfFkg==");
+        assert!(verify(&headers, b"hello").is_ok());
+        assert!(verify(&headers, b"hellO").is_err());
+    }
+
+    #[test]
+    fn test_verify_sha256_hex_and_base64() {
+        let hex = "2CF24DBA5FB0A30E26E83B2AC5B9E29E1B161E5C1FA7425E73043362938B9824";
+        assert!(verify(&headers(CHECKSUM_SHA256, hex), b"hello").is_ok());
+        let base64 = "LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=";
+        assert!(verify(&headers(CHECKSUM_SHA256, base64), b"hello").is_ok());
+        assert!(verify(&headers(CHECKSUM_SHA256, hex), b"bye").is_err());
+    }
+
+    #[test]
+    fn test_verify_without_headers() {
+        assert!(verify(&HeaderMap::new(), b"anything").is_ok());
+    }
+}
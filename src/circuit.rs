@@ -0,0 +1,183 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Circuit {
+    /// Commands run normally; counts consecutive failures
+    Closed(u32),
+    /// Commands are short-circuited until the instant passes
+    Open(Instant),
+    /// One trial command is running to test recovery
+    HalfOpen,
+}
+
+/// Per-route circuit breaker: after `threshold` consecutive command failures a
+/// route is rejected for `cooldown`, then a single trial request decides
+/// whether it closes again
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    pub threshold: u32,
+    pub cooldown: Duration,
+    circuits: Mutex<HashMap<String, Circuit>>,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        CircuitBreaker {
+            threshold,
+            cooldown,
+            circuits: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Permit for a request to `route` to run its command, or None while the
+    /// circuit is open
+    pub fn permit(self: &Arc<Self>, route: &str) -> Option<Permit> {
+        self.allow(route).then(|| Permit {
+            breaker: self.clone(),
+            route: route.to_string(),
+            recorded: false,
+        })
+    }
+
+    /// Whether a request to `route` may run its command
+    fn allow(&self, route: &str) -> bool {
+        let mut circuits = self.circuits.lock().unwrap();
+        match circuits.get(route).copied() {
+            None | Some(Circuit::Closed(_)) => true,
+            Some(Circuit::Open(until)) if Instant::now() >= until => {
+                circuits.insert(route.to_string(), Circuit::HalfOpen);
+                true
+            }
+            Some(Circuit::Open(_)) | Some(Circuit::HalfOpen) => false,
+        }
+    }
+
+    /// Record the outcome of a command run for `route`
+    pub fn record(&self, route: &str, success: bool) {
+        let mut circuits = self.circuits.lock().unwrap();
+        let next = match (circuits.get(route).copied(), success) {
+            (_, true) => Circuit::Closed(0),
+            (Some(Circuit::HalfOpen), false) => Circuit::Open(Instant::now() + self.cooldown),
+            (Some(Circuit::Closed(failures)), false) if failures + 1 < self.threshold => {
+                Circuit::Closed(failures + 1)
+            }
+            (None, false) if self.threshold > 1 => Circuit::Closed(1),
+            (_, false) => Circuit::Open(Instant::now() + self.cooldown),
+        };
+        circuits.insert(route.to_string(), next);
+    }
+
+    /// Let another trial through when the one running for `route` ended without an outcome
+    fn abandon(&self, route: &str) {
+        let mut circuits = self.circuits.lock().unwrap();
+        if let Some(circuit @ Circuit::HalfOpen) = circuits.get_mut(route) {
+            *circuit = Circuit::Open(Instant::now());
+        }
+    }
+}
+
+/// A request's go-ahead to run a route's command. Dropping it without calling
+/// `record`, e.g. when the request is rejected or the client leaves, gives up
+/// the route's trial instead of leaving the circuit half-open for good.
+#[derive(Debug)]
+pub struct Permit {
+    breaker: Arc<CircuitBreaker>,
+    route: String,
+    recorded: bool,
+}
+
+impl Permit {
+    /// Record the outcome of the command run under this permit
+    pub fn record(mut self, success: bool) {
+        self.breaker.record(&self.route, success);
+        self.recorded = true;
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if !self.recorded {
+            self.breaker.abandon(&self.route);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_opens_after_threshold_failures() {
+        let breaker = CircuitBreaker::new(3, Duration::from_secs(60));
+        for _ in 0..2 {
+            assert!(breaker.allow("GET /a"));
+            breaker.record("GET /a", false);
+        }
+        assert!(breaker.allow("GET /a"));
+        breaker.record("GET /a", false);
+        assert!(!breaker.allow("GET /a"));
+        // Other routes are unaffected
+        assert!(breaker.allow("GET /b"));
+    }
+
+    #[test]
+    fn test_success_resets_failure_count() {
+        let breaker = CircuitBreaker::new(2, Duration::from_secs(60));
+        breaker.record("GET /a", false);
+        breaker.record("GET /a", true);
+        breaker.record("GET /a", false);
+        assert!(breaker.allow("GET /a"));
+    }
+
+    #[test]
+    fn test_half_open_after_cooldown() {
+        let breaker = CircuitBreaker::new(1, Duration::from_millis(20));
+        breaker.record("GET /a", false);
+        assert!(!breaker.allow("GET /a"));
+
+        std::thread::sleep(Duration::from_millis(30));
+        // A single trial is let through while it runs
+        assert!(breaker.allow("GET /a"));
+        assert!(!breaker.allow("GET /a"));
+
+        breaker.record("GET /a", true);
+        assert!(breaker.allow("GET /a"));
+    }
+
+    #[test]
+    fn test_failed_trial_reopens() {
+        let breaker = CircuitBreaker::new(1, Duration::from_millis(20));
+        breaker.record("GET /a", false);
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(breaker.allow("GET /a"));
+        breaker.record("GET /a", false);
+        assert!(!breaker.allow("GET /a"));
+    }
+
+    #[test]
+    fn test_dropped_trial_lets_another_through() {
+        let breaker = Arc::new(CircuitBreaker::new(1, Duration::from_millis(20)));
+        breaker.record("GET /a", false);
+        std::thread::sleep(Duration::from_millis(30));
+
+        let trial = breaker.permit("GET /a").unwrap();
+        assert!(breaker.permit("GET /a").is_none());
+        drop(trial);
+        let trial = breaker.permit("GET /a").unwrap();
+        trial.record(true);
+        assert!(breaker.permit("GET /a").is_some());
+    }
+
+    #[test]
+    fn test_dropped_permit_keeps_closed_circuit() {
+        let breaker = Arc::new(CircuitBreaker::new(2, Duration::from_secs(60)));
+        breaker.permit("GET /a").unwrap().record(false);
+        drop(breaker.permit("GET /a"));
+        breaker.permit("GET /a").unwrap().record(false);
+        assert!(breaker.permit("GET /a").is_none());
+    }
+}
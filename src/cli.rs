@@ -1,25 +1,103 @@
-use clap::{Parser, ValueEnum};
+use axum::http::HeaderName;
+use clap::{ArgGroup, Parser, ValueEnum};
+use std::{net::IpAddr, path::PathBuf};
+use tracing::Level;
 
-use crate::shell::{HeaderFormat, ShellType};
+use crate::runner::BodyFormat;
+use crate::scheduler::{Overload, Scheduling};
+use crate::shell::{HeaderFormat, ParamMode, ShellType};
+use crate::webhook::WebhookOn;
 
-#[derive(Clone, Debug, ValueEnum)]
+#[derive(Clone, Copy, Debug, Default, ValueEnum, PartialEq)]
 pub enum LogLevel {
     Error,
     Warn,
+    #[default]
     Info,
     Debug,
     Trace,
 }
 
+impl LogLevel {
+    pub fn as_level(&self) -> Level {
+        match self {
+            LogLevel::Error => Level::ERROR,
+            LogLevel::Warn => Level::WARN,
+            LogLevel::Info => Level::INFO,
+            LogLevel::Debug => Level::DEBUG,
+            LogLevel::Trace => Level::TRACE,
+        }
+    }
+}
+
+/// Format of the log lines written to stdout
+#[derive(Clone, Copy, Debug, Default, ValueEnum, PartialEq)]
+pub enum LogFormat {
+    /// Human-readable lines
+    #[default]
+    Text,
+    /// One JSON object per line, for log aggregators
+    Json,
+}
+
+/// What happens to requests with more than --max-query-params query parameters
+#[derive(Clone, Copy, Debug, Default, ValueEnum, PartialEq)]
+pub enum QueryOverflow {
+    /// Respond 400
+    #[default]
+    Reject,
+    /// Drop the parameters past the limit
+    Truncate,
+}
+
+/// Format of the readiness event printed to stdout once listening
+#[derive(Clone, Copy, Debug, ValueEnum, PartialEq)]
+pub enum StartupEvent {
+    /// A single JSON line, e.g. {"event":"listening","addr":"0.0.0.0:8080","routes":3}
+    Json,
+}
+
 #[derive(Parser, Debug)]
 #[command(author, version, about = "Turn any shell command into an API")]
+#[command(group(
+    ArgGroup::new("concurrency_limits")
+        .args(["max_concurrency", "shell_concurrency"])
+        .multiple(true)
+))]
 pub struct Args {
+    /// Address to listen on, e.g. 0.0.0.0 for all interfaces or :: for IPv6
+    /// (plus IPv4 unless --ipv6-only). Local connections only by default.
+    #[arg(long, default_value = "127.0.0.1")]
+    pub host: IpAddr,
+
+    /// Only accept IPv6 clients when --host is an IPv6 address
+    #[arg(long)]
+    pub ipv6_only: bool,
+
+    /// Port to listen on; 0 lets the OS pick a free one
     #[arg(long, default_value_t = 8080)]
     pub port: u16,
 
+    /// Write the bound port to this file once listening (useful with --port 0)
+    #[arg(long, value_name = "PATH")]
+    pub port_file: Option<PathBuf>,
+
+    /// Serve Prometheus metrics on /metrics at this port, on a listener separate
+    /// from the command routes
+    #[arg(long, value_name = "PORT")]
+    pub metrics_port: Option<u16>,
+
+    /// Print a readiness event to stdout once listening, for supervisors parsing stdout
+    #[arg(long, value_enum)]
+    pub startup_event: Option<StartupEvent>,
+
     #[arg(long, value_enum, default_value_t = LogLevel::Info)]
     pub log_level: LogLevel,
 
+    /// Format of log lines; `json` also puts the access log's fields into the object
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    pub log_format: LogFormat,
+
     /// Shell to use for executing commands (auto-detected from $SHELL if not set)
     #[arg(long, value_enum)]
     pub shell: Option<ShellType>,
@@ -36,8 +114,555 @@ pub struct Args {
     #[arg(long, value_enum)]
     pub query_format: Option<HeaderFormat>,
 
+    /// Format for passing request cookies to commands
+    /// 'assoc' uses a COOKIES associative array (bash/zsh only)
+    /// 'json' exports COOKIES_JSON environment variable
+    #[arg(long, value_enum)]
+    pub cookie_format: Option<HeaderFormat>,
+
+    /// How commands receive the request body: on stdin, as REQUEST_BODY
+    /// (REQUEST_BODY_BASE64 when not UTF-8), or both
+    #[arg(long, value_enum, default_value_t = BodyFormat::Stdin)]
+    pub body_format: BodyFormat,
+
+    /// Largest body in bytes set as REQUEST_BODY; larger ones are left out of the environment
+    #[arg(long, value_name = "BYTES", default_value_t = 128 * 1024)]
+    pub max_body_env: usize,
+
+    /// Export a request cookie's value as an environment variable,
+    /// e.g. --cookie-env session=SESSION_ID; unset when the cookie is missing
+    #[arg(long, value_name = "COOKIE=VAR")]
+    pub cookie_env: Vec<String>,
+
+    /// How `:name` placeholders receive param values: 'substitute' inserts the escaped
+    /// value into the command, 'env' and 'positional' insert "$PARAM_name" / "$1"
+    /// references so values are never parsed as shell code
+    #[arg(long, value_enum, default_value_t = ParamMode::Substitute)]
+    pub param_mode: ParamMode,
+
+    /// Let requests pick one of these shells with an X-Shell header, e.g.
+    /// --allow-shell-header bash,fish. Only for trusted clients; others are ignored.
+    #[arg(long, value_enum, value_name = "SHELLS", value_delimiter = ',')]
+    pub allow_shell_header: Vec<ShellType>,
+
+    /// Return the rendered script of each request, base64-encoded, in an X-Debug-Script
+    /// header. Needs --log-level debug or trace and a loopback --host
+    #[arg(long)]
+    pub debug_echo_script: bool,
+
+    /// Answer OPTIONS requests with 204 and an Allow header listing the
+    /// registered methods, unless an explicit OPTIONS route exists
+    #[arg(long)]
+    pub auto_options: bool,
+
+    /// Hide hop-by-hop headers (Connection, Keep-Alive, Transfer-Encoding, ...
+    /// and any named in Connection) from commands
+    #[arg(long = "strip-hop-by-hop")]
+    pub strip_hop_by_hop: bool,
+
+    /// HTML page to return for a status code, e.g. --error-page 404=./404.html 500=./500.html
+    /// Used when the response body is empty and for unmatched routes
+    #[arg(long = "error-page", value_name = "STATUS=FILE", num_args = 1..)]
+    pub error_pages: Vec<String>,
+
+    /// Validate a path or query param with a built-in check (uuid, email, int, alnum),
+    /// e.g. --validate id:uuid; failing requests get 400
+    #[arg(long = "validate", value_name = "PARAM:TYPE")]
+    pub validators: Vec<String>,
+
+    /// Return the error page even when the command produced a body
+    #[arg(long)]
+    pub error_page_always: bool,
+
+    /// Request body content types accepted by every route (comma-separated);
+    /// others are rejected with 415. Supports `type/*` wildcards.
+    #[arg(long, value_delimiter = ',')]
+    pub allowed_content_types: Vec<String>,
+
+    /// Headers commands may set with `@header:` (comma-separated, case-insensitive);
+    /// others are dropped with a warning
+    #[arg(long, value_delimiter = ',')]
+    pub allowed_response_headers: Vec<String>,
+
+    /// Headers commands may not set with `@header:`, e.g. Strict-Transport-Security
+    #[arg(long, value_delimiter = ',')]
+    pub blocked_response_headers: Vec<String>,
+
+    /// Response Content-Type per request method when the command doesn't set one,
+    /// used instead of auto-detection, e.g. POST=application/json,PUT=application/json
+    #[arg(long, value_name = "METHOD=TYPE", value_delimiter = ',')]
+    pub default_content_type_by_method: Vec<String>,
+
+    /// Insert command output into --route-wrap-html templates without HTML-escaping
+    #[arg(long)]
+    pub no_escape: bool,
+
+    /// Reject requests whose URI is longer than this many bytes with 414 (0 disables)
+    #[arg(long, default_value_t = 8192)]
+    pub max_uri_length: usize,
+
+    /// Most query parameters a request may carry; see --on-too-many-query-params
+    #[arg(long, value_name = "N")]
+    pub max_query_params: Option<usize>,
+
+    /// Reject requests over --max-query-params with 400, or truncate their query
+    #[arg(long, value_enum, default_value_t = QueryOverflow::Reject, requires = "max_query_params")]
+    pub on_too_many_query_params: QueryOverflow,
+
+    /// Honor at most this many `@header:` lines per response; later ones are ignored
+    #[arg(long, value_name = "N")]
+    pub max_response_headers: Option<usize>,
+
+    /// Reject request bodies larger than this many bytes with 413 (0 disables the limit)
+    #[arg(long, default_value_t = 2 * 1024 * 1024)]
+    pub max_body_size: usize,
+
+    /// Respond 400 when the body doesn't match its Content-MD5 or X-Checksum-SHA256 header
+    #[arg(long)]
+    pub verify_checksum: bool,
+
+    /// Log a warning when a command exits 0 without output and the response is an empty 200
+    #[arg(long)]
+    pub warn_empty_response: bool,
+
+    /// Add a Server-Timing header with queue, spawn, exec and body durations
+    #[arg(long)]
+    pub server_timing: bool,
+
+    /// Respond 403 to state-changing requests unless their X-CSRF-Token header
+    /// matches the --csrf-cookie cookie (double-submit)
+    #[arg(long)]
+    pub csrf: bool,
+
+    /// Cookie holding the CSRF token checked by --csrf
+    #[arg(long, default_value = "csrf_token")]
+    pub csrf_cookie: String,
+
+    /// Respond 401 to requests that don't carry this key in --api-key-header;
+    /// repeatable. The health check and CORS preflights need no key.
+    #[arg(long = "api-key", value_name = "KEY")]
+    pub api_keys: Vec<String>,
+
+    /// Header carrying the key checked by --api-key
+    #[arg(long, default_value = "x-api-key", requires = "api_keys")]
+    pub api_key_header: HeaderName,
+
+    /// Require this header to carry a nonce; 400 when missing, 409 when it was
+    /// already used within --nonce-window-secs (replay protection)
+    #[arg(long, value_name = "HEADER")]
+    pub nonce_header: Option<String>,
+
+    /// How long used nonces are remembered
+    #[arg(long, default_value_t = 300, requires = "nonce_header")]
+    pub nonce_window_secs: u64,
+
+    /// Allow cross-origin requests from this origin (`*` for any); repeatable.
+    /// Preflight requests are answered without running a command
+    #[arg(long = "cors-origin", value_name = "ORIGIN")]
+    pub cors_origins: Vec<String>,
+
+    /// Host header values requests must carry (comma-separated, port ignored), e.g.
+    /// --allowed-hosts example.com,api.example.com; others get 421. `*` allows any host
+    #[arg(long, value_name = "HOSTS", value_delimiter = ',')]
+    pub allowed_hosts: Vec<String>,
+
+    /// Start in maintenance mode, where command routes answer 503 while the health
+    /// check stays up. SIGUSR1 toggles it at runtime
+    #[arg(long)]
+    pub maintenance: bool,
+
+    /// Body of the 503 responses in maintenance mode
+    #[arg(long, default_value = "Down for maintenance")]
+    pub maintenance_body: String,
+
+    /// POST a JSON summary of each request (route, status, duration, exit code) here
+    #[arg(long, value_name = "URL")]
+    pub webhook_url: Option<String>,
+
+    /// Which responses --webhook-url is called for
+    #[arg(long, value_enum, default_value_t = WebhookOn::All, requires = "webhook_url")]
+    pub webhook_on: WebhookOn,
+
+    /// Methods allowed cross-origin (default GET,HEAD,POST,PUT,PATCH,DELETE)
+    #[arg(long, value_delimiter = ',', requires = "cors_origins")]
+    pub cors_methods: Vec<String>,
+
+    /// Serve HTTPS with this PEM certificate (chain); requires --tls-key
+    #[arg(long, value_name = "FILE", requires = "tls_key", conflicts_with = "max_connections")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM private key for --tls-cert
+    #[arg(long, value_name = "FILE", requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+
+    /// Take REQUEST_SCHEME from X-Forwarded-Proto, for servers behind a TLS-terminating proxy
+    #[arg(long)]
+    pub trust_proxy: bool,
+
+    /// Maximum number of simultaneously open client connections; extra ones wait to be accepted
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub max_connections: Option<u32>,
+
+    /// Shut down gracefully after this many seconds without requests (for scale-to-zero setups)
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub idle_shutdown_secs: Option<u64>,
+
+    /// Place every command into this pre-created cgroup (v2), e.g. "sherut/jobs"
+    /// for /sys/fs/cgroup/sherut/jobs. sherut needs write access to its cgroup.procs.
+    #[cfg(all(target_os = "linux", feature = "cgroup"))]
+    #[arg(long)]
+    pub cgroup: Option<String>,
+
+    /// Pipe request bodies through this command first; its stdout becomes the route
+    /// command's stdin and a non-zero exit responds 400
+    #[arg(long, value_name = "COMMAND")]
+    pub request_filter: Option<String>,
+
+    /// Kill commands that run longer than this many milliseconds and answer 504.
+    /// Commands see REQUEST_TIMEOUT_MS and REQUEST_DEADLINE_MS (epoch millis).
+    #[arg(long)]
+    pub command_timeout_ms: Option<u64>,
+
+    /// Maximum number of commands running at once; further requests wait for a slot
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub max_concurrency: Option<u32>,
+
+    /// Maximum number of commands running at once in a shell, e.g. --shell-concurrency zsh=4
+    #[arg(long = "shell-concurrency", value_name = "SHELL=N")]
+    pub shell_concurrency: Vec<String>,
+
+    /// Respond 503 with Retry-After to requests that waited this many milliseconds
+    /// for a --max-concurrency or --shell-concurrency slot
+    #[arg(long, requires = "concurrency_limits")]
+    pub queue_timeout_ms: Option<u64>,
+
+    /// Order in which queued requests get a --max-concurrency or --shell-concurrency slot
+    #[arg(long, value_enum, default_value_t = Scheduling::Fifo, requires = "concurrency_limits")]
+    pub scheduling: Scheduling,
+
+    /// What requests do when all --max-concurrency or --shell-concurrency slots are taken:
+    /// wait in the queue, or get 503 with Retry-After right away
+    #[arg(long, value_enum, default_value_t = Overload::Queue, requires = "concurrency_limits")]
+    pub on_overload: Overload,
+
+    /// Signals (names or numbers) that don't count as failure when the command
+    /// produced output, e.g. PIPE for `producer | head`
+    #[arg(long, value_delimiter = ',', value_parser = parse_signal)]
+    pub success_signals: Vec<i32>,
+
+    /// Status for successful commands that print nothing (and no @status:), e.g. 204
+    #[arg(long, value_name = "STATUS", value_parser = clap::value_parser!(u16).range(100..=599))]
+    pub empty_status: Option<u16>,
+
+    /// Treat whitespace-only output as empty, responding 204 (or --empty-status)
+    #[arg(long)]
+    pub treat_whitespace_as_empty: bool,
+
+    /// Respond to failures with exit codes 1-99 using status BASE + code, e.g. with 400
+    /// exit 4 responds 404
+    #[arg(long, value_name = "BASE", value_parser = clap::value_parser!(u16).range(100..=500))]
+    pub status_from_exit: Option<u16>,
+
+    /// Stop running a route's command after this many consecutive failures,
+    /// answering 503 until the cooldown passes and a trial request succeeds
+    #[arg(long, value_name = "FAILURES", value_parser = clap::value_parser!(u32).range(1..))]
+    pub circuit_breaker: Option<u32>,
+
+    /// Seconds an open circuit rejects requests before a trial request is let through
+    #[arg(long, default_value_t = 30)]
+    pub circuit_cooldown_secs: u64,
+
+    /// Command backing /healthz; exit code 0 responds 200, anything else 503
+    #[arg(long)]
+    pub health_command: Option<String>,
+
+    /// Probe a dependency in the background, e.g. --probe "db:pg_isready:10s";
+    /// the health check responds 200 only while every probe passes
+    #[arg(long = "probe", value_name = "NAME:COMMAND:INTERVAL")]
+    pub probes: Vec<String>,
+
+    /// Serve a built-in health check on this path (instead of /healthz), answering
+    /// 200 `ok` without spawning a shell unless --health-command is set
+    #[arg(long, value_name = "PATH", value_parser = parse_health_path)]
+    pub health_path: Option<String>,
+
+    /// How long (in milliseconds) a health command result is reused
+    #[arg(long, default_value_t = 1000)]
+    pub health_cache_ms: u64,
+
+    /// Run route commands as this user via `sudo -n -u USER`; sudo must allow it
+    /// without a password and let the environment through (SETENV)
+    #[arg(long, value_name = "USER")]
+    pub sudo_user: Option<String>,
+
+    /// Setup command run once before binding; startup aborts if it fails
+    #[arg(long, value_name = "COMMAND")]
+    pub preflight: Option<String>,
+
     #[arg(long = "route", value_names = ["PATH", "COMMAND"], num_args = 2)]
     pub routes: Vec<String>,
+
+    /// Register a route per script in this directory, e.g. ./api/users/[id]/get.sh
+    /// serves GET /users/:id
+    #[arg(long, value_name = "DIR")]
+    pub scripts_dir: Option<PathBuf>,
+
+    /// Read more routes from this file, one `[METHOD] PATH COMMAND` per line
+    #[arg(long, value_name = "FILE")]
+    pub routes_file: Option<PathBuf>,
+
+    /// Reload --routes-file when it changes, keeping the old routes if it fails to parse
+    #[arg(long, requires = "routes_file")]
+    pub watch: bool,
+
+    /// Directory commands run in, instead of sherut's own working directory
+    #[arg(long, value_name = "DIR")]
+    pub workdir: Option<PathBuf>,
+
+    /// Run a route's command in this directory (relative ones resolve against --workdir),
+    /// e.g. --route-workdir "POST /build" ./frontend
+    #[arg(long = "route-workdir", value_names = ["ROUTE", "DIR"], num_args = 2)]
+    pub route_workdirs: Vec<String>,
+
+    /// Override the log level of a single route, e.g. --route-log-level "GET /noisy" debug
+    #[arg(long = "route-log-level", value_names = ["ROUTE", "LEVEL"], num_args = 2)]
+    pub route_log_levels: Vec<String>,
+
+    /// Request body content types a route accepts (comma-separated), overriding
+    /// --allowed-content-types, e.g. --route-consumes "POST /upload" "image/png,image/jpeg"
+    #[arg(long = "route-consumes", value_names = ["ROUTE", "TYPES"], num_args = 2)]
+    pub route_consumes: Vec<String>,
+
+    /// Location header template for 201 responses that don't set one; `:name` is filled
+    /// from route params and `@location: name=value` lines,
+    /// e.g. --route-created-location "POST /items" "/items/:id"
+    #[arg(long = "route-created-location", value_names = ["ROUTE", "TEMPLATE"], num_args = 2)]
+    pub route_created_locations: Vec<String>,
+
+    /// Wrap a route's output in an HTML template, replacing its `{{body}}` placeholder,
+    /// e.g. --route-wrap-html "GET /status" ./template.html
+    #[arg(long = "route-wrap-html", value_names = ["ROUTE", "FILE"], num_args = 2)]
+    pub route_wrap_html: Vec<String>,
+
+    /// Encoding of the request body on a route's stdin: raw (default), base64 or hex,
+    /// e.g. --route-stdin-encoding "POST /upload" base64
+    #[arg(long = "route-stdin-encoding", value_names = ["ROUTE", "ENCODING"], num_args = 2)]
+    pub route_stdin_encodings: Vec<String>,
+
+    /// Send a route's text output in this charset instead of UTF-8, e.g.
+    /// --route-charset "GET /legacy" iso-8859-1; it's added to the Content-Type
+    #[arg(long = "route-charset", value_names = ["ROUTE", "CHARSET"], num_args = 2)]
+    pub route_charsets: Vec<String>,
+
+    /// Respond with a route's stdout lines as a JSON string array, skipping magic prefixes
+    #[arg(long = "route-split-lines-to-json", value_name = "ROUTE")]
+    pub route_split_lines_to_json: Vec<String>,
+
+    /// Split a route's stdout into a JSON array on this delimiter instead of newlines,
+    /// e.g. --route-split-delimiter "GET /ids" ","
+    #[arg(long = "route-split-delimiter", value_names = ["ROUTE", "DELIMITER"], num_args = 2)]
+    pub route_split_delimiters: Vec<String>,
+
+    /// Reject requests to this route with 400 when the body is empty
+    #[arg(long = "route-require-body", value_name = "ROUTE")]
+    pub route_require_body: Vec<String>,
+
+    /// Reject requests to this route with 400 when a body is present
+    #[arg(long = "route-forbid-body", value_name = "ROUTE")]
+    pub route_forbid_body: Vec<String>,
+
+    /// Add a response header rendered from `:param`, `:query.name` and `:header.name`
+    /// placeholders, e.g. --route-response-header "GET /items" "X-Count: :query.n"
+    #[arg(long = "route-response-header", value_names = ["ROUTE", "HEADER"], num_args = 2)]
+    pub route_response_headers: Vec<String>,
+
+    /// Respond with a route's output lines as server-sent events (text/event-stream)
+    #[arg(long = "route-as-sse", value_name = "ROUTE")]
+    pub route_as_sse: Vec<String>,
+
+    /// Transform a route's JSON output with a jq expression, e.g. --route-jq "GET /users" ".data"
+    #[arg(long = "route-jq", value_names = ["ROUTE", "EXPRESSION"], num_args = 2)]
+    pub route_jq: Vec<String>,
+
+    /// Exit codes that count as success for a route instead of only 0,
+    /// e.g. --route-success-codes "GET /diff" 0,1
+    #[arg(long = "route-success-codes", value_names = ["ROUTE", "CODES"], num_args = 2)]
+    pub route_success_codes: Vec<String>,
+
+    /// Only register a route when an environment variable is set (non-empty) or has
+    /// a value, e.g. --route-enabled-if "GET /beta" FEATURE_X=1
+    #[arg(long = "route-enabled-if", value_names = ["ROUTE", "CONDITION"], num_args = 2)]
+    pub route_enabled_if: Vec<String>,
+
+    /// Body size limit in bytes for a route, overriding --max-body-size,
+    /// e.g. --route-max-body-size "POST /upload" 104857600
+    #[arg(long = "route-max-body-size", value_names = ["ROUTE", "BYTES"], num_args = 2)]
+    pub route_max_body_sizes: Vec<String>,
+
+    /// Mark a route as deprecated: its responses carry `Deprecation: true` and calls are logged
+    #[arg(long = "route-deprecated", value_name = "ROUTE")]
+    pub route_deprecated: Vec<String>,
+
+    /// Sunset date (HTTP date) announced in a deprecated route's Sunset header,
+    /// e.g. --route-sunset "GET /old" "Wed, 31 Dec 2025 23:59:59 GMT"
+    #[arg(long = "route-sunset", value_names = ["ROUTE", "DATE"], num_args = 2)]
+    pub route_sunsets: Vec<String>,
+
+    /// Only run a route's command when a header or query param is present or has a value,
+    /// e.g. --route-when "GET /feature" "header:X-Beta=true" or "query:debug"
+    #[arg(long = "route-when", value_names = ["ROUTE", "CONDITION"], num_args = 2)]
+    pub route_when: Vec<String>,
+
+    /// Command run when a route's --route-when condition doesn't hold (default: 503)
+    #[arg(long = "route-otherwise", value_names = ["ROUTE", "COMMAND"], num_args = 2)]
+    pub route_otherwise: Vec<String>,
+
+    /// Limit how often a route's command runs, e.g. --route-rate-limit "POST /jobs" 10/m
+    /// (units s, m, h). Bursts up to the limit are allowed.
+    #[arg(long = "route-rate-limit", value_names = ["ROUTE", "RATE"], num_args = 2)]
+    pub route_rate_limits: Vec<String>,
+
+    /// Status for requests over a --route-rate-limit
+    #[arg(long, default_value_t = 429, value_parser = clap::value_parser!(u16).range(100..=599))]
+    pub rate_limit_status: u16,
+
+    /// Body for requests over a --route-rate-limit
+    #[arg(long, default_value = "Too Many Requests")]
+    pub rate_limit_body: String,
+
+    /// Command generating the body for requests over a --route-rate-limit, with
+    /// MATCHED_ROUTE, REQUEST_PATH and RETRY_AFTER (seconds) in its environment
+    #[arg(long, value_name = "COMMAND")]
+    pub rate_limit_command: Option<String>,
+
+    /// Answer a route with a fixed body without running a command, e.g.
+    /// --static-response "GET /ping" pong
+    #[arg(long = "static-response", value_names = ["ROUTE", "BODY"], num_args = 2)]
+    pub static_responses: Vec<String>,
+
+    /// Status of a --static-response route (default 200)
+    #[arg(long = "static-status", value_names = ["ROUTE", "STATUS"], num_args = 2)]
+    pub static_statuses: Vec<String>,
+
+    /// Content-Type of a --static-response route (default text/plain; charset=utf-8)
+    #[arg(long = "static-content-type", value_names = ["ROUTE", "TYPE"], num_args = 2)]
+    pub static_content_types: Vec<String>,
+
+    /// Serve a route JSON-RPC style: the body's `method` field picks the command
+    /// from --rpc-method-command instead of the route's own command
+    #[arg(long = "route-rpc", value_name = "ROUTE")]
+    pub route_rpc: Vec<String>,
+
+    /// Command for a JSON-RPC method on --route-rpc routes,
+    /// e.g. --rpc-method-command "getUser=./get_user.sh"
+    #[arg(long = "rpc-method-command", value_name = "METHOD=COMMAND")]
+    pub rpc_method_commands: Vec<String>,
+}
+
+/// Split a comma-separated option value, dropping empty entries
+pub fn split_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|item| item.trim().to_string())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+/// Parse a METHOD=TYPE mapping, upper-casing the method
+pub fn parse_method_content_type(spec: &str) -> Result<(String, String), String> {
+    let (method, content_type) = spec
+        .split_once('=')
+        .map(|(m, t)| (m.trim(), t.trim()))
+        .filter(|(m, t)| !m.is_empty() && !t.is_empty())
+        .ok_or_else(|| format!("expected METHOD=TYPE, got '{}'", spec))?;
+    Ok((method.to_uppercase(), content_type.to_string()))
+}
+
+/// Parse a --cookie-env COOKIE=VAR mapping; VAR must be a valid variable name
+pub fn parse_cookie_env(spec: &str) -> Result<(String, String), String> {
+    let (cookie, var) = spec
+        .split_once('=')
+        .map(|(c, v)| (c.trim(), v.trim()))
+        .filter(|(c, v)| !c.is_empty() && !v.is_empty())
+        .ok_or_else(|| format!("expected COOKIE=VAR, got '{}'", spec))?;
+    let valid_var = !var.starts_with(|c: char| c.is_ascii_digit())
+        && var.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid_var {
+        return Err(format!("'{}' is not a valid variable name", var));
+    }
+    Ok((cookie.to_string(), var.to_string()))
+}
+
+/// Parse a --shell-concurrency value like "zsh=4"
+pub fn parse_shell_concurrency(spec: &str) -> Result<(ShellType, usize), String> {
+    let (shell, max) = spec
+        .split_once('=')
+        .ok_or_else(|| format!("expected SHELL=N, got '{}'", spec))?;
+    let shell = ShellType::from_str(shell.trim(), true)
+        .map_err(|_| format!("unknown shell '{}'", shell.trim()))?;
+    let max = max
+        .trim()
+        .parse::<usize>()
+        .ok()
+        .filter(|max| *max > 0)
+        .ok_or_else(|| format!("invalid limit '{}'", max.trim()))?;
+    Ok((shell, max))
+}
+
+/// Parse a signal given by name (PIPE, SIGPIPE) or number
+pub fn parse_signal(value: &str) -> Result<i32, String> {
+    let name = value.trim().to_uppercase();
+    let name = name.strip_prefix("SIG").unwrap_or(&name);
+    signal_number(name)
+        .or_else(|| name.parse::<i32>().ok().filter(|n| (1..=64).contains(n)))
+        .ok_or_else(|| format!("unknown signal '{}'", value))
+}
+
+/// Number of the signal named `name` (without SIG) on this platform, as they
+/// differ between e.g. Linux and macOS
+#[cfg(unix)]
+fn signal_number(name: &str) -> Option<i32> {
+    let signal = match name {
+        "HUP" => libc::SIGHUP,
+        "INT" => libc::SIGINT,
+        "QUIT" => libc::SIGQUIT,
+        "KILL" => libc::SIGKILL,
+        "USR1" => libc::SIGUSR1,
+        "USR2" => libc::SIGUSR2,
+        "PIPE" => libc::SIGPIPE,
+        "ALRM" => libc::SIGALRM,
+        "TERM" => libc::SIGTERM,
+        _ => return None,
+    };
+    Some(signal)
+}
+
+/// There are no signal names off Unix, so only numbers are accepted there
+#[cfg(not(unix))]
+fn signal_number(_name: &str) -> Option<i32> {
+    None
+}
+
+/// Check that a --health-path value is a plain absolute path
+pub fn parse_health_path(value: &str) -> Result<String, String> {
+    if !value.starts_with('/') || value.contains(['{', '}', ':', '*']) {
+        return Err(format!("expected a literal path starting with '/', got '{}'", value));
+    }
+    Ok(value.to_string())
+}
+
+/// Parse an --error-page value like "404=./404.html" into status and file path
+pub fn parse_error_page(spec: &str) -> Result<(u16, String), String> {
+    let (status, path) = spec
+        .split_once('=')
+        .ok_or_else(|| format!("expected STATUS=FILE, got '{}'", spec))?;
+    let status = status
+        .trim()
+        .parse::<u16>()
+        .ok()
+        .filter(|code| (100..1000).contains(code))
+        .ok_or_else(|| format!("invalid status code '{}'", status))?;
+    Ok((status, path.trim().to_string()))
 }
 
 #[cfg(test)]
@@ -63,6 +688,14 @@ mod tests {
         assert!(matches!(args.log_level, LogLevel::Info));
     }
 
+    #[test]
+    fn test_log_format() {
+        let args = Args::parse_from(["sherut"]);
+        assert_eq!(args.log_format, LogFormat::Text);
+        let args = Args::parse_from(["sherut", "--log-format", "json"]);
+        assert_eq!(args.log_format, LogFormat::Json);
+    }
+
     #[test]
     fn test_log_level_debug() {
         let args = Args::parse_from(["sherut", "--log-level", "debug"]);
@@ -105,12 +738,673 @@ mod tests {
         assert_eq!(args.header_format, Some(HeaderFormat::Assoc));
     }
 
+    #[test]
+    fn test_param_mode() {
+        assert_eq!(Args::parse_from(["sherut"]).param_mode, ParamMode::Substitute);
+        let args = Args::parse_from(["sherut", "--param-mode", "positional"]);
+        assert_eq!(args.param_mode, ParamMode::Positional);
+    }
+
+    #[test]
+    fn test_allow_shell_header() {
+        assert!(Args::parse_from(["sherut"]).allow_shell_header.is_empty());
+        let args = Args::parse_from(["sherut", "--allow-shell-header", "bash,fish"]);
+        assert_eq!(args.allow_shell_header, vec![ShellType::Bash, ShellType::Fish]);
+        assert!(Args::try_parse_from(["sherut", "--allow-shell-header", "python3"]).is_err());
+    }
+
+    #[test]
+    fn test_debug_echo_script() {
+        assert!(!Args::parse_from(["sherut"]).debug_echo_script);
+        assert!(Args::parse_from(["sherut", "--debug-echo-script"]).debug_echo_script);
+    }
+
     #[test]
     fn test_query_format_json() {
         let args = Args::parse_from(["sherut", "--query-format", "json"]);
         assert_eq!(args.query_format, Some(HeaderFormat::Json));
     }
 
+    #[test]
+    fn test_cookie_format() {
+        assert_eq!(Args::parse_from(["sherut"]).cookie_format, None);
+        let args = Args::parse_from(["sherut", "--cookie-format", "json"]);
+        assert_eq!(args.cookie_format, Some(HeaderFormat::Json));
+    }
+
+    #[test]
+    fn test_auto_options_flag() {
+        assert!(!Args::parse_from(["sherut"]).auto_options);
+        assert!(Args::parse_from(["sherut", "--auto-options"]).auto_options);
+    }
+
+    #[test]
+    fn test_strip_hop_by_hop_flag() {
+        let args = Args::parse_from(["sherut", "--strip-hop-by-hop"]);
+        assert!(args.strip_hop_by_hop);
+    }
+
+    #[test]
+    fn test_error_pages() {
+        let args = Args::parse_from([
+            "sherut",
+            "--error-page", "404=./404.html", "500=./500.html",
+        ]);
+        assert_eq!(args.error_pages, vec!["404=./404.html", "500=./500.html"]);
+        assert!(!args.error_page_always);
+    }
+
+    #[test]
+    fn test_validators() {
+        assert!(Args::parse_from(["sherut"]).validators.is_empty());
+        let args = Args::parse_from(["sherut", "--validate", "id:uuid", "--validate", "n:int"]);
+        assert_eq!(args.validators, vec!["id:uuid", "n:int"]);
+    }
+
+    #[test]
+    fn test_parse_error_page() {
+        assert_eq!(
+            parse_error_page("404=./404.html"),
+            Ok((404, "./404.html".to_string()))
+        );
+        assert!(parse_error_page("./404.html").is_err());
+        assert!(parse_error_page("abc=./404.html").is_err());
+        assert!(parse_error_page("42=./404.html").is_err());
+    }
+
+    #[test]
+    fn test_response_header_lists() {
+        let args = Args::parse_from([
+            "sherut",
+            "--allowed-response-headers", "X-Custom,Content-Type",
+            "--blocked-response-headers", "Strict-Transport-Security",
+        ]);
+        assert_eq!(args.allowed_response_headers, vec!["X-Custom", "Content-Type"]);
+        assert_eq!(args.blocked_response_headers, vec!["Strict-Transport-Security"]);
+    }
+
+    #[test]
+    fn test_allowed_content_types() {
+        let args = Args::parse_from([
+            "sherut",
+            "--allowed-content-types", "application/json,text/plain",
+        ]);
+        assert_eq!(args.allowed_content_types, vec!["application/json", "text/plain"]);
+    }
+
+    #[test]
+    fn test_route_created_location() {
+        let args = Args::parse_from([
+            "sherut",
+            "--route-created-location", "POST /items", "/items/:id",
+        ]);
+        assert_eq!(args.route_created_locations, vec!["POST /items", "/items/:id"]);
+    }
+
+    #[test]
+    fn test_route_wrap_html() {
+        let args = Args::parse_from([
+            "sherut",
+            "--no-escape",
+            "--route-wrap-html", "GET /status", "./template.html",
+        ]);
+        assert!(args.no_escape);
+        assert_eq!(args.route_wrap_html, vec!["GET /status", "./template.html"]);
+    }
+
+    #[test]
+    fn test_route_stdin_encoding() {
+        let args = Args::parse_from([
+            "sherut",
+            "--route-stdin-encoding", "POST /upload", "base64",
+        ]);
+        assert_eq!(args.route_stdin_encodings, vec!["POST /upload", "base64"]);
+    }
+
+    #[test]
+    fn test_route_charset() {
+        let args = Args::parse_from(["sherut", "--route-charset", "GET /legacy", "iso-8859-1"]);
+        assert_eq!(args.route_charsets, vec!["GET /legacy", "iso-8859-1"]);
+    }
+
+    #[test]
+    fn test_route_split_lines_to_json() {
+        let args = Args::parse_from([
+            "sherut",
+            "--route-split-lines-to-json", "GET /users",
+            "--route-split-delimiter", "GET /ids", ",",
+        ]);
+        assert_eq!(args.route_split_lines_to_json, vec!["GET /users"]);
+        assert_eq!(args.route_split_delimiters, vec!["GET /ids", ","]);
+    }
+
+    #[test]
+    fn test_route_body_requirements() {
+        let args = Args::parse_from([
+            "sherut",
+            "--route-require-body", "POST /items",
+            "--route-forbid-body", "GET /items",
+        ]);
+        assert_eq!(args.route_require_body, vec!["POST /items"]);
+        assert_eq!(args.route_forbid_body, vec!["GET /items"]);
+    }
+
+    #[test]
+    fn test_route_response_header() {
+        let args = Args::parse_from([
+            "sherut",
+            "--route-response-header", "GET /items", "X-Count: :query.n",
+        ]);
+        assert_eq!(args.route_response_headers, vec!["GET /items", "X-Count: :query.n"]);
+    }
+
+    #[test]
+    fn test_route_as_sse() {
+        let args = Args::parse_from(["sherut", "--route-as-sse", "GET /events"]);
+        assert_eq!(args.route_as_sse, vec!["GET /events"]);
+    }
+
+    #[test]
+    fn test_route_jq() {
+        let args = Args::parse_from(["sherut", "--route-jq", "GET /users", ".data"]);
+        assert_eq!(args.route_jq, vec!["GET /users", ".data"]);
+    }
+
+    #[test]
+    fn test_route_success_codes() {
+        let args = Args::parse_from(["sherut", "--route-success-codes", "GET /diff", "0,1"]);
+        assert_eq!(args.route_success_codes, vec!["GET /diff", "0,1"]);
+    }
+
+    #[test]
+    fn test_route_deprecated() {
+        let args = Args::parse_from([
+            "sherut",
+            "--route-deprecated", "GET /old",
+            "--route-sunset", "GET /old", "Wed, 31 Dec 2025 23:59:59 GMT",
+        ]);
+        assert_eq!(args.route_deprecated, vec!["GET /old"]);
+        assert_eq!(args.route_sunsets, vec!["GET /old", "Wed, 31 Dec 2025 23:59:59 GMT"]);
+    }
+
+    #[test]
+    fn test_route_rate_limit() {
+        let args = Args::parse_from(["sherut"]);
+        assert_eq!(args.rate_limit_status, 429);
+        assert_eq!(args.rate_limit_body, "Too Many Requests");
+        assert_eq!(args.rate_limit_command, None);
+
+        let args = Args::parse_from([
+            "sherut",
+            "--route-rate-limit", "POST /jobs", "10/m",
+            "--rate-limit-status", "503",
+            "--rate-limit-command", "cat throttled.html",
+        ]);
+        assert_eq!(args.route_rate_limits, vec!["POST /jobs", "10/m"]);
+        assert_eq!(args.rate_limit_status, 503);
+        assert_eq!(args.rate_limit_command.as_deref(), Some("cat throttled.html"));
+        assert!(Args::try_parse_from(["sherut", "--rate-limit-status", "99"]).is_err());
+    }
+
+    #[test]
+    fn test_static_response() {
+        let args = Args::parse_from([
+            "sherut",
+            "--static-response", "GET /ping", "pong",
+            "--static-status", "GET /ping", "202",
+            "--static-content-type", "GET /ping", "text/html",
+        ]);
+        assert_eq!(args.static_responses, vec!["GET /ping", "pong"]);
+        assert_eq!(args.static_statuses, vec!["GET /ping", "202"]);
+        assert_eq!(args.static_content_types, vec!["GET /ping", "text/html"]);
+    }
+
+    #[test]
+    fn test_route_when() {
+        let args = Args::parse_from([
+            "sherut",
+            "--route-when", "GET /feature", "header:X-Beta=true",
+            "--route-otherwise", "GET /feature", "echo stable",
+        ]);
+        assert_eq!(args.route_when, vec!["GET /feature", "header:X-Beta=true"]);
+        assert_eq!(args.route_otherwise, vec!["GET /feature", "echo stable"]);
+    }
+
+    #[test]
+    fn test_route_rpc() {
+        let args = Args::parse_from([
+            "sherut",
+            "--route-rpc", "POST /rpc",
+            "--rpc-method-command", "getUser=./get_user.sh",
+            "--rpc-method-command", "ping=echo pong",
+        ]);
+        assert_eq!(args.route_rpc, vec!["POST /rpc"]);
+        assert_eq!(args.rpc_method_commands, vec!["getUser=./get_user.sh", "ping=echo pong"]);
+    }
+
+    #[test]
+    fn test_route_enabled_if() {
+        let args = Args::parse_from(["sherut", "--route-enabled-if", "GET /beta", "FEATURE_X=1"]);
+        assert_eq!(args.route_enabled_if, vec!["GET /beta", "FEATURE_X=1"]);
+    }
+
+    #[test]
+    fn test_split_list() {
+        assert_eq!(split_list(" a, b,,c "), vec!["a", "b", "c"]);
+        assert!(split_list("").is_empty());
+    }
+
+    #[test]
+    fn test_host() {
+        let args = Args::parse_from(["sherut"]);
+        assert_eq!(args.host, IpAddr::from([127, 0, 0, 1]));
+        assert!(!args.ipv6_only);
+
+        let args = Args::parse_from(["sherut", "--host", "::", "--ipv6-only"]);
+        assert_eq!(args.host, "::".parse::<IpAddr>().unwrap());
+        assert!(args.ipv6_only);
+        assert!(Args::try_parse_from(["sherut", "--host", "localhost"]).is_err());
+    }
+
+    #[test]
+    fn test_metrics_port() {
+        assert_eq!(Args::parse_from(["sherut"]).metrics_port, None);
+        let args = Args::parse_from(["sherut", "--metrics-port", "9090"]);
+        assert_eq!(args.metrics_port, Some(9090));
+    }
+
+    #[test]
+    fn test_port_file() {
+        let args = Args::parse_from(["sherut", "--port", "0", "--port-file", "/tmp/sherut.port"]);
+        assert_eq!(args.port, 0);
+        assert_eq!(args.port_file, Some(PathBuf::from("/tmp/sherut.port")));
+    }
+
+    #[test]
+    fn test_startup_event() {
+        assert_eq!(Args::parse_from(["sherut"]).startup_event, None);
+        let args = Args::parse_from(["sherut", "--startup-event", "json"]);
+        assert_eq!(args.startup_event, Some(StartupEvent::Json));
+    }
+
+    #[test]
+    fn test_api_keys() {
+        let args = Args::parse_from(["sherut"]);
+        assert!(args.api_keys.is_empty());
+        assert_eq!(args.api_key_header, "x-api-key");
+
+        let args = Args::parse_from([
+            "sherut",
+            "--api-key", "one",
+            "--api-key", "two",
+            "--api-key-header", "Authorization",
+        ]);
+        assert_eq!(args.api_keys, vec!["one", "two"]);
+        assert_eq!(args.api_key_header, "authorization");
+        assert!(Args::try_parse_from(["sherut", "--api-key-header", "x-key"]).is_err());
+        let invalid_header = ["sherut", "--api-key", "k", "--api-key-header", "a b"];
+        assert!(Args::try_parse_from(invalid_header).is_err());
+    }
+
+    #[test]
+    fn test_csrf() {
+        let args = Args::parse_from(["sherut"]);
+        assert!(!args.csrf);
+        assert_eq!(args.csrf_cookie, "csrf_token");
+
+        let args = Args::parse_from(["sherut", "--csrf", "--csrf-cookie", "xsrf"]);
+        assert!(args.csrf);
+        assert_eq!(args.csrf_cookie, "xsrf");
+    }
+
+    #[test]
+    fn test_nonce_header() {
+        let args = Args::parse_from(["sherut"]);
+        assert_eq!(args.nonce_header, None);
+        assert_eq!(args.nonce_window_secs, 300);
+
+        let args = Args::parse_from([
+            "sherut",
+            "--nonce-header", "X-Nonce",
+            "--nonce-window-secs", "60",
+        ]);
+        assert_eq!(args.nonce_header.as_deref(), Some("X-Nonce"));
+        assert_eq!(args.nonce_window_secs, 60);
+    }
+
+    #[test]
+    fn test_allowed_hosts() {
+        assert!(Args::parse_from(["sherut"]).allowed_hosts.is_empty());
+        let args = Args::parse_from(["sherut", "--allowed-hosts", "example.com,api.example.com"]);
+        assert_eq!(args.allowed_hosts, vec!["example.com", "api.example.com"]);
+    }
+
+    #[test]
+    fn test_maintenance() {
+        let args = Args::parse_from(["sherut"]);
+        assert!(!args.maintenance);
+        assert_eq!(args.maintenance_body, "Down for maintenance");
+        let args = Args::parse_from(["sherut", "--maintenance", "--maintenance-body", "Back soon"]);
+        assert!(args.maintenance);
+        assert_eq!(args.maintenance_body, "Back soon");
+    }
+
+    #[test]
+    fn test_webhook() {
+        let args = Args::parse_from(["sherut"]);
+        assert_eq!(args.webhook_url, None);
+        assert_eq!(args.webhook_on, WebhookOn::All);
+        let args = Args::parse_from([
+            "sherut",
+            "--webhook-url", "http://hooks.local/sherut",
+            "--webhook-on", "error",
+        ]);
+        assert_eq!(args.webhook_url.as_deref(), Some("http://hooks.local/sherut"));
+        assert_eq!(args.webhook_on, WebhookOn::Error);
+        assert!(Args::try_parse_from(["sherut", "--webhook-on", "error"]).is_err());
+    }
+
+    #[test]
+    fn test_cors() {
+        assert!(Args::parse_from(["sherut"]).cors_origins.is_empty());
+        let args = Args::parse_from([
+            "sherut",
+            "--cors-origin", "https://a.example.com",
+            "--cors-origin", "https://b.example.com",
+            "--cors-methods", "GET,POST",
+        ]);
+        assert_eq!(args.cors_origins, vec!["https://a.example.com", "https://b.example.com"]);
+        assert_eq!(args.cors_methods, vec!["GET", "POST"]);
+        assert!(Args::try_parse_from(["sherut", "--cors-methods", "GET"]).is_err());
+    }
+
+    #[test]
+    fn test_tls_flags() {
+        let args = Args::parse_from(["sherut", "--tls-cert", "cert.pem", "--tls-key", "key.pem"]);
+        assert_eq!(args.tls_cert, Some(PathBuf::from("cert.pem")));
+        assert_eq!(args.tls_key, Some(PathBuf::from("key.pem")));
+        assert!(Args::try_parse_from(["sherut", "--tls-cert", "cert.pem"]).is_err());
+        assert!(Args::try_parse_from(["sherut", "--tls-key", "key.pem"]).is_err());
+        assert!(!Args::parse_from(["sherut"]).trust_proxy);
+        assert!(Args::parse_from(["sherut", "--trust-proxy"]).trust_proxy);
+    }
+
+    #[test]
+    fn test_max_connections() {
+        assert_eq!(Args::parse_from(["sherut"]).max_connections, None);
+        let args = Args::parse_from(["sherut", "--max-connections", "100"]);
+        assert_eq!(args.max_connections, Some(100));
+        assert!(Args::try_parse_from(["sherut", "--max-connections", "0"]).is_err());
+    }
+
+    #[test]
+    fn test_idle_shutdown_secs() {
+        assert_eq!(Args::parse_from(["sherut"]).idle_shutdown_secs, None);
+        let args = Args::parse_from(["sherut", "--idle-shutdown-secs", "300"]);
+        assert_eq!(args.idle_shutdown_secs, Some(300));
+        assert!(Args::try_parse_from(["sherut", "--idle-shutdown-secs", "0"]).is_err());
+    }
+
+    #[test]
+    fn test_verify_checksum() {
+        assert!(!Args::parse_from(["sherut"]).verify_checksum);
+        assert!(Args::parse_from(["sherut", "--verify-checksum"]).verify_checksum);
+    }
+
+    #[test]
+    fn test_warn_empty_response() {
+        assert!(!Args::parse_from(["sherut"]).warn_empty_response);
+        assert!(Args::parse_from(["sherut", "--warn-empty-response"]).warn_empty_response);
+    }
+
+    #[test]
+    fn test_server_timing() {
+        assert!(!Args::parse_from(["sherut"]).server_timing);
+        assert!(Args::parse_from(["sherut", "--server-timing"]).server_timing);
+    }
+
+    #[test]
+    fn test_max_query_params() {
+        let args = Args::parse_from(["sherut"]);
+        assert_eq!(args.max_query_params, None);
+        assert_eq!(args.on_too_many_query_params, QueryOverflow::Reject);
+
+        let args = Args::parse_from([
+            "sherut",
+            "--max-query-params", "100",
+            "--on-too-many-query-params", "truncate",
+        ]);
+        assert_eq!(args.max_query_params, Some(100));
+        assert_eq!(args.on_too_many_query_params, QueryOverflow::Truncate);
+        let truncate = ["sherut", "--on-too-many-query-params", "truncate"];
+        assert!(Args::try_parse_from(truncate).is_err());
+    }
+
+    #[test]
+    fn test_max_uri_length() {
+        assert_eq!(Args::parse_from(["sherut"]).max_uri_length, 8192);
+        let args = Args::parse_from(["sherut", "--max-uri-length", "1024"]);
+        assert_eq!(args.max_uri_length, 1024);
+    }
+
+    #[test]
+    fn test_max_response_headers() {
+        assert_eq!(Args::parse_from(["sherut"]).max_response_headers, None);
+        let args = Args::parse_from(["sherut", "--max-response-headers", "50"]);
+        assert_eq!(args.max_response_headers, Some(50));
+    }
+
+    #[test]
+    fn test_max_body_size() {
+        assert_eq!(Args::parse_from(["sherut"]).max_body_size, 2 * 1024 * 1024);
+        let args = Args::parse_from([
+            "sherut",
+            "--max-body-size", "1024",
+            "--route-max-body-size", "POST /upload", "104857600",
+        ]);
+        assert_eq!(args.max_body_size, 1024);
+        assert_eq!(args.route_max_body_sizes, vec!["POST /upload", "104857600"]);
+    }
+
+    #[test]
+    fn test_default_content_type_by_method() {
+        let args = Args::parse_from([
+            "sherut",
+            "--default-content-type-by-method", "post=application/json,PUT=application/json",
+        ]);
+        let parsed: Vec<_> = args
+            .default_content_type_by_method
+            .iter()
+            .map(|spec| parse_method_content_type(spec).unwrap())
+            .collect();
+        assert_eq!(parsed, vec![
+            ("POST".to_string(), "application/json".to_string()),
+            ("PUT".to_string(), "application/json".to_string()),
+        ]);
+        assert!(parse_method_content_type("POST").is_err());
+    }
+
+    #[test]
+    fn test_body_format() {
+        let args = Args::parse_from(["sherut"]);
+        assert_eq!(args.body_format, BodyFormat::Stdin);
+        assert_eq!(args.max_body_env, 131072);
+        let args = Args::parse_from(["sherut", "--body-format", "both", "--max-body-env", "1024"]);
+        assert_eq!(args.body_format, BodyFormat::Both);
+        assert_eq!(args.max_body_env, 1024);
+    }
+
+    #[test]
+    fn test_cookie_env() {
+        let args = Args::parse_from(["sherut", "--cookie-env", "session=SESSION_ID"]);
+        assert_eq!(args.cookie_env, vec!["session=SESSION_ID"]);
+        assert_eq!(
+            parse_cookie_env("session=SESSION_ID"),
+            Ok(("session".to_string(), "SESSION_ID".to_string()))
+        );
+        assert!(parse_cookie_env("session").is_err());
+        assert!(parse_cookie_env("session=SESSION-ID").is_err());
+        assert!(parse_cookie_env("session=1ID").is_err());
+    }
+
+    #[test]
+    fn test_max_concurrency() {
+        let args = Args::parse_from([
+            "sherut",
+            "--max-concurrency", "4",
+            "--queue-timeout-ms", "250",
+        ]);
+        assert_eq!(args.max_concurrency, Some(4));
+        assert_eq!(args.queue_timeout_ms, Some(250));
+        assert!(Args::try_parse_from(["sherut", "--queue-timeout-ms", "250"]).is_err());
+        assert_eq!(args.scheduling, Scheduling::Fifo);
+
+        let args = Args::parse_from([
+            "sherut",
+            "--max-concurrency", "4",
+            "--scheduling", "priority",
+        ]);
+        assert_eq!(args.scheduling, Scheduling::Priority);
+        assert_eq!(args.on_overload, Overload::Queue);
+
+        let args = Args::parse_from([
+            "sherut",
+            "--max-concurrency", "4",
+            "--on-overload", "reject",
+        ]);
+        assert_eq!(args.on_overload, Overload::Reject);
+        assert!(Args::try_parse_from(["sherut", "--on-overload", "reject"]).is_err());
+    }
+
+    #[test]
+    fn test_shell_concurrency() {
+        let args = Args::parse_from([
+            "sherut",
+            "--shell-concurrency", "zsh=4",
+            "--queue-timeout-ms", "250",
+        ]);
+        assert_eq!(args.shell_concurrency, vec!["zsh=4"]);
+        assert_eq!(args.queue_timeout_ms, Some(250));
+
+        assert_eq!(parse_shell_concurrency("zsh=4"), Ok((ShellType::Zsh, 4)));
+        assert_eq!(parse_shell_concurrency(" Bash = 2 "), Ok((ShellType::Bash, 2)));
+        assert!(parse_shell_concurrency("zsh").is_err());
+        assert!(parse_shell_concurrency("python=2").is_err());
+        assert!(parse_shell_concurrency("sh=0").is_err());
+    }
+
+    #[test]
+    fn test_sudo_user() {
+        assert_eq!(Args::parse_from(["sherut"]).sudo_user, None);
+        let args = Args::parse_from(["sherut", "--sudo-user", "www-data"]);
+        assert_eq!(args.sudo_user.as_deref(), Some("www-data"));
+    }
+
+    #[test]
+    fn test_request_filter() {
+        let args = Args::parse_from(["sherut", "--request-filter", "gpg --decrypt"]);
+        assert_eq!(args.request_filter.as_deref(), Some("gpg --decrypt"));
+    }
+
+    #[test]
+    fn test_command_timeout_ms() {
+        assert!(Args::parse_from(["sherut"]).command_timeout_ms.is_none());
+        let args = Args::parse_from(["sherut", "--command-timeout-ms", "2500"]);
+        assert_eq!(args.command_timeout_ms, Some(2500));
+    }
+
+    #[test]
+    fn test_empty_output_handling() {
+        let args = Args::parse_from(["sherut"]);
+        assert_eq!(args.empty_status, None);
+        assert!(!args.treat_whitespace_as_empty);
+
+        let args = Args::parse_from([
+            "sherut",
+            "--empty-status", "204",
+            "--treat-whitespace-as-empty",
+        ]);
+        assert_eq!(args.empty_status, Some(204));
+        assert!(args.treat_whitespace_as_empty);
+        assert!(Args::try_parse_from(["sherut", "--empty-status", "99"]).is_err());
+    }
+
+    #[test]
+    fn test_status_from_exit() {
+        assert_eq!(Args::parse_from(["sherut"]).status_from_exit, None);
+        let args = Args::parse_from(["sherut", "--status-from-exit", "400"]);
+        assert_eq!(args.status_from_exit, Some(400));
+        assert!(Args::try_parse_from(["sherut", "--status-from-exit", "600"]).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_success_signals() {
+        let args = Args::parse_from(["sherut", "--success-signals", "PIPE,sigterm,2"]);
+        assert_eq!(args.success_signals, vec![libc::SIGPIPE, libc::SIGTERM, 2]);
+        let args = Args::parse_from(["sherut", "--success-signals", "USR1"]);
+        assert_eq!(args.success_signals, vec![libc::SIGUSR1]);
+        assert!(Args::try_parse_from(["sherut", "--success-signals", "NOPE"]).is_err());
+    }
+
+    #[test]
+    fn test_circuit_breaker() {
+        let args = Args::parse_from(["sherut", "--circuit-breaker", "5"]);
+        assert_eq!(args.circuit_breaker, Some(5));
+        assert_eq!(args.circuit_cooldown_secs, 30);
+        assert!(Args::try_parse_from(["sherut", "--circuit-breaker", "0"]).is_err());
+    }
+
+    #[test]
+    fn test_health_command() {
+        let args = Args::parse_from(["sherut", "--health-command", "pg_isready"]);
+        assert_eq!(args.health_command.as_deref(), Some("pg_isready"));
+        assert_eq!(args.health_cache_ms, 1000);
+        assert_eq!(args.health_path, None);
+        assert!(args.probes.is_empty());
+
+        let args =
+            Args::parse_from(["sherut", "--probe", "db:pg_isready:10s", "--probe", "q:true:1m"]);
+        assert_eq!(args.probes, vec!["db:pg_isready:10s", "q:true:1m"]);
+
+        let args = Args::parse_from(["sherut", "--health-path", "/livez"]);
+        assert_eq!(args.health_path.as_deref(), Some("/livez"));
+        assert!(Args::try_parse_from(["sherut", "--health-path", "livez"]).is_err());
+        assert!(Args::try_parse_from(["sherut", "--health-path", "/users/:id"]).is_err());
+    }
+
+    #[test]
+    fn test_preflight() {
+        assert_eq!(Args::parse_from(["sherut"]).preflight, None);
+        let args = Args::parse_from(["sherut", "--preflight", "mkdir -p /tmp/jobs"]);
+        assert_eq!(args.preflight.as_deref(), Some("mkdir -p /tmp/jobs"));
+    }
+
+    #[test]
+    fn test_workdir() {
+        let args = Args::parse_from([
+            "sherut",
+            "--workdir", "/srv/app",
+            "--route-workdir", "POST /build", "frontend",
+        ]);
+        assert_eq!(args.workdir, Some(PathBuf::from("/srv/app")));
+        assert_eq!(args.route_workdirs, vec!["POST /build", "frontend"]);
+    }
+
+    #[test]
+    fn test_scripts_dir() {
+        let args = Args::parse_from(["sherut", "--scripts-dir", "./api"]);
+        assert_eq!(args.scripts_dir, Some(PathBuf::from("./api")));
+    }
+
+    #[test]
+    fn test_routes_file_and_watch() {
+        let args = Args::parse_from(["sherut", "--routes-file", "routes.txt", "--watch"]);
+        assert_eq!(args.routes_file, Some(PathBuf::from("routes.txt")));
+        assert!(args.watch);
+        assert!(Args::try_parse_from(["sherut", "--watch"]).is_err());
+    }
+
     #[test]
     fn test_single_route() {
         let args = Args::parse_from([
@@ -139,6 +1433,21 @@ mod tests {
         assert!(args.routes.is_empty());
     }
 
+    #[test]
+    fn test_route_log_levels() {
+        let args = Args::parse_from([
+            "sherut",
+            "--route-log-level", "GET /noisy", "debug",
+        ]);
+        assert_eq!(args.route_log_levels, vec!["GET /noisy", "debug"]);
+    }
+
+    #[test]
+    fn test_log_level_as_level() {
+        assert_eq!(LogLevel::Warn.as_level(), Level::WARN);
+        assert_eq!(LogLevel::Trace.as_level(), Level::TRACE);
+    }
+
     #[test]
     fn test_combined_options() {
         let args = Args::parse_from([
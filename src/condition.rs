@@ -0,0 +1,91 @@
+use axum::http::HeaderMap;
+use std::collections::HashMap;
+
+/// Where a --route-when condition looks for its value
+#[derive(Clone, Debug, PartialEq)]
+pub enum Source {
+    Header,
+    Query,
+}
+
+/// A request condition like `header:X-Beta=true` (equality) or `query:debug` (presence)
+#[derive(Clone, Debug, PartialEq)]
+pub struct Condition {
+    source: Source,
+    name: String,
+    value: Option<String>,
+}
+
+impl Condition {
+    pub fn matches(&self, headers: &HeaderMap, query_params: &HashMap<String, String>) -> bool {
+        let actual = match self.source {
+            Source::Header => headers.get(self.name.as_str()).and_then(|v| v.to_str().ok()),
+            Source::Query => query_params.get(&self.name).map(String::as_str),
+        };
+        match (&self.value, actual) {
+            (Some(expected), Some(actual)) => expected == actual,
+            (None, actual) => actual.is_some(),
+            (Some(_), None) => false,
+        }
+    }
+}
+
+/// Parse a condition: `header:NAME`, `header:NAME=VALUE`, `query:NAME` or `query:NAME=VALUE`
+pub fn parse_condition(spec: &str) -> Result<Condition, String> {
+    let invalid = || format!("expected header:NAME[=VALUE] or query:NAME[=VALUE], got '{}'", spec);
+    let (source, rest) = spec.split_once(':').ok_or_else(invalid)?;
+    let source = match source.trim().to_lowercase().as_str() {
+        "header" => Source::Header,
+        "query" => Source::Query,
+        _ => return Err(invalid()),
+    };
+    let (name, value) = match rest.split_once('=') {
+        Some((name, value)) => (name.trim(), Some(value.to_string())),
+        None => (rest.trim(), None),
+    };
+    if name.is_empty() {
+        return Err(invalid());
+    }
+    // Header lookups are case-insensitive, HeaderMap keys are lowercase
+    let name = match source {
+        Source::Header => name.to_lowercase(),
+        Source::Query => name.to_string(),
+    };
+    Ok(Condition { source, name, value })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    #[test]
+    fn test_parse_condition() {
+        let condition = parse_condition("header:X-Beta=true").unwrap();
+        assert_eq!(condition.source, Source::Header);
+        assert_eq!(condition.name, "x-beta");
+        assert_eq!(condition.value.as_deref(), Some("true"));
+
+        let condition = parse_condition("query:debug").unwrap();
+        assert_eq!(condition.source, Source::Query);
+        assert_eq!(condition.value, None);
+
+        assert!(parse_condition("cookie:a=b").is_err());
+        assert!(parse_condition("header:").is_err());
+        assert!(parse_condition("X-Beta=true").is_err());
+    }
+
+    #[test]
+    fn test_condition_matches() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-beta", HeaderValue::from_static("true"));
+        let query = HashMap::from([("debug".to_string(), String::new())]);
+
+        assert!(parse_condition("header:X-Beta=true").unwrap().matches(&headers, &query));
+        assert!(!parse_condition("header:X-Beta=false").unwrap().matches(&headers, &query));
+        assert!(parse_condition("header:x-beta").unwrap().matches(&headers, &query));
+        assert!(!parse_condition("header:X-Other").unwrap().matches(&headers, &query));
+        assert!(parse_condition("query:debug").unwrap().matches(&headers, &query));
+        assert!(!parse_condition("query:debug=1").unwrap().matches(&headers, &query));
+    }
+}
@@ -0,0 +1,102 @@
+use axum::http::{HeaderValue, Method};
+use tower_http::cors::{AllowHeaders, AllowOrigin, CorsLayer};
+
+/// Methods allowed cross-origin when --cors-methods isn't given
+const DEFAULT_METHODS: [Method; 6] = [
+    Method::GET,
+    Method::HEAD,
+    Method::POST,
+    Method::PUT,
+    Method::PATCH,
+    Method::DELETE,
+];
+
+/// CORS layer for the allowed `origins` (`*` allows any) and `methods`.
+/// It answers preflight requests itself, before routing.
+pub fn cors_layer(origins: &[String], methods: &[String]) -> Result<CorsLayer, String> {
+    let allow_origin = if origins.iter().any(|o| o == "*") {
+        AllowOrigin::any()
+    } else {
+        let origins = origins
+            .iter()
+            .map(|o| {
+                HeaderValue::from_str(o.trim_end_matches('/'))
+                    .map_err(|_| format!("invalid CORS origin '{}'", o))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        AllowOrigin::list(origins)
+    };
+    let methods = if methods.is_empty() {
+        DEFAULT_METHODS.to_vec()
+    } else {
+        methods
+            .iter()
+            .map(|m| {
+                Method::from_bytes(m.trim().to_uppercase().as_bytes())
+                    .map_err(|_| format!("invalid CORS method '{}'", m))
+            })
+            .collect::<Result<Vec<_>, _>>()?
+    };
+    Ok(CorsLayer::new()
+        .allow_origin(allow_origin)
+        .allow_methods(methods)
+        .allow_headers(AllowHeaders::mirror_request()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, http::Request, routing::get, Router};
+    use tower::ServiceExt;
+
+    fn app(origins: &[&str]) -> Router {
+        let origins: Vec<String> = origins.iter().map(|o| o.to_string()).collect();
+        Router::new()
+            .route("/items", get(|| async { "items" }))
+            .layer(cors_layer(&origins, &[]).unwrap())
+    }
+
+    fn request(method: &str, origin: &str) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .uri("/items")
+            .header("origin", origin)
+            .header("access-control-request-method", "GET")
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_allowed_origin() {
+        let app = app(&["https://app.example.com"]);
+        let allowed = request("GET", "https://app.example.com");
+        let response = app.clone().oneshot(allowed).await.unwrap();
+        assert_eq!(
+            response.headers()["access-control-allow-origin"],
+            "https://app.example.com"
+        );
+
+        let response = app.oneshot(request("GET", "https://evil.example.com")).await.unwrap();
+        assert!(!response.headers().contains_key("access-control-allow-origin"));
+    }
+
+    #[tokio::test]
+    async fn test_any_origin() {
+        let response = app(&["*"]).oneshot(request("GET", "https://a.test")).await.unwrap();
+        assert_eq!(response.headers()["access-control-allow-origin"], "*");
+    }
+
+    #[tokio::test]
+    async fn test_preflight_for_get_only_route() {
+        let response = app(&["*"]).oneshot(request("OPTIONS", "https://a.test")).await.unwrap();
+        assert!(response.status().is_success());
+        let methods = response.headers()["access-control-allow-methods"].to_str().unwrap();
+        assert!(methods.contains("GET"));
+    }
+
+    #[test]
+    fn test_invalid_method() {
+        assert!(cors_layer(&["*".to_string()], &["GET".to_string()]).is_ok());
+        assert!(cors_layer(&["*".to_string()], &["GE T".to_string()]).is_err());
+    }
+}
@@ -0,0 +1,79 @@
+use axum::http::{header, HeaderMap, Method};
+
+/// Header that must repeat the CSRF cookie's value
+pub const CSRF_HEADER: &str = "x-csrf-token";
+
+/// Double-submit check: requests with state-changing methods must send the
+/// value of the `cookie_name` cookie in the X-CSRF-Token header as well
+pub fn check(method: &Method, headers: &HeaderMap, cookie_name: &str) -> bool {
+    if matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS) {
+        return true;
+    }
+    let Some(token) = headers.get(CSRF_HEADER).and_then(|v| v.to_str().ok()) else {
+        return false;
+    };
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(parse_cookies)
+        .any(|(name, value)| name == cookie_name && !value.is_empty() && eq_const_time(value, token))
+}
+
+/// Split a Cookie header into name/value pairs
+pub fn parse_cookies(cookie_header: &str) -> impl Iterator<Item = (&str, &str)> {
+    cookie_header.split(';').filter_map(|pair| {
+        let (name, value) = pair.split_once('=')?;
+        Some((name.trim(), value.trim().trim_matches('"')))
+    })
+}
+
+/// Compare without returning early, so timing doesn't reveal the matching prefix
+pub fn eq_const_time(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0u8, |diff, (x, y)| diff | (x ^ y))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, value.parse().unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn test_parse_cookies() {
+        let cookies: Vec<_> = parse_cookies("a=1; csrf_token=\"xyz\";b").collect();
+        assert_eq!(cookies, vec![("a", "1"), ("csrf_token", "xyz")]);
+    }
+
+    #[test]
+    fn test_check_matching_token() {
+        let headers = headers(&[("cookie", "session=s; csrf_token=abc"), (CSRF_HEADER, "abc")]);
+        assert!(check(&Method::POST, &headers, "csrf_token"));
+    }
+
+    #[test]
+    fn test_check_mismatch_or_missing() {
+        let mismatch = headers(&[("cookie", "csrf_token=abc"), (CSRF_HEADER, "abd")]);
+        assert!(!check(&Method::POST, &mismatch, "csrf_token"));
+        let no_header = headers(&[("cookie", "csrf_token=abc")]);
+        assert!(!check(&Method::DELETE, &no_header, "csrf_token"));
+        let no_cookie = headers(&[(CSRF_HEADER, "abc")]);
+        assert!(!check(&Method::PUT, &no_cookie, "csrf_token"));
+    }
+
+    #[test]
+    fn test_check_exempts_safe_methods() {
+        assert!(check(&Method::GET, &HeaderMap::new(), "csrf_token"));
+        assert!(check(&Method::HEAD, &HeaderMap::new(), "csrf_token"));
+    }
+}
@@ -0,0 +1,247 @@
+use axum::{
+    body::{Body, Bytes, HttpBody},
+    response::Response,
+};
+use http_body::Frame;
+use std::{
+    io,
+    pin::Pin,
+    process::{ExitStatus, Output},
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader},
+    process::Child,
+    sync::{mpsc, oneshot},
+    time::{timeout_at, Instant},
+};
+use tracing::warn;
+
+use crate::runner::finish_with_timeout;
+
+/// Output line that sends everything before it to the client right away; the
+/// rest of the output follows as the command writes it
+pub const FLUSH_MARKER: &str = "@flush";
+
+/// How a command's run ended
+pub enum Finished {
+    Done(Output),
+    /// The output hit `FLUSH_MARKER`: what came before it (with a placeholder
+    /// success status, as the command is still running), the rest to come and
+    /// the real exit status
+    Flushed(Output, FlushedOutput, Exit),
+}
+
+/// Exit status of a flushed command, known once it ends
+pub struct Exit(oneshot::Receiver<io::Result<ExitStatus>>);
+
+impl Exit {
+    /// Wait for the command to end. Fails if it timed out or its output couldn't be
+    /// read; None if the client went away first and the command was killed.
+    pub async fn status(self) -> Option<io::Result<ExitStatus>> {
+        self.0.await.ok()
+    }
+}
+
+/// Output a command writes after `FLUSH_MARKER`, forwarded by a background task
+/// that kills the command once the deadline passes or the client goes away
+pub struct FlushedOutput(mpsc::Receiver<io::Result<Bytes>>);
+
+/// Like `finish_with_timeout`, but stops waiting at a `FLUSH_MARKER` line
+pub async fn finish_or_flush(
+    mut child: Child,
+    input: &[u8],
+    timeout: Option<Duration>,
+) -> io::Result<Finished> {
+    let (Some(stdout), Some(mut stderr)) = (child.stdout.take(), child.stderr.take()) else {
+        return finish_with_timeout(child, input, timeout).await.map(Finished::Done);
+    };
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+
+    // Feed stdin and drain stderr alongside stdout, so no pipe fills up
+    if let Some(mut stdin) = child.stdin.take() {
+        let input = input.to_vec();
+        tokio::spawn(async move {
+            if let Err(e) = stdin.write_all(&input).await {
+                warn!("Failed to write to stdin: {}", e);
+            }
+        });
+    }
+    let stderr = tokio::spawn(async move {
+        let mut buf = Vec::new();
+        let _ = stderr.read_to_end(&mut buf).await;
+        buf
+    });
+
+    let mut stdout = BufReader::new(stdout);
+    let mut head = Vec::new();
+    let flushed = within(deadline, read_until_flush(&mut stdout, &mut head)).await?;
+    if !flushed {
+        let status = within(deadline, child.wait()).await?;
+        let stderr = stderr.await.unwrap_or_default();
+        return Ok(Finished::Done(Output { status, stdout: head, stderr }));
+    }
+
+    let (tx, rx) = mpsc::channel(16);
+    let (exit_tx, exit_rx) = oneshot::channel();
+    tokio::spawn(async move {
+        let forward = async {
+            let mut buf = vec![0; 8192];
+            loop {
+                let n = stdout.read(&mut buf).await?;
+                if n == 0 {
+                    break;
+                }
+                if tx.send(Ok(Bytes::copy_from_slice(&buf[..n]))).await.is_err() {
+                    // The client went away; dropping `child` kills the command
+                    return Ok(None);
+                }
+            }
+            child.wait().await.map(Some)
+        };
+        match within(deadline, forward).await {
+            Ok(Some(status)) => {
+                let _ = exit_tx.send(Ok(status));
+            }
+            Ok(None) => {}
+            Err(e) => {
+                let _ = exit_tx.send(Err(io::Error::new(e.kind(), e.to_string())));
+                let _ = tx.send(Err(e)).await;
+            }
+        }
+    });
+    let head = Output { status: ExitStatus::default(), stdout: head, stderr: Vec::new() };
+    Ok(Finished::Flushed(head, FlushedOutput(rx), Exit(exit_rx)))
+}
+
+/// Drop the `FLUSH_MARKER` lines from the output of a command that wasn't streamed
+pub fn drop_flush_markers(mut output: Output) -> Output {
+    output.stdout = output
+        .stdout
+        .split_inclusive(|&b| b == b'\n')
+        .filter(|line| !is_flush_marker(line))
+        .flatten()
+        .copied()
+        .collect();
+    output
+}
+
+fn is_flush_marker(line: &[u8]) -> bool {
+    let line = String::from_utf8_lossy(line);
+    let line = line.trim_end();
+    line == FLUSH_MARKER || line.strip_prefix(FLUSH_MARKER) == Some(":")
+}
+
+/// Read lines into `head` until EOF (false) or a flush marker (true), which is dropped
+async fn read_until_flush(
+    stdout: &mut BufReader<impl AsyncRead + Unpin>,
+    head: &mut Vec<u8>,
+) -> io::Result<bool> {
+    loop {
+        let start = head.len();
+        if stdout.read_until(b'\n', head).await? == 0 {
+            return Ok(false);
+        }
+        if is_flush_marker(&head[start..]) {
+            head.truncate(start);
+            return Ok(true);
+        }
+    }
+}
+
+/// Await `future`, failing with `TimedOut` if `deadline` passes first
+async fn within<T>(
+    deadline: Option<Instant>,
+    future: impl Future<Output = io::Result<T>>,
+) -> io::Result<T> {
+    match deadline {
+        Some(deadline) => timeout_at(deadline, future)
+            .await
+            .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "command timed out"))),
+        None => future.await,
+    }
+}
+
+/// Send `response`'s body, built from the output before the flush marker, and
+/// then the rest of the output as it arrives
+pub fn stream_rest(response: Response, rest: FlushedOutput) -> Response {
+    let (parts, first) = response.into_parts();
+    Response::from_parts(parts, Body::new(FlushedBody { first: Some(first), rest: rest.0 }))
+}
+
+struct FlushedBody {
+    first: Option<Body>,
+    rest: mpsc::Receiver<io::Result<Bytes>>,
+}
+
+impl HttpBody for FlushedBody {
+    type Data = Bytes;
+    type Error = io::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, io::Error>>> {
+        if let Some(first) = &mut self.first {
+            match Pin::new(first).poll_frame(cx) {
+                Poll::Ready(None) => self.first = None,
+                frame => return frame.map(|frame| frame.map(|f| f.map_err(io::Error::other))),
+            }
+        }
+        self.rest
+            .poll_recv(cx)
+            .map(|chunk| chunk.map(|chunk| chunk.map(Frame::data)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::shell_command;
+    use crate::shell::ShellType;
+
+    #[tokio::test]
+    async fn test_finish_without_marker() {
+        let child = shell_command(&ShellType::Sh, "cat; echo err >&2").spawn().unwrap();
+        let Finished::Done(out) = finish_or_flush(child, b"in\n", None).await.unwrap() else {
+            panic!("no flush marker was printed");
+        };
+        assert!(out.status.success());
+        assert_eq!(out.stdout, b"in\n");
+        assert_eq!(out.stderr, b"err\n");
+    }
+
+    #[tokio::test]
+    async fn test_flush_times_out_after_marker() {
+        let child = shell_command(&ShellType::Sh, "echo a; echo @flush:; sleep 5").spawn().unwrap();
+        let timeout = Some(Duration::from_millis(200));
+        let flushed = finish_or_flush(child, b"", timeout).await.unwrap();
+        let Finished::Flushed(head, mut rest, exit) = flushed else {
+            panic!("the flush marker was printed");
+        };
+        assert_eq!(head.stdout, b"a\n");
+        let error = rest.0.recv().await.unwrap().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        let error = exit.status().await.unwrap().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn test_flushed_exit_status() {
+        let child = shell_command(&ShellType::Sh, "echo @flush; echo b; exit 3").spawn().unwrap();
+        let Finished::Flushed(_, mut rest, exit) = finish_or_flush(child, b"", None).await.unwrap()
+        else {
+            panic!("the flush marker was printed");
+        };
+        assert_eq!(&rest.0.recv().await.unwrap().unwrap()[..], b"b\n");
+        assert_eq!(exit.status().await.unwrap().unwrap().code(), Some(3));
+    }
+
+    #[test]
+    fn test_drop_flush_markers() {
+        let stdout = b"a\n@flush:\nb\n@flush\n@flushed\n".to_vec();
+        let output = Output { status: ExitStatus::default(), stdout, stderr: Vec::new() };
+        assert_eq!(drop_flush_markers(output).stdout, b"a\nb\n@flushed\n");
+    }
+}
@@ -1,225 +1,4043 @@
 use axum::{
-    body::Bytes,
-    extract::{Extension, MatchedPath, Path, Query},
-    http::{HeaderMap, Method, StatusCode},
+    body::{Body, Bytes, HttpBody},
+    extract::{rejection::BytesRejection, Extension, MatchedPath, Path, Query, Request, State},
+    http::{
+        header, request::Parts, HeaderMap, HeaderName, HeaderValue, Method, StatusCode, Uri,
+    },
+    middleware::Next,
     response::{IntoResponse, Response},
 };
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use clap::ValueEnum;
+use regex::Regex;
 use serde_json::json;
-use std::{collections::HashMap, process::Stdio, sync::Arc};
-use tokio::{io::AsyncWriteExt, process::Command};
-use tracing::{debug, error, warn};
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    io,
+    process::{ExitStatus, Output},
+    sync::{Arc, LazyLock},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tracing::Level;
+
+use crate::checksum;
+use crate::circuit::Permit;
+use crate::cli::QueryOverflow;
+use crate::csrf::{self, parse_cookies};
+use crate::flush::{
+    drop_flush_markers, finish_or_flush, stream_rest, Exit, Finished, FlushedOutput,
+};
+use crate::nonce::NonceCheck;
+use crate::request_id::RequestId;
+use crate::routes::split_host;
+use crate::runner::{
+    body_env, exit_signal, finish_with_timeout, killed_by, run_with_timeout, shell_command,
+    sudo_command,
+};
+use crate::scheduler::{Overload, Scheduler, Slot};
+use crate::shell::{
+    array_definition, assoc_definition, build_shell_script, is_assoc_key, positional_ref,
+    shell_escape, var_ref, HeaderFormat, ParamMode, ShellType,
+};
+use crate::state::{AppState, RouteConfig};
+use crate::timing::ServerTiming;
+use crate::validate::validate_params;
+use crate::webhook::Summary;
+
+/// Target of handler events; these are gated per route instead of by the global filter
+pub const LOG_TARGET: &str = "sherut::handler";
+
+/// Header naming the kind of failure on error responses
+const ERROR_CODE_HEADER: &str = "x-error-code";
+
+/// Header choosing the shell for a request, see --allow-shell-header
+const SHELL_HEADER: &str = "x-shell";
+
+/// Headers reporting how the command exited
+const EXIT_CODE_HEADER: &str = "x-command-exit-code";
+const SIGNAL_HEADER: &str = "x-command-signal";
+
+/// Header carrying the base64 rendered script, see --debug-echo-script
+const DEBUG_SCRIPT_HEADER: &str = "x-debug-script";
+
+/// Scripts longer than this are cut before being echoed in DEBUG_SCRIPT_HEADER
+const DEBUG_SCRIPT_MAX: usize = 4096;
+
+/// What `respond` found out about the command, for the handler's headers and access log
+#[derive(Default)]
+struct Trace {
+    timing: ServerTiming,
+    /// Exit code of the command; None if it didn't run or was killed by a signal
+    exit_code: Option<i32>,
+    /// Signal that killed the command (Unix only)
+    signal: Option<i32>,
+    /// The rendered script, kept when --debug-echo-script is on
+    script: Option<String>,
+    /// Output still coming from a command that printed `@flush:`
+    flushed: Option<(FlushedOutput, FlushedRun)>,
+}
+
+/// Emit a handler event if it passes the route's log level
+macro_rules! route_log {
+    ($max:expr, $level:expr, $($arg:tt)+) => {
+        if $level <= $max {
+            tracing::event!(target: LOG_TARGET, $level, $($arg)+);
+        }
+    };
+}
+
+/// A command still running after printing `@flush:`, with what has to last until it exits
+struct FlushedRun {
+    exit: Exit,
+    /// Command slots, released only once the command exits
+    slots: Vec<Slot>,
+    /// Circuit breaker permit the real exit status is reported to
+    permit: Option<Permit>,
+    success_codes: Option<Vec<i32>>,
+}
+
+impl FlushedRun {
+    /// Wait for the command to exit, then free its slots and report how it exited
+    async fn finish(self, state: &AppState, log_level: Level, route: &str) -> Option<ExitStatus> {
+        let exit = self.exit.status().await;
+        drop(self.slots);
+        let status = match exit {
+            Some(Ok(status)) => status,
+            Some(Err(e)) => {
+                route_log!(log_level, Level::WARN, "Flushed command for {} failed: {}", route, e);
+                if let Some(permit) = self.permit {
+                    permit.record(false);
+                }
+                return None;
+            }
+            None => {
+                route_log!(log_level, Level::DEBUG, "Client left, killed command for {}", route);
+                return None;
+            }
+        };
+        // The head was sent, so the command produced output
+        let codes = self.success_codes.as_deref();
+        let success = exit_succeeded(&status, codes, &state.success_signals, true);
+        route_log!(
+            log_level,
+            Level::DEBUG,
+            "Flushed command for {} exited with {}",
+            route,
+            status
+        );
+        if let Some(permit) = self.permit {
+            permit.record(success);
+        }
+        Some(status)
+    }
+}
+
+pub async fn handler(
+    Extension(state): Extension<Arc<AppState>>,
+    matched_path: MatchedPath,
+    Path(params): Path<HashMap<String, String>>,
+    Query(query_pairs): Query<Vec<(String, String)>>,
+    parts: Parts,
+    body: Result<Bytes, BytesRejection>,
+) -> Response {
+    if let Some(idle) = &state.idle {
+        idle.touch();
+    }
+    let started = Instant::now();
+    let body = match body {
+        Ok(body) => body,
+        Err(rejection) if rejection.status() == StatusCode::PAYLOAD_TOO_LARGE => {
+            return with_error_code(rejection, "too_large");
+        }
+        Err(rejection) => return rejection.into_response(),
+    };
+    let route = match_route(&state, &parts, matched_path.as_str());
+    let log_level = route.log_level;
+    if state.maintenance.is_on() {
+        route_log!(log_level, Level::DEBUG, "Maintenance mode, not running {}", route.pattern);
+        let body = state.maintenance.body.clone();
+        return (StatusCode::SERVICE_UNAVAILABLE, body).into_response();
+    }
+    if !state.allowed_hosts.is_empty() {
+        let host = request_host(&parts);
+        if !host.as_ref().is_some_and(|host| state.allowed_hosts.contains(host)) {
+            route_log!(log_level, Level::WARN, "Rejecting request for host {:?}", host);
+            return (StatusCode::MISDIRECTED_REQUEST, "Misdirected Request").into_response();
+        }
+    }
+    let deprecation = route.config.deprecated.then(|| route.config.sunset.clone());
+    if deprecation.is_some() {
+        route_log!(log_level, Level::WARN, "Deprecated route called: {}", route.pattern);
+    }
+    let route_name = route.key.clone().unwrap_or_else(|| route.pattern.to_string());
+    let mut trace = Trace::default();
+    let mut response = respond(&state, &parts, route, params, query_pairs, body, &mut trace).await;
+    let flushed = match trace.flushed.take() {
+        Some((rest, run)) => {
+            response = stream_rest(response, rest);
+            Some(run)
+        }
+        None => None,
+    };
+    let exit_code = trace.exit_code;
+
+    let mut response = apply_error_page(&state, response, false);
+    if state.server_timing
+        && let Ok(value) = HeaderValue::from_str(&trace.timing.finish())
+    {
+        response.headers_mut().insert("server-timing", value);
+    }
+    let headers = response.headers_mut();
+    if let Some(code) = trace.exit_code {
+        headers.insert(EXIT_CODE_HEADER, HeaderValue::from(code));
+    }
+    if let Some(signal) = trace.signal {
+        headers.insert(SIGNAL_HEADER, HeaderValue::from(signal));
+    }
+    if let Some(script) = trace.script {
+        let cut = script.floor_char_boundary(DEBUG_SCRIPT_MAX);
+        if let Ok(value) = HeaderValue::from_str(&BASE64.encode(&script[..cut])) {
+            response.headers_mut().insert(DEBUG_SCRIPT_HEADER, value);
+        }
+    }
+
+    if let Some(sunset) = deprecation {
+        let headers = response.headers_mut();
+        headers.insert("deprecation", HeaderValue::from_static("true"));
+        if let Some(sunset) = sunset {
+            headers.insert("sunset", sunset);
+        }
+    }
+
+    // Access log, with the size of the body actually sent. The fields are
+    // for structured (--log-format json) output; exit_status is left out
+    // when no command ran
+    let status = response.status();
+    let bytes = response.body().size_hint().exact();
+    let duration_ms = started.elapsed().as_secs_f64() * 1000.0;
+    route_log!(
+        log_level,
+        Level::INFO,
+        method = %parts.method,
+        path = parts.uri.path(),
+        status = status.as_u16(),
+        duration_ms,
+        exit_status = exit_code,
+        "{} {} -> {} ({} bytes)",
+        parts.method,
+        parts.uri.path(),
+        status.as_u16(),
+        bytes.map_or_else(|| "-".to_string(), |n| n.to_string())
+    );
+    match flushed {
+        // The webhook hears about flushed commands once they exit
+        Some(run) => {
+            let state = state.clone();
+            let (method, path) = (parts.method.clone(), parts.uri.path().to_string());
+            tokio::spawn(async move {
+                let exit = run.finish(&state, log_level, &route_name).await;
+                if let Some(webhook) = &state.webhook {
+                    webhook.notify(&Summary {
+                        route: &route_name,
+                        method: method.as_str(),
+                        path: &path,
+                        status: status.as_u16(),
+                        duration_ms: started.elapsed().as_secs_f64() * 1000.0,
+                        exit_code: exit.and_then(|status| status.code()),
+                    });
+                }
+            });
+        }
+        None => {
+            if let Some(webhook) = &state.webhook {
+                webhook.notify(&Summary {
+                    route: &route_name,
+                    method: parts.method.as_str(),
+                    path: parts.uri.path(),
+                    status: status.as_u16(),
+                    duration_ms,
+                    exit_code,
+                });
+            }
+        }
+    }
+    if state.warn_empty_response && status == StatusCode::OK && bytes == Some(0) {
+        route_log!(
+            log_level,
+            Level::WARN,
+            "Empty 200 response for {} {}; the command succeeded without output",
+            parts.method,
+            parts.uri.path()
+        );
+    }
+    response
+}
+
+/// The route a request matched, with the key of its command and its config
+struct MatchedRoute<'a> {
+    pattern: &'a str,
+    key: Option<String>,
+    config: RouteConfig,
+    log_level: Level,
+}
+
+/// Whether a host-scoped route has exactly `pattern` as its path, so a request
+/// that found no command for its own host is a 404 rather than a config error
+fn only_on_other_hosts(state: &AppState, pattern: &str) -> bool {
+    state.commands.keys().any(|key| {
+        key.split_once(' ')
+            .map(|(_, target)| split_host(target))
+            .is_some_and(|(host, path)| host.is_some() && path == pattern)
+    })
+}
+
+/// Find the command key and config for a request to `pattern`. Host-scoped routes
+/// are tried first, then host-agnostic ones; within each, the method-specific key before ANY.
+fn match_route<'a>(state: &AppState, parts: &Parts, pattern: &'a str) -> MatchedRoute<'a> {
+    let method = parts.method.as_str();
+    let host = request_host(parts).unwrap_or_default();
+    let key = [
+        format!("{} {}{}", method, host, pattern),
+        format!("ANY {}{}", host, pattern),
+        format!("{} {}", method, pattern),
+        format!("ANY {}", pattern),
+    ]
+    .into_iter()
+    .find(|key| state.commands.contains_key(key));
+
+    let config = key
+        .as_ref()
+        .and_then(|key| state.route_configs.get(key))
+        .cloned()
+        .unwrap_or_default();
+    let log_level = config.log_level.unwrap_or(state.log_level).as_level();
+
+    MatchedRoute {
+        pattern,
+        key,
+        config,
+        log_level,
+    }
+}
+
+/// Run the route's command for a request and build the response
+async fn respond(
+    state: &AppState,
+    parts: &Parts,
+    route: MatchedRoute<'_>,
+    params: HashMap<String, String>,
+    mut query_pairs: Vec<(String, String)>,
+    body: Bytes,
+    trace: &mut Trace,
+) -> Response {
+    let Parts {
+        method, uri, headers, ..
+    } = parts;
+    let method_str = method.as_str();
+    let MatchedRoute {
+        pattern: route_pattern,
+        key: route_key,
+        config: route_config,
+        log_level,
+    } = route;
+    // Cap the query before it grows QUERY, QUERY_JSON and the script
+    if let Some(max) = state.max_query_params
+        && query_pairs.len() > max
+    {
+        let count = query_pairs.len();
+        match state.query_overflow {
+            QueryOverflow::Reject => {
+                route_log!(
+                    log_level,
+                    Level::WARN,
+                    "Rejecting {} query params for {} (limit {})",
+                    count,
+                    route_pattern,
+                    max
+                );
+                return (StatusCode::BAD_REQUEST, "Too Many Query Parameters").into_response();
+            }
+            QueryOverflow::Truncate => {
+                route_log!(
+                    log_level,
+                    Level::WARN,
+                    "Keeping the first {} of {} query params for {}",
+                    max,
+                    count,
+                    route_pattern
+                );
+                query_pairs.truncate(max);
+            }
+        }
+    }
+    // Repeated query keys keep their last value; QUERY_JSON and the assoc
+    // path see all of them
+    let query_params: HashMap<String, String> = query_pairs.iter().cloned().collect();
+
+    route_log!(
+        log_level,
+        Level::DEBUG,
+        "Handling {} request for: {} (body: {} bytes)",
+        method_str,
+        route_pattern,
+        body.len()
+    );
+
+    // Answer OPTIONS without spawning unless the route defines its own command,
+    // for the request's host or any host
+    if *method == Method::OPTIONS && state.auto_options {
+        let host = request_host(parts).unwrap_or_default();
+        let explicit = [
+            format!("OPTIONS {}{}", host, route_pattern),
+            format!("OPTIONS {}", route_pattern),
+        ];
+        if !explicit.iter().any(|key| state.commands.contains_key(key)) {
+            let allow = allowed_methods(state, &host, route_pattern);
+            route_log!(
+                log_level,
+                Level::DEBUG,
+                "Auto OPTIONS for {}: Allow: {}",
+                route_pattern,
+                allow
+            );
+            return (StatusCode::NO_CONTENT, [(header::ALLOW, allow)]).into_response();
+        }
+    }
+
+    let command_template = match route_key.as_ref().and_then(|key| state.commands.get(key)) {
+        Some(cmd) => cmd,
+        // The path only exists for other hosts
+        None if only_on_other_hosts(state, route_pattern) =>
+        {
+            return (StatusCode::NOT_FOUND, "Route not found".to_string()).into_response();
+        }
+        None => {
+            route_log!(
+                log_level,
+                Level::ERROR,
+                "Route config missing for: {} {}",
+                method_str, route_pattern
+            );
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Config Error".to_string(),
+            )
+                .into_response();
+        }
+    };
+
+    if let Some(cookie_name) = &state.csrf_cookie
+        && !csrf::check(method, headers, cookie_name)
+    {
+        route_log!(log_level, Level::DEBUG, "CSRF token check failed for {}", route_pattern);
+        return (StatusCode::FORBIDDEN, "Forbidden".to_string()).into_response();
+    }
+
+    if let Some((rate, key)) = route_config.rate_limit.zip(route_key.as_deref())
+        && let Err(retry_after) = state.rate_limiter.check(key, rate)
+    {
+        route_log!(log_level, Level::WARN, "Rate limit exceeded for {}", key);
+        return throttled(state, route_pattern, uri.path(), retry_after, log_level).await;
+    }
+
+    if let Some(limit) = route_config.max_body_size.or(state.max_body_size)
+        && body.len() > limit
+    {
+        route_log!(
+            log_level,
+            Level::DEBUG,
+            "Rejecting {} byte body for {} (limit {})",
+            body.len(),
+            route_pattern,
+            limit
+        );
+        let response = (StatusCode::PAYLOAD_TOO_LARGE, "Payload Too Large".to_string());
+        return with_error_code(response, "too_large");
+    }
+
+    // Reject bodies whose content type the route does not accept
+    let accepted_types = route_config
+        .consumes
+        .as_ref()
+        .unwrap_or(&state.allowed_content_types);
+    if !body.is_empty() && !accepted_types.is_empty() {
+        let content_type = headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("");
+        if !content_type_allowed(content_type, accepted_types) {
+            route_log!(
+                log_level,
+                Level::DEBUG,
+                "Rejecting content type '{}' for {}",
+                content_type,
+                route_pattern
+            );
+            return (
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "Unsupported Media Type".to_string(),
+            )
+                .into_response();
+        }
+    }
+
+    if route_config.require_body && body.is_empty() {
+        route_log!(log_level, Level::DEBUG, "Rejecting empty body for {}", route_pattern);
+        return (StatusCode::BAD_REQUEST, "Request body required".to_string()).into_response();
+    }
+    if route_config.forbid_body && !body.is_empty() {
+        route_log!(log_level, Level::DEBUG, "Rejecting unexpected body for {}", route_pattern);
+        return (StatusCode::BAD_REQUEST, "Request body not allowed".to_string()).into_response();
+    }
+
+    if state.verify_checksum
+        && let Err(e) = checksum::verify(headers, &body)
+    {
+        route_log!(log_level, Level::DEBUG, "Rejecting body for {}: {}", route_pattern, e);
+        return (StatusCode::BAD_REQUEST, e).into_response();
+    }
+
+    if let Err(e) = validate_params(&state.validators, &params, &query_params) {
+        route_log!(log_level, Level::DEBUG, "Rejecting request for {}: {}", route_pattern, e);
+        return (StatusCode::BAD_REQUEST, e).into_response();
+    }
+
+    // Wildcards typically name files below a directory, so they may not climb out of it
+    let climbs_out = wildcard_params(route_pattern).into_iter().any(|name| {
+        params
+            .get(name)
+            .is_some_and(|value| value.split('/').any(|segment| segment == ".."))
+    });
+    if climbs_out {
+        route_log!(log_level, Level::DEBUG, "Rejecting '..' in wildcard for {}", route_pattern);
+        return (StatusCode::BAD_REQUEST, "Invalid path".to_string()).into_response();
+    }
+
+    // RPC routes run the command registered for the body's `method` field
+    let mut rpc_method = None;
+    let command_template = if route_config.rpc {
+        match rpc_command(&state.rpc_commands, &body) {
+            Ok((method, command)) => {
+                rpc_method = Some(method);
+                command
+            }
+            Err(error) => {
+                route_log!(
+                    log_level,
+                    Level::DEBUG,
+                    "Rejecting RPC call for {}: {}",
+                    route_pattern,
+                    error.2
+                );
+                return rpc_error(error);
+            }
+        }
+    } else {
+        command_template
+    };
+
+    // Conditional routes run their alternative, or answer 503, when the condition fails
+    let command_template = match &route_config.when {
+        Some(condition) if !condition.matches(headers, &query_params) => {
+            route_log!(log_level, Level::DEBUG, "Condition not met for {}", route_pattern);
+            match &route_config.otherwise {
+                Some(command) => command,
+                None => {
+                    return (StatusCode::SERVICE_UNAVAILABLE, "Service Unavailable".to_string())
+                        .into_response();
+                }
+            }
+        }
+        _ => command_template,
+    };
+
+    // A shell picked through X-Shell without associative arrays gets JSON instead
+    let shell = request_shell(state, headers, log_level);
+    let formats = if shell == state.shell || shell.supports_assoc_arrays() {
+        [&state.header_format, &state.query_format, &state.cookie_format].map(Clone::clone)
+    } else {
+        [HeaderFormat::Json, HeaderFormat::Json, HeaderFormat::Json]
+    };
+    let [header_format, query_format, cookie_format] = formats;
+
+    // Replace :param placeholders in command with actual values, or with references
+    // to them so that values never become part of the command text
+    let (command_with_params, param_vars) = match state.param_mode {
+        ParamMode::Substitute => {
+            (substitute_params(command_template, &params, &shell), Vec::new())
+        }
+        mode => reference_params(
+            command_template,
+            &params,
+            &shell,
+            mode,
+            &param_names(route_pattern),
+        ),
+    };
+
+    // Collect headers into a map
+    let mut headers_map: HashMap<String, String> = HashMap::new();
+    for (key, value) in headers.iter() {
+        if let Ok(val_str) = value.to_str() {
+            headers_map.insert(key.to_string(), val_str.to_string());
+        }
+    }
+
+    if state.strip_hop_by_hop {
+        strip_hop_by_hop(&mut headers_map);
+    }
+
+    // Pairs without `=` are skipped; a repeated name keeps its first value,
+    // which browsers send for the most specific path
+    let mut cookies: HashMap<String, String> = HashMap::new();
+    let cookie_pairs = headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(parse_cookies);
+    for (name, value) in cookie_pairs.filter(|(name, _)| !name.is_empty()) {
+        cookies.entry(name.to_string()).or_insert_with(|| value.to_string());
+    }
+
+    // Build the shell script based on shell type and header format
+    let mut shell_script = build_shell_script(
+        &shell,
+        &header_format,
+        &headers_map,
+        &query_format,
+        &query_pairs,
+        &command_with_params,
+    );
+
+    // Build command with environment inheritance; params are also passed
+    // positionally ($1, $2, ...) in the order they appear in the route
+    let ordered = ordered_params(route_pattern, &params);
+
+    // Wildcard params are also split into segments, e.g. *path -> PATH_SEGMENTS=(a b c);
+    // sh has no arrays and gets them newline-separated in the environment instead
+    let mut segment_vars = Vec::new();
+    for name in wildcard_params(route_pattern) {
+        let Some(value) = params.get(name) else {
+            continue;
+        };
+        let segments: Vec<&str> = value.split('/').filter(|s| !s.is_empty()).collect();
+        let var = format!("{}_SEGMENTS", name.to_uppercase());
+        match array_definition(&shell, &var, &segments) {
+            Some(definition) => shell_script.insert_str(0, &definition),
+            None => segment_vars.push((var, segments.join("\n"))),
+        }
+    }
+
+    if cookie_format == HeaderFormat::Assoc {
+        let entries = cookies.iter().filter(|(name, _)| is_assoc_key(name));
+        let entries = entries.map(|(name, value)| (name.as_str(), value.as_str()));
+        if let Some(definition) = assoc_definition(&shell, "COOKIES", entries) {
+            shell_script.insert_str(0, &definition);
+        }
+    }
+
+    let mut cmd = match &state.sudo_user {
+        Some(user) => sudo_command(user, &shell, &shell_script),
+        None => shell_command(&shell, &shell_script),
+    };
+    if let Some(dir) = route_config.workdir.as_ref().or(state.workdir.as_ref()) {
+        cmd.current_dir(dir);
+    }
+    if state.debug_echo_script {
+        trace.script = Some(shell_script);
+    }
+    cmd.envs(segment_vars);
+    cmd.envs(param_vars);
+    if shell.takes_positional_args() {
+        cmd.arg("sherut").args(&ordered);
+    }
+    cmd.env("PARAMS_ORDERED", json!(ordered).to_string());
+    for (name, value) in &params {
+        cmd.env(format!("PARAM_{}", name), value);
+    }
+    // Routing context, e.g. for ANY routes
+    cmd.env("REQUEST_METHOD", method_str);
+    cmd.env("REQUEST_PATH", uri.path());
+    cmd.env("MATCHED_ROUTE", route_pattern);
+    cmd.env("REQUEST_SCHEME", request_scheme(state, headers));
+    if let Some(method) = &rpc_method {
+        cmd.env("RPC_METHOD", method);
+    }
+    for (cookie, var) in &state.cookie_env {
+        if let Some(value) = cookies.get(cookie) {
+            cmd.env(var, value);
+        }
+    }
+    if let Some(RequestId(id)) = parts.extensions.get::<RequestId>() {
+        cmd.env("REQUEST_ID", id);
+    }
+    let start_time = state.started.wall.duration_since(UNIX_EPOCH).unwrap_or_default();
+    cmd.env("SERVER_START_TIME", start_time.as_secs().to_string());
+    let uptime = state.started.instant.elapsed();
+    cmd.env("SERVER_UPTIME_SECONDS", uptime.as_secs().to_string());
+
+    #[cfg(all(target_os = "linux", feature = "cgroup"))]
+    if let Some(procs) = &state.cgroup_procs
+        && let Err(e) = crate::cgroup::attach(&mut cmd, procs)
+    {
+        route_log!(log_level, Level::ERROR, "Cannot open {}: {}", procs.display(), e);
+        return with_error_code((StatusCode::INTERNAL_SERVER_ERROR, e.to_string()), "spawn_failed");
+    }
+
+    // Keys that can't go into the assoc arrays are only reachable through JSON
+    let assoc_fallback = |format: &HeaderFormat, map: &HashMap<String, String>| {
+        *format == HeaderFormat::Assoc
+            && shell.supports_assoc_arrays()
+            && map.keys().any(|key| !is_assoc_key(key))
+    };
+
+    // For JSON header format, also set as environment variable
+    if header_format == HeaderFormat::Json
+        || assoc_fallback(&header_format, &headers_map)
+    {
+        let headers_json = json!(headers_map).to_string();
+        cmd.env("HEADERS_JSON", &headers_json);
+    }
+
+    // For JSON query format, also set as environment variable
+    if query_format == HeaderFormat::Json
+        || assoc_fallback(&query_format, &query_params)
+    {
+        let query_json = query_json(&query_pairs).to_string();
+        cmd.env("QUERY_JSON", &query_json);
+    }
+
+    // For JSON cookie format, also set as environment variable
+    if cookie_format == HeaderFormat::Json || assoc_fallback(&cookie_format, &cookies) {
+        cmd.env("COOKIES_JSON", json!(cookies).to_string());
+    }
+
+    // Wait for a slot when the number of running commands is limited,
+    // overall and for the shell the command runs in
+    let queued = Instant::now();
+    let schedulers = [state.concurrency.as_ref(), state.shell_concurrency.get(&shell)];
+    let mut slots = Vec::new();
+    for scheduler in schedulers.into_iter().flatten() {
+        let slot = match state.on_overload {
+            Overload::Queue => acquire_slot(scheduler, method, state.queue_timeout).await,
+            Overload::Reject => scheduler.try_acquire(),
+        };
+        match slot {
+            Some(slot) => {
+                route_log!(
+                    log_level,
+                    Level::DEBUG,
+                    "Got a command slot for {} ({}/{} in flight)",
+                    route_pattern,
+                    scheduler.in_flight(),
+                    scheduler.max()
+                );
+                slots.push(slot);
+            }
+            None => {
+                let reason = match state.on_overload {
+                    Overload::Queue => "Queue timeout",
+                    Overload::Reject => "No free command slot",
+                };
+                route_log!(log_level, Level::WARN, "{} for {}", reason, route_pattern);
+                let retry_after = state.queue_timeout.unwrap_or_default().as_secs().max(1);
+                return (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    [(header::RETRY_AFTER, retry_after.to_string())],
+                    "Service Unavailable".to_string(),
+                )
+                    .into_response();
+            }
+        }
+    }
+    trace.timing.record("queue", queued.elapsed());
+
+    // Let scripts budget their own downstream calls
+    if let Some(timeout) = state.command_timeout {
+        let deadline = SystemTime::now() + timeout;
+        let deadline_ms = deadline.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+        cmd.env("REQUEST_TIMEOUT_MS", timeout.as_millis().to_string());
+        cmd.env("REQUEST_DEADLINE_MS", deadline_ms.to_string());
+    }
+
+    // Pass the body through the request filter; its stdout is what the command reads
+    let body = match &state.request_filter {
+        Some(filter) => {
+            let mut filter_cmd = shell_command(&state.shell, filter);
+            match run_with_timeout(&mut filter_cmd, &body, state.command_timeout).await {
+                Ok(out) if out.status.success() => Bytes::from(out.stdout),
+                Ok(out) => {
+                    let stderr = String::from_utf8_lossy(&out.stderr);
+                    route_log!(log_level, Level::DEBUG, "Request filter rejected body: {}", stderr);
+                    return (StatusCode::BAD_REQUEST, format!("Bad Request:\n{}", stderr))
+                        .into_response();
+                }
+                Err(e) => {
+                    route_log!(log_level, Level::ERROR, "Request filter failed: {}", e);
+                    return (StatusCode::INTERNAL_SERVER_ERROR, format!("Error:\n{}", e))
+                        .into_response();
+                }
+            }
+        }
+        None => body,
+    };
+
+    if state.body_format.uses_env() {
+        let (var, value) = body_env(&body);
+        if state.max_body_env.is_none_or(|max| value.len() <= max) {
+            cmd.env(var, value);
+        } else {
+            route_log!(
+                log_level,
+                Level::WARN,
+                "Not setting {} for {}: {} bytes exceeds --max-body-env",
+                var,
+                route_pattern,
+                value.len()
+            );
+        }
+    }
+
+    // Reject replays; checked late, so requests rejected for any other reason don't
+    // use up their nonce. Only the circuit breaker comes after, so a replay can't
+    // take a half-open route's trial.
+    if let Some(nonces) = &state.nonces {
+        let nonce = headers.get(nonces.header.as_str()).and_then(|v| v.to_str().ok());
+        let Some(nonce) = nonce.filter(|n| !n.is_empty()) else {
+            route_log!(log_level, Level::DEBUG, "Missing nonce for {}", route_pattern);
+            return (StatusCode::BAD_REQUEST, "Nonce required".to_string()).into_response();
+        };
+        match nonces.check(nonce) {
+            NonceCheck::Fresh => {}
+            NonceCheck::Replayed => {
+                route_log!(log_level, Level::WARN, "Replayed nonce for {}", route_pattern);
+                return (StatusCode::CONFLICT, "Nonce already used".to_string()).into_response();
+            }
+            NonceCheck::Full => {
+                route_log!(log_level, Level::WARN, "Too many nonces in the window, rejecting");
+                return (StatusCode::SERVICE_UNAVAILABLE, "Service Unavailable".to_string())
+                    .into_response();
+            }
+        }
+    }
+
+    // Short-circuit routes whose command keeps failing; checked last, so requests
+    // turned away for any other reason don't take the trial
+    let mut permit = None;
+    if let Some((breaker, key)) = state.circuit_breaker.as_ref().zip(route_key.as_deref()) {
+        let Some(granted) = breaker.permit(key) else {
+            route_log!(log_level, Level::WARN, "Circuit open for {}, not running command", key);
+            return (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Service Unavailable".to_string(),
+            )
+                .into_response();
+        };
+        permit = Some(granted);
+    }
+
+    // Spawn process and write body to stdin
+    let input = match state.body_format.uses_stdin() {
+        true => route_config.stdin_encoding.encode(&body),
+        false => Cow::Borrowed(&[][..]),
+    };
+    // Routes that post-process their output need all of it, so they don't stream
+    // and only drop the flush marker
+    let can_flush = route_config.jq.is_none()
+        && route_config.charset.is_none()
+        && route_config.wrap_html.is_none()
+        && route_config.split_delimiter.is_none()
+        && !route_config.as_sse;
+    let spawning = Instant::now();
+    let output = match cmd.spawn() {
+        Ok(child) => {
+            trace.timing.record("spawn", spawning.elapsed());
+            let running = Instant::now();
+            let finished = if can_flush {
+                finish_or_flush(child, &input, state.command_timeout).await
+            } else {
+                finish_with_timeout(child, &input, state.command_timeout)
+                    .await
+                    .map(|out| Finished::Done(drop_flush_markers(out)))
+            };
+            trace.timing.record("exec", running.elapsed());
+            finished
+        }
+        Err(e) => Err(e),
+    };
+    trace.timing.start_processing();
+    // A flushed command is still running: its output so far becomes the start
+    // of the response and the handler streams the rest
+    let output = match output {
+        Ok(Finished::Done(out)) => {
+            trace.exit_code = out.status.code();
+            trace.signal = exit_signal(&out.status);
+            Ok(out)
+        }
+        Ok(Finished::Flushed(head, rest, exit)) => {
+            route_log!(log_level, Level::DEBUG, "Flushing output of {} early", route_pattern);
+            let run = FlushedRun {
+                exit,
+                slots: std::mem::take(&mut slots),
+                permit: permit.take(),
+                success_codes: route_config.success_codes.clone(),
+            };
+            trace.flushed = Some((rest, run));
+            Ok(head)
+        }
+        Err(e) => Err(e),
+    };
+
+    let succeeded = |out: &Output| {
+        let codes = route_config.success_codes.as_deref();
+        exit_succeeded(&out.status, codes, &state.success_signals, !out.stdout.is_empty())
+    };
+
+    // A flushed command took the permit along and reports its real exit status once it ends
+    if let Some(permit) = permit {
+        permit.record(matches!(&output, Ok(out) if succeeded(out)));
+    }
+
+    match output {
+        Ok(out) => {
+            let stdout = String::from_utf8_lossy(&out.stdout).to_string();
+            let stderr = String::from_utf8_lossy(&out.stderr).to_string();
+
+            if !succeeded(&out) {
+                route_log!(log_level, Level::WARN, "Command failed. Stderr: {}", stderr);
+                let status = state
+                    .status_from_exit
+                    .and_then(|base| exit_status(base, out.status.code()?))
+                    .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+                return with_error_code((status, format!("Error:\n{}", stderr)), "nonzero_exit");
+            }
+
+            if let Some(delimiter) = &route_config.split_delimiter {
+                return split_to_json(&stdout, delimiter);
+            }
+
+            // --- MAGIC PREFIX PARSING START ---
+            let mut builder = Response::builder().status(StatusCode::OK);
+            let mut body_accum = String::new();
+            let mut content_type_set = false;
+            let mut status = StatusCode::OK;
+            let mut status_set = false;
+            let mut location_set = false;
+            let mut location_values: HashMap<String, String> = HashMap::new();
+            let mut headers_set = HashSet::new();
+            let mut header_lines = 0;
+            // Once a binary Content-Type is set, the output after the magic
+            // lines is passed through byte for byte. So is output that isn't
+            // UTF-8 and has no magic lines at all, e.g. `cat image.png`.
+            let passthrough =
+                std::str::from_utf8(&out.stdout).is_err() && !has_magic_lines(&out.stdout);
+            let mut binary = passthrough;
+            let mut raw_body: &[u8] = if passthrough { &out.stdout } else { &[] };
+            // With @body-start:/@body-end: markers, only the lines between them
+            // are the body; the rest is logged
+            let mut in_body = !out.stdout.split(|&b| b == b'\n').any(|line| {
+                is_marker(&String::from_utf8_lossy(line), "@body-start")
+            });
+
+            let mut rest: &[u8] = if passthrough { &[] } else { &out.stdout };
+            while !rest.is_empty() {
+                let (line, next) = split_line(rest);
+                if binary && !line.starts_with(b"@") {
+                    raw_body = rest;
+                    break;
+                }
+                rest = next;
+                let line = String::from_utf8_lossy(line);
+                let line = line.as_ref();
+
+                if let Some(val) = line.strip_prefix("@header:") {
+                    // Syntax: @header: Content-Type: application/json
+                    // The name ends at the first ':', so values may contain colons
+                    // (e.g. URLs with ports) and may be empty
+                    if let Some((k, v)) = val.split_once(':') {
+                        let (k, v) = (k.trim(), v.trim());
+                        let (Ok(name), Ok(value)) =
+                            (HeaderName::from_bytes(k.as_bytes()), HeaderValue::from_str(v))
+                        else {
+                            route_log!(
+                                log_level,
+                                Level::WARN,
+                                "Skipping invalid header '{}: {}' set by {}",
+                                k,
+                                v,
+                                route_pattern
+                            );
+                            continue;
+                        };
+                        let header_name = name.as_str().to_string();
+                        if !response_header_allowed(state, &header_name) {
+                            route_log!(
+                                log_level,
+                                Level::WARN,
+                                "Dropping disallowed header '{}' set by {}",
+                                k,
+                                route_pattern
+                            );
+                            continue;
+                        }
+                        header_lines += 1;
+                        if let Some(max) = state.max_response_headers
+                            && header_lines > max
+                        {
+                            if header_lines == max + 1 {
+                                route_log!(
+                                    log_level,
+                                    Level::WARN,
+                                    "{} set more than {} headers, ignoring the rest",
+                                    route_pattern,
+                                    max
+                                );
+                            }
+                            continue;
+                        }
+                        if header_name == "content-type" {
+                            content_type_set = true;
+                            binary = is_binary_content_type(v);
+                        }
+                        if header_name == "location" {
+                            location_set = true;
+                        }
+                        headers_set.insert(header_name);
+                        builder = builder.header(name, value);
+                        route_log!(log_level, Level::DEBUG, "Set Header: {} -> {}", k, v);
+                    }
+                } else if let Some(val) = line.strip_prefix("@status:") {
+                    // Syntax: @status: 404
+                    if let Ok(code) = val.trim().parse::<u16>()
+                        && let Ok(status_code) = StatusCode::from_u16(code)
+                    {
+                        builder = builder.status(status_code);
+                        status = status_code;
+                        status_set = true;
+                        route_log!(log_level, Level::DEBUG, "Set Status: {}", status_code);
+                    }
+                } else if let Some(val) = line.strip_prefix("@cookie:") {
+                    // Syntax: @cookie: session=abc; Path=/; HttpOnly
+                    // Each line adds its own Set-Cookie header
+                    match HeaderValue::from_str(val.trim()) {
+                        Ok(cookie) => {
+                            headers_set.insert("set-cookie".to_string());
+                            builder = builder.header(header::SET_COOKIE, cookie);
+                            route_log!(log_level, Level::DEBUG, "Set Cookie: {}", val.trim());
+                        }
+                        Err(_) => {
+                            route_log!(log_level, Level::WARN, "Invalid cookie: {}", val.trim());
+                        }
+                    }
+                } else if let Some(val) = line.strip_prefix("@location:") {
+                    // Syntax: @location: id=42 (fills :id in --route-created-location)
+                    if let Some((k, v)) = val.split_once('=') {
+                        location_values.insert(k.trim().to_string(), v.trim().to_string());
+                    }
+                } else if is_marker(line, "@body-start") {
+                    in_body = true;
+                } else if is_marker(line, "@body-end") {
+                    in_body = false;
+                } else if in_body {
+                    // Normal content
+                    body_accum.push_str(line);
+                    body_accum.push('\n');
+                } else {
+                    route_log!(log_level, Level::INFO, "{}: {}", route_pattern, line);
+                }
+            }
+
+            // Output with nothing in it gets the empty-body status, e.g. 204
+            let empty = if state.treat_whitespace_as_empty {
+                body_accum.trim().is_empty()
+            } else {
+                body_accum.is_empty()
+            };
+            let empty_status = state
+                .empty_status
+                .or(state.treat_whitespace_as_empty.then_some(StatusCode::NO_CONTENT));
+            let empty_response = !binary && !status_set && empty && empty_status.is_some();
+            if empty_response && let Some(code) = empty_status {
+                route_log!(log_level, Level::DEBUG, "Empty output, responding {}", code);
+                builder = builder.status(code);
+                status = code;
+                body_accum.clear();
+                content_type_set = true;
+            }
+
+            if !binary
+                && !empty_response
+                && let Some(filter) = &route_config.jq
+            {
+                match filter.apply(&body_accum) {
+                    Ok(filtered) => body_accum = filtered,
+                    Err(e) => {
+                        route_log!(log_level, Level::WARN, "jq failed on {}: {}", route_pattern, e);
+                        return (StatusCode::INTERNAL_SERVER_ERROR, format!("Error:\n{}", e))
+                            .into_response();
+                    }
+                }
+            }
+
+            // Send each output line as a server-sent event
+            if !binary && route_config.as_sse {
+                body_accum = to_sse(&body_accum);
+                if !content_type_set {
+                    builder = builder.header("Content-Type", "text/event-stream");
+                    content_type_set = true;
+                }
+            }
+
+            // Route header templates, unless the command set the header itself
+            for (name, template) in &route_config.response_headers {
+                let header_name = name.to_lowercase();
+                if headers_set.contains(&header_name) {
+                    continue;
+                }
+                let value = render_header_template(template, &params, &query_params, &headers_map);
+                let Ok(value) = HeaderValue::from_str(&value) else {
+                    route_log!(log_level, Level::WARN, "Invalid value for header {}", name);
+                    continue;
+                };
+                content_type_set |= header_name == "content-type";
+                location_set |= header_name == "location";
+                route_log!(log_level, Level::DEBUG, "Set Header: {} -> {:?}", name, value);
+                builder = builder.header(name, value);
+            }
+
+            // Embed the output into the route's HTML template
+            if !binary
+                && !empty_response
+                && let Some(template) = &route_config.wrap_html
+            {
+                let content = if state.no_escape {
+                    body_accum
+                } else {
+                    escape_html(&body_accum)
+                };
+                body_accum = template.replace("{{body}}", &content);
+                if !content_type_set {
+                    builder = builder.header("Content-Type", "text/html");
+                    content_type_set = true;
+                }
+            }
+
+            // Point 201 responses at the created resource
+            if status == StatusCode::CREATED
+                && !location_set
+                && let Some(template) = &route_config.created_location
+            {
+                let mut values = params.clone();
+                values.extend(location_values);
+                let location = render_location(template, &values);
+                route_log!(log_level, Level::DEBUG, "Set Location: {}", location);
+                builder = builder.header(header::LOCATION, location);
+            }
+
+            if binary {
+                // Content-Type is set unless the output was passed through as is
+                if !content_type_set {
+                    let content_type = state
+                        .method_content_types
+                        .get(method_str)
+                        .map_or("application/octet-stream", String::as_str);
+                    builder = builder.header("Content-Type", content_type);
+                }
+                let mut body = body_accum.into_bytes();
+                body.extend_from_slice(raw_body);
+                return build_response(builder, Body::from(body), route_pattern, log_level);
+            }
+
+            // Fall back to the method's default Content-Type, then auto-detection
+            if !content_type_set
+                && let Some(default_type) = state.method_content_types.get(method_str)
+            {
+                builder = builder.header("Content-Type", default_type);
+                content_type_set = true;
+                route_log!(
+                    log_level,
+                    Level::DEBUG,
+                    "Default Content-Type for {}: {}",
+                    method_str,
+                    default_type
+                );
+            }
+
+            // Auto-detect Content-Type if not explicitly set
+            if !content_type_set {
+                let detected = detect_content_type(&body_accum);
+                builder = builder.header("Content-Type", detected);
+                route_log!(log_level, Level::DEBUG, "Auto-detected Content-Type: {}", detected);
+            }
+
+            // Transcode to the route's charset and declare it
+            let body = match &route_config.charset {
+                Some(charset) => {
+                    if let Some(headers) = builder.headers_mut()
+                        && let Some(content_type) = headers.get(header::CONTENT_TYPE)
+                        && let Ok(content_type) = content_type.to_str()
+                        && let Ok(value) =
+                            HeaderValue::from_str(&with_charset(content_type, &charset.label))
+                    {
+                        headers.insert(header::CONTENT_TYPE, value);
+                    }
+                    Body::from(charset.encoding.encode(&body_accum).0.into_owned())
+                }
+                None => Body::from(body_accum),
+            };
+
+            // Return the built response
+            build_response(builder, body, route_pattern, log_level)
+            // --- MAGIC PREFIX PARSING END ---
+        }
+        Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+            route_log!(log_level, Level::WARN, "Command timed out for {}", route_pattern);
+            let response = (StatusCode::GATEWAY_TIMEOUT, "Command timed out".to_string());
+            with_error_code(response, "timeout")
+        }
+        Err(e) => {
+            route_log!(log_level, Level::ERROR, "Cannot run command for {}: {}", route_pattern, e);
+            with_error_code((StatusCode::INTERNAL_SERVER_ERROR, e.to_string()), "spawn_failed")
+        }
+    }
+}
+
+/// Finish the response built from a command's output. A header that slipped
+/// through invalid fails the builder; answer 500 then instead of panicking.
+fn build_response(
+    builder: axum::http::response::Builder,
+    body: Body,
+    route_pattern: &str,
+    log_level: Level,
+) -> Response {
+    match builder.body(body) {
+        Ok(response) => response,
+        Err(e) => {
+            route_log!(log_level, Level::ERROR, "Invalid response from {}: {}", route_pattern, e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error").into_response()
+        }
+    }
+}
+
+/// Replace any charset parameter of `content_type` with `charset`
+fn with_charset(content_type: &str, charset: &str) -> String {
+    let mut parts: Vec<&str> = content_type
+        .split(';')
+        .map(str::trim)
+        .filter(|part| !part.to_ascii_lowercase().starts_with("charset="))
+        .collect();
+    let charset = format!("charset={}", charset);
+    parts.push(&charset);
+    parts.join("; ")
+}
+
+/// Tag a failed command's response with a stable X-Error-Code (`spawn_failed`,
+/// `nonzero_exit`, `timeout`, `too_large`) so failures can be told apart without
+/// parsing the body; X-Request-Id identifies the request
+fn with_error_code(response: impl IntoResponse, code: &'static str) -> Response {
+    let mut response = response.into_response();
+    response
+        .headers_mut()
+        .insert(ERROR_CODE_HEADER, HeaderValue::from_static(code));
+    response
+}
+
+/// Escape text for safe inclusion in HTML
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Query params as a JSON object; repeated keys become arrays of their values
+fn query_json(query_pairs: &[(String, String)]) -> serde_json::Value {
+    let mut object = serde_json::Map::new();
+    for (key, value) in query_pairs {
+        match object.get_mut(key) {
+            Some(serde_json::Value::Array(values)) => values.push(json!(value)),
+            Some(single) => *single = json!([single.take(), value]),
+            None => {
+                object.insert(key.clone(), json!(value));
+            }
+        }
+    }
+    serde_json::Value::Object(object)
+}
+
+/// Wildcard params (`{*name}`) of a route pattern
+static WILDCARD_PARAM: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\{\*([a-zA-Z0-9_]+)\}").expect("Invalid regex"));
+
+/// Params (`{name}` or `{*name}`) of a route pattern
+static ROUTE_PARAM: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\{\*?([a-zA-Z0-9_]+)\}").expect("Invalid regex"));
+
+/// Names of the wildcard params (`{*name}`) in a route pattern
+fn wildcard_params(route_pattern: &str) -> Vec<&str> {
+    WILDCARD_PARAM
+        .captures_iter(route_pattern)
+        .filter_map(|caps| caps.get(1).map(|m| m.as_str()))
+        .collect()
+}
+
+/// Names of the params in the order they appear in the route pattern
+fn param_names(route_pattern: &str) -> Vec<&str> {
+    ROUTE_PARAM
+        .captures_iter(route_pattern)
+        .filter_map(|caps| caps.get(1).map(|m| m.as_str()))
+        .collect()
+}
+
+/// Param values in the order their names appear in the route pattern
+fn ordered_params(route_pattern: &str, params: &HashMap<String, String>) -> Vec<String> {
+    param_names(route_pattern)
+        .into_iter()
+        .filter_map(|name| params.get(name).cloned())
+        .collect()
+}
+
+/// `:name` placeholders of a Location template
+static LOCATION_PLACEHOLDER: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r":([a-zA-Z0-9_]+)").expect("Invalid regex"));
+
+/// Fill `:name` placeholders of a Location template with URL-encoded values,
+/// leaving unknown placeholders untouched
+fn render_location(template: &str, values: &HashMap<String, String>) -> String {
+    LOCATION_PLACEHOLDER
+        .replace_all(template, |caps: &regex::Captures| match values.get(&caps[1]) {
+            Some(value) => url_encode(value),
+            None => caps[0].to_string(),
+        })
+        .to_string()
+}
+
+/// `:name`, `:query.name` and `:header.name` placeholders of a response header template
+static HEADER_PLACEHOLDER: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r":(?:(query|header)\.)?([a-zA-Z0-9_-]+)").expect("Invalid regex")
+});
+
+/// Fill `:name`, `:query.name` and `:header.name` placeholders of a response header
+/// template. Unknown route params are left untouched, missing query params and
+/// headers become empty.
+fn render_header_template(
+    template: &str,
+    params: &HashMap<String, String>,
+    query_params: &HashMap<String, String>,
+    headers: &HashMap<String, String>,
+) -> String {
+    HEADER_PLACEHOLDER
+        .replace_all(template, |caps: &regex::Captures| {
+            let name = &caps[2];
+            match caps.get(1).map(|m| m.as_str()) {
+                Some("query") => query_params.get(name).cloned().unwrap_or_default(),
+                Some(_) => headers.get(&name.to_lowercase()).cloned().unwrap_or_default(),
+                None => params.get(name).cloned().unwrap_or_else(|| caps[0].to_string()),
+            }
+        })
+        .to_string()
+}
+
+/// `:name` placeholders with an optional modifier, e.g. `:id|urlencode`. Names
+/// match route params the way routes.rs parses them, so `:id` never matches
+/// inside `:identifier`.
+static PARAM_PLACEHOLDER: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r":([a-zA-Z0-9_]+)(?:\|(urlencode|shell|upper|lower|raw))?").expect("Invalid regex")
+});
+
+/// Replace `:name` and `:name|modifier` placeholders with param values in one
+/// pass, leaving placeholders that name no param untouched. Bare placeholders
+/// are shell-escaped, same as `:name|shell`.
+fn substitute_params(
+    template: &str,
+    params: &HashMap<String, String>,
+    shell: &ShellType,
+) -> String {
+    PARAM_PLACEHOLDER
+        .replace_all(template, |caps: &regex::Captures| match params.get(&caps[1]) {
+            Some(value) => {
+                let modifier = caps.get(2).map_or("shell", |m| m.as_str());
+                apply_modifier(value, modifier, shell)
+            }
+            None => caps[0].to_string(),
+        })
+        .to_string()
+}
+
+/// Replace `:name` and `:name|modifier` placeholders with quoted references to
+/// `PARAM_name` (ParamMode::Env) or the positional arguments (ParamMode::Positional).
+/// Values changed by a modifier are referenced as `PARAM_name_MODIFIER`, returned
+/// alongside the command since they still need to be set.
+fn reference_params(
+    template: &str,
+    params: &HashMap<String, String>,
+    shell: &ShellType,
+    mode: ParamMode,
+    names: &[&str],
+) -> (String, Vec<(String, String)>) {
+    // Positions follow the arguments the command is given, see ordered_params
+    let positions: Vec<&str> = names
+        .iter()
+        .copied()
+        .filter(|name| params.contains_key(*name))
+        .collect();
+    // Shells without positional arguments fall back to the environment
+    let reference = |key: &str| {
+        positions
+            .iter()
+            .position(|name| *name == key)
+            .filter(|_| mode == ParamMode::Positional)
+            .and_then(|index| positional_ref(shell, index + 1))
+            .unwrap_or_else(|| var_ref(shell, &format!("PARAM_{}", key)))
+    };
+
+    let mut vars: Vec<(String, String)> = Vec::new();
+    let command = PARAM_PLACEHOLDER
+        .replace_all(template, |caps: &regex::Captures| {
+            let key = &caps[1];
+            let Some(value) = params.get(key) else {
+                return caps[0].to_string();
+            };
+            match caps.get(2).map(|m| m.as_str()) {
+                None | Some("shell") | Some("raw") => reference(key),
+                Some(modifier) => {
+                    let var = format!("PARAM_{}_{}", key, modifier.to_uppercase());
+                    if !vars.iter().any(|(name, _)| *name == var) {
+                        vars.push((var.clone(), modified_value(value, modifier)));
+                    }
+                    var_ref(shell, &var)
+                }
+            }
+        })
+        .to_string();
+    (command, vars)
+}
+
+/// Respond 414 to requests whose URI is longer than `max` (0 for no limit), before
+/// they are routed and their path, query and body extracted
+pub async fn limit_uri_length(State(max): State<usize>, request: Request, next: Next) -> Response {
+    if max > 0 && uri_length(request.uri()) > max {
+        return (StatusCode::URI_TOO_LONG, "URI Too Long".to_string()).into_response();
+    }
+    next.run(request).await
+}
+
+/// Length of `uri` as the client sent it, without formatting it
+fn uri_length(uri: &Uri) -> usize {
+    let scheme = uri.scheme_str().map_or(0, |scheme| scheme.len() + "://".len());
+    let authority = uri.authority().map_or(0, |authority| authority.as_str().len());
+    scheme + authority + uri.path_and_query().map_or(0, |pq| pq.as_str().len())
+}
+
+/// Whether a command exiting with `status` succeeded: it exited with one of the
+/// success codes (0 by default), or was cut off by an accepted signal (e.g.
+/// SIGPIPE from `| head`) after producing output
+fn exit_succeeded(
+    status: &ExitStatus,
+    success_codes: Option<&[i32]>,
+    success_signals: &[i32],
+    has_output: bool,
+) -> bool {
+    let exit_ok = match success_codes {
+        Some(codes) => status.code().is_some_and(|code| codes.contains(&code)),
+        None => status.success(),
+    };
+    exit_ok || (has_output && killed_by(status, success_signals))
+}
+
+/// Transform a param value for substitution into the command text. Everything
+/// but `urlencode` output is shell-escaped, except `raw`: a raw value becomes
+/// shell code, so it must never come from an untrusted client.
+fn apply_modifier(value: &str, modifier: &str, shell: &ShellType) -> String {
+    match modifier {
+        "urlencode" | "raw" => modified_value(value, modifier),
+        _ => shell_escape(&modified_value(value, modifier), shell),
+    }
+}
+
+/// A param value transformed by a modifier, without any escaping
+fn modified_value(value: &str, modifier: &str) -> String {
+    match modifier {
+        "urlencode" => url_encode(value),
+        "upper" => value.to_uppercase(),
+        "lower" => value.to_lowercase(),
+        _ => value.to_string(),
+    }
+}
+
+/// The shell named in the X-Shell header when it is in --allow-shell-header,
+/// otherwise the configured one
+fn request_shell(state: &AppState, headers: &HeaderMap, log_level: Level) -> ShellType {
+    let Some(requested) = headers.get(SHELL_HEADER).and_then(|v| v.to_str().ok()) else {
+        return state.shell.clone();
+    };
+    match ShellType::from_str(requested.trim(), true) {
+        Ok(shell) if state.allowed_request_shells.contains(&shell) => shell,
+        _ => {
+            route_log!(log_level, Level::DEBUG, "Ignoring X-Shell: {}", requested);
+            state.shell.clone()
+        }
+    }
+}
+
+/// "https" when the listener terminates TLS (or, with --trust-proxy, when
+/// X-Forwarded-Proto says the client used it), "http" otherwise
+fn request_scheme(state: &AppState, headers: &HeaderMap) -> &'static str {
+    let forwarded = headers
+        .get("x-forwarded-proto")
+        .and_then(|v| v.to_str().ok())
+        .filter(|_| state.trust_proxy)
+        // Proxies may append their own hop, the first entry is the client's
+        .map(|v| v.split(',').next().unwrap_or("").trim().to_ascii_lowercase());
+    match forwarded.as_deref() {
+        Some("https") => "https",
+        Some("http") => "http",
+        _ if state.tls => "https",
+        _ => "http",
+    }
+}
+
+/// Response for a request over its route's rate limit, with the body from
+/// --rate-limit-command when set
+async fn throttled(
+    state: &AppState,
+    route_pattern: &str,
+    path: &str,
+    retry_after: Duration,
+    log_level: Level,
+) -> Response {
+    let retry_after = retry_after.as_secs_f64().ceil().max(1.0).to_string();
+    let mut body = state.throttle.body.clone();
+    if let Some(command) = &state.throttle.command {
+        let mut cmd = shell_command(&state.shell, command);
+        cmd.env("MATCHED_ROUTE", route_pattern);
+        cmd.env("REQUEST_PATH", path);
+        cmd.env("RETRY_AFTER", &retry_after);
+        match run_with_timeout(&mut cmd, &[], state.command_timeout).await {
+            Ok(output) if output.status.success() => {
+                body = String::from_utf8_lossy(&output.stdout).into_owned();
+            }
+            Ok(output) => route_log!(
+                log_level,
+                Level::WARN,
+                "Rate limit command failed with {}, using --rate-limit-body",
+                output.status
+            ),
+            Err(e) => route_log!(
+                log_level,
+                Level::WARN,
+                "Rate limit command failed: {}, using --rate-limit-body",
+                e
+            ),
+        }
+    }
+    let content_type = detect_content_type(&body);
+    (
+        state.throttle.status,
+        [(header::RETRY_AFTER, retry_after), (header::CONTENT_TYPE, content_type.to_string())],
+        body,
+    )
+        .into_response()
+}
+
+/// Turn every line of `output` into an SSE `data:` frame
+fn to_sse(output: &str) -> String {
+    output
+        .lines()
+        .map(|line| format!("data: {}\n\n", line))
+        .collect()
+}
+
+/// Lines starting with these are interpreted instead of returned as body
+const MAGIC_PREFIXES: [&str; 6] =
+    ["@header:", "@status:", "@cookie:", "@location:", "@body-start", "@body-end"];
+
+/// Whether any line of `output` starts with a magic prefix
+fn has_magic_lines(output: &[u8]) -> bool {
+    output.split(|&b| b == b'\n').any(|line| {
+        MAGIC_PREFIXES
+            .iter()
+            .any(|prefix| line.starts_with(prefix.as_bytes()))
+    })
+}
+
+/// Whether `line` is the marker `name`, written as "@name" or "@name:"
+fn is_marker(line: &str, name: &str) -> bool {
+    line.trim_end()
+        .strip_prefix(name)
+        .is_some_and(|rest| rest.is_empty() || rest == ":")
+}
+
+/// Split off the first line of `output`, without its line ending, like `str::lines`
+fn split_line(output: &[u8]) -> (&[u8], &[u8]) {
+    let (line, rest) = match output.iter().position(|&b| b == b'\n') {
+        Some(i) => (&output[..i], &output[i + 1..]),
+        None => (output, &[][..]),
+    };
+    (line.strip_suffix(b"\r").unwrap_or(line), rest)
+}
+
+/// Whether a Content-Type describes bytes that must not be treated as text lines
+fn is_binary_content_type(content_type: &str) -> bool {
+    let mime = content_type.split(';').next().unwrap_or("").trim().to_lowercase();
+    let textual = mime.starts_with("text/")
+        || mime.ends_with("+json")
+        || mime.ends_with("+xml")
+        || matches!(
+            mime.as_str(),
+            "application/json"
+                | "application/xml"
+                | "application/javascript"
+                | "application/x-www-form-urlencoded"
+                | "image/svg+xml"
+        );
+    !mime.is_empty() && !textual
+}
+
+/// Status signalled by exit code 1-99 as `base + code`, if it is a valid status
+fn exit_status(base: u16, code: i32) -> Option<StatusCode> {
+    let code = u16::try_from(code).ok().filter(|code| (1..=99).contains(code))?;
+    StatusCode::from_u16(base + code).ok()
+}
+
+/// Respond with each delimited record of `output` as an element of a JSON string array
+fn split_to_json(output: &str, delimiter: &str) -> Response {
+    let records: Vec<&str> = if delimiter == "\n" {
+        output.lines().collect()
+    } else {
+        let output = output.strip_suffix('\n').unwrap_or(output);
+        let output = output.strip_suffix(delimiter).unwrap_or(output);
+        if output.is_empty() {
+            Vec::new()
+        } else {
+            output.split(delimiter).collect()
+        }
+    };
+    (
+        [(header::CONTENT_TYPE, "application/json")],
+        serde_json::to_string(&records).unwrap(),
+    )
+        .into_response()
+}
+
+/// Percent-encode everything except RFC 3986 unreserved characters
+fn url_encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+/// Headers that describe a single connection rather than the request (RFC 9110 §7.6.1)
+const HOP_BY_HOP_HEADERS: [&str; 9] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Remove hop-by-hop headers, including any named in the Connection header
+fn strip_hop_by_hop(headers: &mut HashMap<String, String>) {
+    if let Some(connection) = headers.get("connection").cloned() {
+        for name in connection.split(',') {
+            headers.remove(&name.trim().to_lowercase());
+        }
+    }
+    for name in HOP_BY_HOP_HEADERS {
+        headers.remove(name);
+    }
+}
+
+/// A JSON-RPC error: HTTP status, error code, message and the request's id
+type RpcError = (StatusCode, i32, &'static str, Option<serde_json::Value>);
+
+/// Pick the command for a JSON-RPC style body's `method`, failing for bodies
+/// that aren't JSON, lack a method or name an unknown one
+fn rpc_command<'a>(
+    commands: &'a HashMap<String, String>,
+    body: &[u8],
+) -> Result<(String, &'a String), RpcError> {
+    let request: serde_json::Value = serde_json::from_slice(body)
+        .map_err(|_| (StatusCode::BAD_REQUEST, -32700, "Parse error", None))?;
+    let id = request.get("id").cloned();
+    let Some(method) = request.get("method").and_then(|m| m.as_str()) else {
+        return Err((StatusCode::BAD_REQUEST, -32600, "Invalid Request", id));
+    };
+    let command = commands
+        .get(method)
+        .ok_or((StatusCode::NOT_FOUND, -32601, "Method not found", id))?;
+    Ok((method.to_string(), command))
+}
+
+fn rpc_error((status, code, message, id): RpcError) -> Response {
+    let body = json!({
+        "jsonrpc": "2.0",
+        "error": {"code": code, "message": message},
+        "id": id,
+    });
+    (status, [(header::CONTENT_TYPE, "application/json")], body.to_string()).into_response()
+}
+
+/// Whether a command may set the (lowercased) header via `@header:`
+fn response_header_allowed(state: &AppState, name: &str) -> bool {
+    let listed = |list: &[String]| list.iter().any(|h| h.eq_ignore_ascii_case(name));
+    (state.allowed_response_headers.is_empty() || listed(&state.allowed_response_headers))
+        && !listed(&state.blocked_response_headers)
+}
+
+/// Check a Content-Type header value against accepted media types (`type/*` allowed)
+fn content_type_allowed(content_type: &str, accepted: &[String]) -> bool {
+    let media_type = content_type
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_lowercase();
+    accepted.iter().any(|allowed| {
+        let allowed = allowed.to_lowercase();
+        match allowed.strip_suffix("/*") {
+            Some(prefix) => media_type.split('/').next() == Some(prefix),
+            None => allowed == "*/*" || allowed == media_type,
+        }
+    })
+}
+
+/// Take a concurrency slot, giving up after `timeout` if one is set
+async fn acquire_slot(
+    scheduler: &Arc<Scheduler>,
+    method: &Method,
+    timeout: Option<Duration>,
+) -> Option<Slot> {
+    let acquire = scheduler.acquire(method);
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, acquire).await.ok(),
+        None => Some(acquire.await),
+    }
+}
+
+/// Host the request was sent to, lowercased and without port
+fn request_host(parts: &Parts) -> Option<String> {
+    let host = parts
+        .headers
+        .get(header::HOST)
+        .and_then(|v| v.to_str().ok())
+        .or_else(|| parts.uri.host())?;
+    let host = match host.rsplit_once(':') {
+        Some((name, port)) if port.chars().all(|c| c.is_ascii_digit()) => name,
+        _ => host,
+    };
+    Some(host.to_lowercase())
+}
+
+/// Compute the Allow header value for a path on `host` from the routes registered
+/// for that host or any host
+fn allowed_methods(state: &AppState, host: &str, route_pattern: &str) -> String {
+    const ALL: [&str; 7] = ["GET", "HEAD", "POST", "PUT", "DELETE", "PATCH", "OPTIONS"];
+
+    let registered: Vec<&str> = state
+        .commands
+        .keys()
+        .filter_map(|key| key.split_once(' '))
+        .filter(|(_, target)| {
+            let (route_host, path) = split_host(target);
+            path == route_pattern && route_host.is_none_or(|route_host| route_host == host)
+        })
+        .map(|(method, _)| method)
+        .collect();
+
+    ALL.iter()
+        .filter(|m| {
+            **m == "OPTIONS"
+                || registered
+                    .iter()
+                    .any(|r| *r == "ANY" || r == *m || (**m == "HEAD" && *r == "GET"))
+        })
+        .copied()
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Auto-detect content type based on body content
+fn detect_content_type(body: &str) -> &'static str {
+    let trimmed = body.trim();
+
+    // Check for JSON: starts with { or [
+    if (trimmed.starts_with('{') && trimmed.ends_with('}'))
+        || (trimmed.starts_with('[') && trimmed.ends_with(']'))
+    {
+        // Verify it's valid JSON
+        if serde_json::from_str::<serde_json::Value>(trimmed).is_ok() {
+            return "application/json";
+        }
+    }
+
+    // Check for HTML/XML: starts with <
+    if trimmed.starts_with('<') {
+        let lower = trimmed.to_lowercase();
+        
+        // Check for HTML first (more specific)
+        if lower.starts_with("<!doctype html") || lower.starts_with("<html") {
+            return "text/html";
+        }
+        
+        // Check for XML declaration or common XML patterns
+        if trimmed.starts_with("<?xml")
+            || trimmed.starts_with("<!DOCTYPE")
+            || (trimmed.starts_with('<') && trimmed.ends_with('>') && trimmed.contains("</"))
+        {
+            return "application/xml";
+        }
+    }
+
+    // Default to plain text
+    "text/plain"
+}
+
+/// Replace the body of a response with the configured error page for its status.
+/// Non-empty bodies are kept unless `force` or `--error-page-always` is set.
+fn apply_error_page(state: &AppState, response: Response, force: bool) -> Response {
+    let Some(page) = state.error_pages.get(&response.status().as_u16()) else {
+        return response;
+    };
+    let is_empty = response.body().size_hint().exact() == Some(0);
+    if !(is_empty || force || state.error_page_always) {
+        return response;
+    }
+
+    let (mut parts, _) = response.into_parts();
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts
+        .headers
+        .insert(header::CONTENT_TYPE, HeaderValue::from_static("text/html"));
+    Response::from_parts(parts, Body::from(page.clone()))
+}
+
+pub async fn fallback_handler(Extension(state): Extension<Arc<AppState>>) -> Response {
+    let response = (StatusCode::NOT_FOUND, "Route not found".to_string()).into_response();
+    apply_error_page(&state, response, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::circuit::CircuitBreaker;
+    use crate::condition::parse_condition;
+    use crate::cli::LogLevel;
+    use crate::routes::{build_router, limit_uri, parse_routes};
+    use crate::runner::StdinEncoding;
+    use crate::jq::JqFilter;
+    use crate::maintenance::Maintenance;
+    use crate::nonce::NonceStore;
+    use crate::rate_limit::{Rate, Throttle};
+    use crate::runner::BodyFormat;
+    use crate::scheduler::Scheduling;
+    use crate::state::Charset;
+    use crate::test_support::CapturedMessages;
+    use crate::validate::Validator;
+    use crate::webhook::{Webhook, WebhookOn};
+    use axum::http::Request;
+    use tower::ServiceExt;
+
+    /// Build a router from `routes` (spec, command pairs) and `state`
+    fn router(routes: &[&str], state: AppState) -> axum::Router {
+        let raw: Vec<String> = routes.iter().map(|r| r.to_string()).collect();
+        let entries = parse_routes(&raw).unwrap();
+        let mut state = state;
+        for route in &entries {
+            state.commands.insert(route.key(), route.command.clone());
+        }
+        build_router(&entries, Arc::new(state)).unwrap()
+    }
+
+    /// Route `request` through a router built from `routes` and `state`
+    async fn send(routes: &[&str], state: AppState, request: Request<Body>) -> Response {
+        router(routes, state).oneshot(request).await.unwrap()
+    }
+
+    fn request(method: &str, uri: &str) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    async fn body_string(response: Response) -> String {
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    fn test_state() -> AppState {
+        AppState {
+            shell: crate::shell::ShellType::Sh,
+            header_format: HeaderFormat::Json,
+            query_format: HeaderFormat::Json,
+            cookie_format: HeaderFormat::Json,
+            ..Default::default()
+        }
+    }
+
+    /// Collects the levels of events emitted under the handler's log target
+    #[derive(Clone, Default)]
+    struct CapturedLevels(Arc<std::sync::Mutex<Vec<Level>>>);
+
+    impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for CapturedLevels {
+        fn on_event(
+            &self,
+            event: &tracing::Event<'_>,
+            _ctx: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            if event.metadata().target() == LOG_TARGET {
+                self.0.lock().unwrap().push(*event.metadata().level());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_route_log_level_overrides_global() {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let captured = CapturedLevels::default();
+        let _guard =
+            tracing::subscriber::set_default(tracing_subscriber::registry().with(captured.clone()));
+
+        let mut state = AppState { log_level: LogLevel::Warn, ..test_state() };
+        state.route_configs.insert(
+            "GET /noisy".to_string(),
+            RouteConfig {
+                log_level: Some(LogLevel::Debug),
+                ..Default::default()
+            },
+        );
+        let routes = ["GET /noisy", "echo hi", "GET /quiet", "echo hi"];
+
+        send(&routes, state.clone(), request("GET", "/quiet")).await;
+        assert!(captured.0.lock().unwrap().is_empty());
+
+        send(&routes, state, request("GET", "/noisy")).await;
+        assert!(captured.0.lock().unwrap().contains(&Level::DEBUG));
+    }
+
+    #[tokio::test]
+    async fn test_access_log_includes_body_bytes() {
+        let captured = CapturedMessages::default();
+        let _guard = captured.set_default();
+
+        send(&["GET /hi", "echo hello"], test_state(), request("GET", "/hi")).await;
+        let messages = captured.messages();
+        assert!(messages.iter().any(|m| m == "GET /hi -> 200 (6 bytes)"), "{:?}", messages);
+    }
+
+    #[tokio::test]
+    async fn test_access_log_json_fields() {
+        let lines = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let writer = lines.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .json()
+            .with_writer(move || WriteInto(writer.clone()))
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        send(&["/fail", "exit 3"], test_state(), request("GET", "/fail")).await;
+
+        let output = String::from_utf8(lines.lock().unwrap().clone()).unwrap();
+        let access = output
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .find(|event| event["fields"]["status"].is_number())
+            .expect(&output);
+        let fields = &access["fields"];
+        assert_eq!(fields["method"], "GET");
+        assert_eq!(fields["path"], "/fail");
+        assert_eq!(fields["status"], 500);
+        assert_eq!(fields["exit_status"], 3);
+        assert!(fields["duration_ms"].as_f64().unwrap() > 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_webhook_called_for_failing_request() {
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let hook = axum::Router::new().route(
+            "/hook",
+            axum::routing::post(move |body: String| {
+                let _ = tx.send(body);
+                async {}
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        tokio::spawn(axum::serve(listener, hook).into_future());
+
+        let webhook = Webhook::new(&url, WebhookOn::Error).unwrap();
+        let state = AppState { webhook: Some(webhook), ..test_state() };
+        let routes = ["GET /ok", "echo ok", "GET /fail", "exit 3"];
+        send(&routes, state.clone(), request("GET", "/ok")).await;
+        send(&routes, state, request("GET", "/fail?x=1")).await;
+
+        // Only the failing request is reported
+        let payload = tokio::time::timeout(Duration::from_secs(5), rx.recv()).await.unwrap();
+        let payload: serde_json::Value = serde_json::from_str(&payload.unwrap()).unwrap();
+        assert_eq!(payload["route"], "GET /fail");
+        assert_eq!(payload["method"], "GET");
+        assert_eq!(payload["path"], "/fail");
+        assert_eq!(payload["status"], 500);
+        assert_eq!(payload["exit_code"], 3);
+        assert!(payload["duration_ms"].as_f64().unwrap() > 0.0);
+    }
+
+    /// Log writer appending to a shared buffer
+    struct WriteInto(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl io::Write for WriteInto {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_warn_empty_response() {
+        let captured = CapturedMessages::default();
+        let _guard = captured.set_default();
+        let warned = || captured.messages().iter().any(|m| m.starts_with("Empty 200 response"));
+
+        send(&["GET /quiet", "true"], test_state(), request("GET", "/quiet")).await;
+        assert!(!warned());
+
+        let state = AppState { warn_empty_response: true, ..test_state() };
+        send(&["GET /quiet", "true"], state, request("GET", "/quiet")).await;
+        assert!(warned());
+    }
+
+    #[tokio::test]
+    async fn test_auto_options_returns_204_without_spawning() {
+        let state = AppState { auto_options: true, ..test_state() };
+        // `exit 1` would produce a 500 if the command were spawned
+        let response = send(&["/any", "exit 1"], state, request("OPTIONS", "/any")).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            response.headers()[header::ALLOW],
+            "GET, HEAD, POST, PUT, DELETE, PATCH, OPTIONS"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_auto_options_allow_lists_registered_methods() {
+        let state = AppState { auto_options: true, ..test_state() };
+        let response = send(
+            &["GET /items", "exit 1", "POST /items", "exit 1"],
+            state,
+            request("OPTIONS", "/items"),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(response.headers()[header::ALLOW], "GET, HEAD, POST, OPTIONS");
+    }
+
+    #[tokio::test]
+    async fn test_auto_options_defers_to_explicit_options_route() {
+        let state = AppState { auto_options: true, ..test_state() };
+        let response = send(
+            &["GET /items", "exit 1", "OPTIONS /items", "echo custom"],
+            state,
+            request("OPTIONS", "/items"),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body_string(response).await, "custom\n");
+    }
+
+    #[tokio::test]
+    async fn test_auto_options_follows_host_scoped_routes() {
+        let routes = [
+            "GET /items", "exit 1",
+            "POST api.example.com/items", "exit 1",
+            "OPTIONS admin.example.com/items", "echo custom",
+        ];
+        let state = AppState { auto_options: true, ..test_state() };
+        let app = router(&routes, state);
+        let options = |host: &str| {
+            let request = Request::builder().method("OPTIONS").uri("/items");
+            request.header(header::HOST, host).body(Body::empty()).unwrap()
+        };
+
+        let response = app.clone().oneshot(options("api.example.com")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(response.headers()[header::ALLOW], "GET, HEAD, POST, OPTIONS");
+
+        let response = app.clone().oneshot(options("other.com")).await.unwrap();
+        assert_eq!(response.headers()[header::ALLOW], "GET, HEAD, OPTIONS");
+
+        let response = app.oneshot(options("admin.example.com")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body_string(response).await, "custom\n");
+    }
+
+    #[tokio::test]
+    async fn test_options_runs_command_without_auto_options() {
+        let response = send(&["/any", "exit 1"], test_state(), request("OPTIONS", "/any")).await;
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    fn params(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_substitute_params_default_is_shell_escaped() {
+        let cmd = substitute_params("echo ':name'", &params(&[("name", "it's")]), &ShellType::Bash);
+        assert_eq!(cmd, "echo 'it'\\''s'");
+    }
+
+    #[test]
+    fn test_substitute_params_shell_modifier() {
+        let cmd = substitute_params(
+            "echo ':name|shell'",
+            &params(&[("name", "it's")]),
+            &ShellType::Bash,
+        );
+        assert_eq!(cmd, "echo 'it'\\''s'");
+    }
+
+    #[test]
+    fn test_substitute_params_escapes_for_fish() {
+        let cmd = substitute_params(
+            "echo ':name'",
+            &params(&[("name", "a\\'b")]),
+            &ShellType::Fish,
+        );
+        assert_eq!(cmd, "echo 'a\\\\\\'b'");
+    }
+
+    #[test]
+    fn test_substitute_params_escapes_typographic_quotes_for_powershell() {
+        let cmd = substitute_params(
+            "Get-Item ':path'",
+            &params(&[("path", "x\u{2019}; Remove-Item C:\\ -Recurse; \u{2019}")]),
+            &ShellType::Powershell,
+        );
+        // Doubled quotes are literal, so the value never leaves the string
+        let expected = "Get-Item 'x\u{2019}\u{2019}; Remove-Item C:\\ -Recurse; \u{2019}\u{2019}'";
+        assert_eq!(cmd, expected);
+    }
+
+    #[test]
+    fn test_substitute_params_urlencode_modifier() {
+        let cmd = substitute_params(
+            "curl 'http://x/?q=:q|urlencode'",
+            &params(&[("q", "a b&c/é")]),
+            &ShellType::Bash,
+        );
+        assert_eq!(cmd, "curl 'http://x/?q=a%20b%26c%2F%C3%A9'");
+    }
+
+    #[test]
+    fn test_substitute_params_upper_and_lower_modifiers() {
+        let values = params(&[("name", "MiXeD")]);
+        assert_eq!(substitute_params(":name|upper", &values, &ShellType::Bash), "MIXED");
+        assert_eq!(substitute_params(":name|lower", &values, &ShellType::Bash), "mixed");
+    }
+
+    #[tokio::test]
+    async fn test_case_modifiers_stay_shell_escaped() {
+        // a'; echo pwned; '
+        let uri = "/x/a%27%3B%20echo%20pwned%3B%20%27";
+        let response = send(&["/x/:v", "printf %s ':v|upper'"], test_state(), request("GET", uri));
+        assert_eq!(body_string(response.await).await, "A'; ECHO PWNED; '\n");
+        let response = send(&["/x/:v", "printf %s ':v|lower'"], test_state(), request("GET", uri));
+        assert_eq!(body_string(response.await).await, "a'; echo pwned; '\n");
+    }
+
+    #[test]
+    fn test_substitute_params_raw_modifier() {
+        let cmd = substitute_params(
+            "echo :name|raw",
+            &params(&[("name", "it's")]),
+            &ShellType::Bash,
+        );
+        assert_eq!(cmd, "echo it's");
+    }
+
+    #[test]
+    fn test_substitute_params_name_prefix_of_another() {
+        let values = params(&[("id", "1"), ("identifier", "abc")]);
+        let cmd = substitute_params("echo :id :identifier :id|upper", &values, &ShellType::Bash);
+        assert_eq!(cmd, "echo 1 abc 1");
+    }
+
+    #[test]
+    fn test_substitute_params_leaves_literal_colons() {
+        // Values aren't substituted again, and `:8080` names no param
+        let values = params(&[("id", ":port"), ("port", "9")]);
+        let cmd = substitute_params(
+            "curl http://localhost:8080/users/:id|raw; echo :unknown",
+            &values,
+            &ShellType::Bash,
+        );
+        assert_eq!(cmd, "curl http://localhost:8080/users/:port; echo :unknown");
+    }
+
+    #[test]
+    fn test_reference_params() {
+        let values = params(&[("id", "$(reboot)"), ("q", "a b")]);
+        let names = ["id", "q"];
+        let template = "echo :id; curl 'x?q=':q|urlencode";
+
+        let (cmd, vars) =
+            reference_params(template, &values, &ShellType::Bash, ParamMode::Env, &names);
+        assert_eq!(cmd, "echo \"$PARAM_id\"; curl 'x?q='\"$PARAM_q_URLENCODE\"");
+        assert_eq!(vars, vec![("PARAM_q_URLENCODE".to_string(), "a%20b".to_string())]);
+
+        let (cmd, _) =
+            reference_params(template, &values, &ShellType::Bash, ParamMode::Positional, &names);
+        assert!(cmd.starts_with("echo \"${1}\";"));
+        let (cmd, _) =
+            reference_params(":q", &values, &ShellType::Fish, ParamMode::Positional, &names);
+        assert_eq!(cmd, "\"$argv[2]\"");
+        let (cmd, _) =
+            reference_params(":q", &values, &ShellType::Powershell, ParamMode::Positional, &names);
+        assert_eq!(cmd, "\"$env:PARAM_q\"");
+    }
+
+    #[test]
+    fn test_reference_params_name_prefix_of_another() {
+        let values = params(&[("id", "1"), ("identifier", "abc")]);
+        let names = ["id", "identifier"];
+        let template = "echo :identifier :id :id|upper :id|upper :other";
+        let (cmd, vars) =
+            reference_params(template, &values, &ShellType::Bash, ParamMode::Env, &names);
+        let upper = "\"$PARAM_id_UPPER\"";
+        assert_eq!(
+            cmd,
+            format!("echo \"$PARAM_identifier\" \"$PARAM_id\" {} {} :other", upper, upper)
+        );
+        assert_eq!(vars, vec![("PARAM_id_UPPER".to_string(), "1".to_string())]);
+    }
+
+    #[tokio::test]
+    async fn test_param_modes_do_not_execute_values() {
+        let routes = ["/echo/:value", "echo \":value\""];
+        for mode in [ParamMode::Env, ParamMode::Positional] {
+            let state = AppState { param_mode: mode, ..test_state() };
+            let response = send(&routes, state, request("GET", "/echo/$(echo%20pwned)")).await;
+            assert_eq!(body_string(response).await, "$(echo pwned)\n", "{:?}", mode);
+        }
+
+        // The legacy mode only protects values placed inside single quotes
+        let response = send(&routes, test_state(), request("GET", "/echo/$(echo%20pwned)")).await;
+        assert_eq!(body_string(response).await, "pwned\n");
+    }
+
+    #[test]
+    fn test_substitute_params_unknown_modifier_is_left_as_pipe() {
+        let cmd = substitute_params(
+            "echo :name|wc -c",
+            &params(&[("name", "abc")]),
+            &ShellType::Bash,
+        );
+        assert_eq!(cmd, "echo abc|wc -c");
+    }
+
+    #[test]
+    fn test_strip_hop_by_hop() {
+        let mut headers = params(&[
+            ("connection", "keep-alive, X-Trace"),
+            ("keep-alive", "timeout=5"),
+            ("transfer-encoding", "chunked"),
+            ("x-trace", "abc"),
+            ("x-api-key", "secret"),
+        ]);
+        strip_hop_by_hop(&mut headers);
+        assert_eq!(headers, params(&[("x-api-key", "secret")]));
+    }
+
+    #[tokio::test]
+    async fn test_strip_hop_by_hop_hides_headers_from_command() {
+        let state = AppState { strip_hop_by_hop: true, ..test_state() };
+        let request = Request::builder()
+            .uri("/headers")
+            .header("connection", "x-trace")
+            .header("upgrade", "websocket")
+            .header("x-trace", "abc")
+            .header("x-api-key", "secret")
+            .body(Body::empty())
+            .unwrap();
+        let response = send(&["/headers", "echo \"$HEADERS_JSON\""], state, request).await;
+        let seen: HashMap<String, String> =
+            serde_json::from_str(&body_string(response).await).unwrap();
+        assert_eq!(seen, params(&[("x-api-key", "secret")]));
+    }
+
+    #[test]
+    fn test_content_type_allowed() {
+        let accepted = vec!["application/json".to_string(), "text/*".to_string()];
+        assert!(content_type_allowed("application/json; charset=utf-8", &accepted));
+        assert!(content_type_allowed("Text/CSV", &accepted));
+        assert!(!content_type_allowed("application/xml", &accepted));
+        assert!(!content_type_allowed("", &accepted));
+    }
+
+    fn post(uri: &str, content_type: &str, body: &str) -> Request<Body> {
+        Request::builder()
+            .method("POST")
+            .uri(uri)
+            .header(header::CONTENT_TYPE, content_type)
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_global_content_type_restriction_rejects_with_415() {
+        let state = AppState {
+            allowed_content_types: vec!["application/json".to_string()],
+            ..test_state()
+        };
+        let routes = ["POST /data", "cat"];
+
+        let response = send(&routes, state.clone(), post("/data", "text/csv", "a,b")).await;
+        assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+        let response = send(&routes, state, post("/data", "application/json", "{}")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_route_consumes_overrides_global_content_types() {
+        let mut state = AppState {
+            allowed_content_types: vec!["application/json".to_string()],
+            ..test_state()
+        };
+        state.route_configs.insert(
+            "POST /csv".to_string(),
+            RouteConfig {
+                consumes: Some(vec!["text/csv".to_string()]),
+                ..Default::default()
+            },
+        );
+
+        let response = send(&["POST /csv", "cat"], state, post("/csv", "text/csv", "a,b")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body_string(response).await, "a,b\n");
+    }
+
+    #[test]
+    fn test_ordered_params_follow_route_pattern() {
+        let values = params(&[("y", "2"), ("x", "1")]);
+        assert_eq!(ordered_params("/a/{x}/b/{y}", &values), vec!["1", "2"]);
+        assert_eq!(ordered_params("/a/{y}/b/{x}", &values), vec!["2", "1"]);
+    }
+
+    #[tokio::test]
+    async fn test_params_passed_positionally_and_as_ordered_list() {
+        let response = send(
+            &["/a/:x/b/:y", "echo \"$1 $2 $PARAMS_ORDERED\""],
+            test_state(),
+            request("GET", "/a/first/b/second"),
+        )
+        .await;
+        assert_eq!(body_string(response).await, "first second [\"first\",\"second\"]\n");
+    }
+
+    #[tokio::test]
+    async fn test_uri_over_limit_returns_414() {
+        let routes = ["/echo/:value", "echo :value"];
+        let app = limit_uri(router(&routes, test_state()), 32);
+
+        let response = app.clone().oneshot(request("GET", "/echo/short")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let long_uri = format!("/echo/{}", "a".repeat(64));
+        let response = app.clone().oneshot(request("GET", &long_uri)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::URI_TOO_LONG);
+
+        // Rejected before routing, so unmatched paths get 414 rather than 404
+        let response = app.oneshot(request("GET", &"/x".repeat(32))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::URI_TOO_LONG);
+
+        // Rejected before any other check
+        let maintenance = Arc::new(Maintenance::new(true, "down".to_string()));
+        let state = AppState { maintenance, ..test_state() };
+        let app = limit_uri(router(&routes, state), 32);
+        let response = app.oneshot(request("GET", &long_uri)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::URI_TOO_LONG);
+    }
+
+    #[test]
+    fn test_uri_length() {
+        for uri in ["/a/b?x=1", "http://example.com:8080/a?x=1", "/", "*"] {
+            let parsed: Uri = uri.parse().unwrap();
+            assert_eq!(uri_length(&parsed), parsed.to_string().len(), "{}", uri);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_workdir() {
+        let base = std::env::temp_dir();
+        let nested = base.join(format!("sherut-workdir-{}", std::process::id()));
+        std::fs::create_dir_all(&nested).unwrap();
+        let mut state = AppState { workdir: Some(base.clone()), ..test_state() };
+        state.route_configs.insert(
+            "ANY /nested".to_string(),
+            RouteConfig { workdir: Some(nested.clone()), ..Default::default() },
+        );
+        let routes = ["/global", "pwd -P", "/nested", "pwd -P"];
+
+        let response = send(&routes, state.clone(), request("GET", "/global")).await;
+        let expected = format!("{}\n", base.canonicalize().unwrap().display());
+        assert_eq!(body_string(response).await, expected);
+        let response = send(&routes, state, request("GET", "/nested")).await;
+        let expected = format!("{}\n", nested.canonicalize().unwrap().display());
+        assert_eq!(body_string(response).await, expected);
+        std::fs::remove_dir(&nested).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_maintenance_toggle() {
+        let maintenance = Arc::new(Maintenance::new(false, "Back soon".to_string()));
+        let state = AppState {
+            maintenance: maintenance.clone(),
+            health_path: Some("/healthz".to_string()),
+            ..test_state()
+        };
+        let app = router(&["/hi", "echo hi"], state);
+
+        let response = app.clone().oneshot(request("GET", "/hi")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        maintenance.toggle();
+        let response = app.clone().oneshot(request("GET", "/hi")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body_string(response).await, "Back soon");
+        let response = app.clone().oneshot(request("GET", "/healthz")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        maintenance.toggle();
+        let response = app.oneshot(request("GET", "/hi")).await.unwrap();
+        assert_eq!(body_string(response).await, "hi\n");
+    }
+
+    #[tokio::test]
+    async fn test_allowed_hosts() {
+        let state = AppState { allowed_hosts: vec!["api.example.com".to_string()], ..test_state() };
+        let with_host = |host: &str| {
+            Request::builder().uri("/hi").header(header::HOST, host).body(Body::empty()).unwrap()
+        };
+        let routes = ["/hi", "echo hi"];
+
+        let response = send(&routes, state.clone(), with_host("API.example.com:8080")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = send(&routes, state.clone(), with_host("evil.example.com")).await;
+        assert_eq!(response.status(), StatusCode::MISDIRECTED_REQUEST);
+        let response = send(&routes, state, request("GET", "/hi")).await;
+        assert_eq!(response.status(), StatusCode::MISDIRECTED_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_max_query_params() {
+        let state = AppState { max_query_params: Some(3), ..test_state() };
+        let routes = ["/q", "echo $QUERY_JSON"];
+        let query: Vec<String> = (0..1000).map(|i| format!("k{}={}", i, i)).collect();
+        let flood = format!("/q?{}", query.join("&"));
+
+        let response = send(&routes, state.clone(), request("GET", "/q?a=1&b=2&c=3")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = send(&routes, state.clone(), request("GET", &flood)).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let state = AppState { query_overflow: QueryOverflow::Truncate, ..state };
+        let response = send(&routes, state, request("GET", &flood)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_str(&body_string(response).await).unwrap();
+        assert_eq!(body, json!({"k0": "0", "k1": "1", "k2": "2"}));
+    }
+
+    #[test]
+    fn test_render_location() {
+        let values = params(&[("id", "a b"), ("org", "acme")]);
+        assert_eq!(render_location("/orgs/:org/items/:id", &values), "/orgs/acme/items/a%20b");
+        assert_eq!(render_location("/items/:missing", &values), "/items/:missing");
+    }
+
+    fn created_location_state(route: &str, template: &str) -> AppState {
+        let mut state = test_state();
+        state.route_configs.insert(
+            route.to_string(),
+            RouteConfig {
+                created_location: Some(template.to_string()),
+                ..Default::default()
+            },
+        );
+        state
+    }
+
+    #[tokio::test]
+    async fn test_created_location_from_script_value() {
+        let state = created_location_state("POST /items", "/items/:id");
+        let response = send(
+            &["POST /items", "echo '@status: 201'; echo '@location: id=42'; echo created"],
+            state,
+            request("POST", "/items"),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(response.headers()[header::LOCATION], "/items/42");
+        assert_eq!(body_string(response).await, "created\n");
+    }
+
+    #[tokio::test]
+    async fn test_created_location_from_route_params() {
+        let state = created_location_state("PUT /items/{id}", "/items/:id");
+        let routes = ["PUT /items/:id", "echo '@status: 201'"];
+        let response = send(&routes, state, request("PUT", "/items/7")).await;
+        assert_eq!(response.headers()[header::LOCATION], "/items/7");
+    }
+
+    #[tokio::test]
+    async fn test_created_location_only_for_201_without_explicit_location() {
+        let state = created_location_state("POST /items", "/items/:id");
+        let response = send(
+            &["POST /items", "echo '@location: id=42'"],
+            state.clone(),
+            request("POST", "/items"),
+        )
+        .await;
+        assert!(!response.headers().contains_key(header::LOCATION));
+
+        let response = send(
+            &["POST /items", "echo '@status: 201'; echo '@header: Location: /custom'"],
+            state,
+            request("POST", "/items"),
+        )
+        .await;
+        assert_eq!(response.headers()[header::LOCATION], "/custom");
+    }
+
+    #[test]
+    fn test_with_charset() {
+        assert_eq!(with_charset("text/plain", "iso-8859-1"), "text/plain; charset=iso-8859-1");
+        assert_eq!(
+            with_charset("text/html; Charset=utf-8; q=1", "shift_jis"),
+            "text/html; q=1; charset=shift_jis"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_route_charset_transcodes_output() {
+        let mut state = test_state();
+        let charset = Charset {
+            label: "iso-8859-1".to_string(),
+            encoding: encoding_rs::WINDOWS_1252,
+        };
+        state.route_configs.insert(
+            "ANY /legacy".to_string(),
+            RouteConfig { charset: Some(charset), ..Default::default() },
+        );
+        let routes = ["/legacy", "printf 'caf\\303\\251'"];
+        let response = send(&routes, state, request("GET", "/legacy")).await;
+
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "text/plain; charset=iso-8859-1");
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"caf\xe9\n");
+    }
+
+    #[tokio::test]
+    async fn test_invalid_response_header_answers_500() {
+        // A control character in the template makes the Location header invalid
+        let state = created_location_state("POST /items", "/items/\n:id");
+        let routes = ["POST /items", "echo '@status: 201'; echo '@location: id=42'"];
+        let response = send(&routes, state, request("POST", "/items")).await;
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(body_string(response).await, "Internal Server Error");
+    }
+
+    #[tokio::test]
+    async fn test_method_default_content_type() {
+        let mut state = test_state();
+        state
+            .method_content_types
+            .insert("POST".to_string(), "application/json".to_string());
+        let routes = ["/items", "echo plain"];
+
+        let response = send(&routes, state.clone(), request("POST", "/items")).await;
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/json");
+
+        let response = send(&routes, state, request("GET", "/items")).await;
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "text/plain");
+    }
+
+    #[tokio::test]
+    async fn test_explicit_content_type_beats_method_default() {
+        let mut state = test_state();
+        state
+            .method_content_types
+            .insert("POST".to_string(), "application/json".to_string());
+        let response = send(
+            &["/items", "echo '@header: Content-Type: text/csv'; echo a,b"],
+            state,
+            request("POST", "/items"),
+        )
+        .await;
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "text/csv");
+    }
+
+    #[tokio::test]
+    async fn test_circuit_breaker_short_circuits_failing_route() {
+        let state = AppState {
+            circuit_breaker: Some(Arc::new(CircuitBreaker::new(
+                2,
+                std::time::Duration::from_secs(60),
+            ))),
+            ..test_state()
+        };
+        let router = router(&["/flaky", "exit 1"], state);
+
+        for _ in 0..2 {
+            let response = router.clone().oneshot(request("GET", "/flaky")).await.unwrap();
+            assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        }
+        let response = router.oneshot(request("GET", "/flaky")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn test_rejected_trial_request_keeps_circuit_usable() {
+        let state = AppState {
+            circuit_breaker: Some(Arc::new(CircuitBreaker::new(1, Duration::from_millis(50)))),
+            nonces: Some(Arc::new(NonceStore::new(
+                "X-Nonce".to_string(),
+                Duration::from_secs(60),
+            ))),
+            ..test_state()
+        };
+        let app = router(&["/exit/:code", "exit :code"], state);
+        let with_nonce = |uri: &str, nonce: &str| {
+            let request = Request::builder().uri(uri).header("x-nonce", nonce);
+            request.body(Body::empty()).unwrap()
+        };
+
+        let response = app.clone().oneshot(with_nonce("/exit/1", "n-1")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        tokio::time::sleep(Duration::from_millis(60)).await;
+
+        // The trial request is turned away without running the command
+        let response = app.clone().oneshot(with_nonce("/exit/0", "n-1")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let response = app.oneshot(with_nonce("/exit/0", "n-2")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    /// State limiting GET /limited to one request a minute
+    fn rate_limited_state(throttle: Throttle) -> AppState {
+        let mut state = AppState { throttle, ..test_state() };
+        let rate = Rate { requests: 1, period: Duration::from_secs(60) };
+        state.route_configs.insert(
+            "GET /limited".to_string(),
+            RouteConfig { rate_limit: Some(rate), ..Default::default() },
+        );
+        state
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_default_response() {
+        let router = router(&["GET /limited", "echo ok"], rate_limited_state(Throttle::default()));
+        let response = router.clone().oneshot(request("GET", "/limited")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = router.oneshot(request("GET", "/limited")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "60");
+        assert_eq!(body_string(response).await, "Too Many Requests");
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_custom_response() {
+        let throttle = Throttle {
+            status: StatusCode::SERVICE_UNAVAILABLE,
+            body: "<html><h1>Slow down</h1></html>".to_string(),
+            command: None,
+        };
+        let router = router(&["GET /limited", "echo ok"], rate_limited_state(throttle));
+        router.clone().oneshot(request("GET", "/limited")).await.unwrap();
+
+        let response = router.oneshot(request("GET", "/limited")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "text/html");
+        assert_eq!(body_string(response).await, "<html><h1>Slow down</h1></html>");
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_command_generates_body() {
+        let throttle = Throttle {
+            command: Some("echo \"$MATCHED_ROUTE retry in $RETRY_AFTER\"".to_string()),
+            ..Throttle::default()
+        };
+        let router = router(&["GET /limited", "echo ok"], rate_limited_state(throttle));
+        router.clone().oneshot(request("GET", "/limited")).await.unwrap();
+
+        let response = router.oneshot(request("GET", "/limited")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(body_string(response).await, "/limited retry in 60\n");
+    }
+
+    #[tokio::test]
+    async fn test_deadline_env_vars_match_timeout() {
+        let state = AppState {
+            command_timeout: Some(std::time::Duration::from_millis(5000)),
+            ..test_state()
+        };
+        let now_ms = || SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+
+        let before = now_ms();
+        let response = send(
+            &["/deadline", "echo \"$REQUEST_TIMEOUT_MS $REQUEST_DEADLINE_MS\""],
+            state,
+            request("GET", "/deadline"),
+        )
+        .await;
+        let after = now_ms();
+
+        let body = body_string(response).await;
+        let (timeout, deadline) = body.trim().split_once(' ').unwrap();
+        assert_eq!(timeout, "5000");
+        let deadline: u128 = deadline.parse().unwrap();
+        assert!(deadline >= before + 5000 && deadline <= after + 5000);
+    }
+
+    #[tokio::test]
+    async fn test_command_timeout_returns_504() {
+        let state = AppState {
+            command_timeout: Some(std::time::Duration::from_millis(100)),
+            ..test_state()
+        };
+        let response = send(&["/slow", "sleep 5"], state, request("GET", "/slow")).await;
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_sigpipe_with_output_is_success_when_configured() {
+        let routes = ["/head", "echo partial; kill -PIPE $$"];
+
+        let response = send(&routes, test_state(), request("GET", "/head")).await;
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        let state = AppState { success_signals: vec![13], ..test_state() };
+        let response = send(&routes, state, request("GET", "/head")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body_string(response).await, "partial\n");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_sigpipe_without_output_is_still_failure() {
+        let state = AppState { success_signals: vec![13], ..test_state() };
+        let response = send(&["/head", "kill -PIPE $$"], state, request("GET", "/head")).await;
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[test]
+    fn test_escape_html() {
+        assert_eq!(
+            escape_html(r#"<a href="x">Tom & Jerry's</a>"#),
+            "&lt;a href=&quot;x&quot;&gt;Tom &amp; Jerry&#39;s&lt;/a&gt;"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_require_body_rejects_empty_body() {
+        let mut state = test_state();
+        state.route_configs.insert(
+            "POST /items".to_string(),
+            RouteConfig { require_body: true, ..Default::default() },
+        );
+        let routes = ["POST /items", "cat"];
+
+        let response = send(&routes, state.clone(), post("/items", "text/plain", "")).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let response = send(&routes, state, post("/items", "text/plain", "x")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_forbid_body_rejects_present_body() {
+        let mut state = test_state();
+        state.route_configs.insert(
+            "GET /items".to_string(),
+            RouteConfig { forbid_body: true, ..Default::default() },
+        );
+        let routes = ["GET /items", "echo ok"];
+        let with_body = Request::builder()
+            .uri("/items")
+            .body(Body::from("unexpected"))
+            .unwrap();
+
+        let response = send(&routes, state.clone(), with_body).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let response = send(&routes, state, request("GET", "/items")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn test_exit_status() {
+        assert_eq!(exit_status(400, 4), Some(StatusCode::NOT_FOUND));
+        assert_eq!(exit_status(500, 2), Some(StatusCode::BAD_GATEWAY));
+        assert_eq!(exit_status(400, 0), None);
+        assert_eq!(exit_status(400, 100), None);
+        assert_eq!(exit_status(400, 130), None);
+    }
+
+    #[tokio::test]
+    async fn test_status_from_exit_code() {
+        let state = AppState { status_from_exit: Some(400), ..test_state() };
+        let response = send(&["/item", "exit 4"], state.clone(), request("GET", "/item")).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = send(&["/item", "exit 9"], state, request("GET", "/item")).await;
+        assert_eq!(response.status(), StatusCode::from_u16(409).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_exit_code_without_status_from_exit_is_500() {
+        let response = send(&["/item", "exit 4"], test_state(), request("GET", "/item")).await;
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[tokio::test]
+    async fn test_verify_checksum() {
+        let state = AppState { verify_checksum: true, ..test_state() };
+        let routes = ["POST /upload", "cat"];
+        let upload = |md5: &str| {
+            Request::builder()
+                .method("POST")
+                .uri("/upload")
+                .header("content-md5", md5)
+                .body(Body::from("hello"))
+                .unwrap()
+        };
+
+        let response = send(&routes, state.clone(), upload("XUFAKrxLKna5cZ2REBfFkg==")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = send(&routes, state, upload("AAAAAAAAAAAAAAAAAAAAAA==")).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_query_json_repeated_keys() {
+        let pairs = [("tag", "a"), ("page", "1"), ("tag", "b"), ("tag", "c")]
+            .map(|(k, v)| (k.to_string(), v.to_string()));
+        assert_eq!(query_json(&pairs), json!({"tag": ["a", "b", "c"], "page": "1"}));
+    }
+
+    #[tokio::test]
+    async fn test_repeated_query_keys() {
+        let routes = ["/search", "echo $QUERY_JSON"];
+        let response = send(&routes, test_state(), request("GET", "/search?tag=a&tag=b")).await;
+        assert_eq!(body_string(response).await, "{\"tag\":[\"a\",\"b\"]}\n");
+
+        let state = AppState {
+            shell: ShellType::Bash,
+            query_format: HeaderFormat::Assoc,
+            ..test_state()
+        };
+        let routes = ["/search", "echo ${QUERY[tag]} ${QUERY_tag[@]}"];
+        let response = send(&routes, state, request("GET", "/search?tag=a&tag=b")).await;
+        assert_eq!(body_string(response).await, "b a b\n");
+    }
+
+    #[tokio::test]
+    async fn test_multiline_command_with_headers() {
+        let state = AppState {
+            shell: ShellType::Bash,
+            header_format: HeaderFormat::Assoc,
+            ..test_state()
+        };
+        let command = "greeting=hello\ncat <<EOF\n$greeting ${HEADERS[x-user]} :name\nEOF";
+        let routes = ["/:name", command];
+        let mut request = request("GET", "/there");
+        request.headers_mut().insert("x-user", HeaderValue::from_static("ann"));
+        let response = send(&routes, state, request).await;
+        assert_eq!(body_string(response).await, "hello ann there\n");
+    }
+
+    #[tokio::test]
+    async fn test_invalid_assoc_query_key_falls_back_to_json() {
+        let state = AppState {
+            shell: ShellType::Bash,
+            query_format: HeaderFormat::Assoc,
+            ..test_state()
+        };
+        let routes = ["/q", "echo ${QUERY[page]} $QUERY_JSON"];
+        let response = send(&routes, state, request("GET", "/q?a%5Db=1&page=2")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = body_string(response).await;
+        assert!(body.starts_with("2 {"), "{}", body);
+        assert!(body.contains(r#""a]b":"1""#));
+    }
+
+    #[tokio::test]
+    async fn test_body_markers_select_region() {
+        let captured = CapturedMessages::default();
+        let _guard = captured.set_default();
+        let script = "echo starting; echo @body-start:; echo '{\"ok\": true}'; \
+                      echo @body-end:; echo done";
+        let response = send(&["GET /job", script], test_state(), request("GET", "/job")).await;
+
+        assert_eq!(body_string(response).await, "{\"ok\": true}\n");
+        let messages = captured.messages();
+        assert!(messages.iter().any(|m| m == "/job: starting"));
+        assert!(messages.iter().any(|m| m == "/job: done"));
+    }
+
+    #[tokio::test]
+    async fn test_body_markers_keep_magic_prefixes() {
+        let script = "echo log; echo '@status: 202'; echo @body-start; echo a; echo @body-end";
+        let response = send(&["GET /job", script], test_state(), request("GET", "/job")).await;
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        assert_eq!(body_string(response).await, "a\n");
+    }
+
+    #[test]
+    fn test_is_marker() {
+        assert!(is_marker("@body-start:", "@body-start"));
+        assert!(is_marker("@body-start", "@body-start"));
+        assert!(!is_marker("@body-starts", "@body-start"));
+    }
+
+    #[tokio::test]
+    async fn test_wildcard_segments_array() {
+        let state = AppState { shell: ShellType::Bash, ..test_state() };
+        let routes = ["/files/*path", "echo :path ${#PATH_SEGMENTS[@]} ${PATH_SEGMENTS[@]}"];
+        let response = send(&routes, state, request("GET", "/files/a/b/c")).await;
+        assert_eq!(body_string(response).await, "a/b/c 3 a b c\n");
+    }
+
+    #[tokio::test]
+    async fn test_wildcard_serves_nested_paths() {
+        let routes = ["/static/*path", "echo ./public/:path $PARAM_path"];
+        let response = send(&routes, test_state(), request("GET", "/static/css/site.css")).await;
+        assert_eq!(body_string(response).await, "./public/css/site.css css/site.css\n");
+
+        let response = send(&routes, test_state(), request("GET", "/static/../secret")).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = send(&routes, test_state(), request("GET", "/static/a/%2e%2e/b")).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_wildcard_segments_env_for_sh() {
+        let routes = ["/files/*path", "printf '%s,' $PATH_SEGMENTS"];
+        let response = send(&routes, test_state(), request("GET", "/files/a/b/c")).await;
+        assert_eq!(body_string(response).await, "a,b,c,\n");
+    }
+
+    #[tokio::test]
+    async fn test_route_success_codes() {
+        let mut state = test_state();
+        state.route_configs.insert(
+            "ANY /check".to_string(),
+            RouteConfig { success_codes: Some(vec![0, 1]), ..Default::default() },
+        );
+        let routes = ["/check", "echo partial; exit $(cat)"];
+
+        let response = send(&routes, state.clone(), post("/check", "text/plain", "1")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body_string(response).await, "partial\n");
+
+        let response = send(&routes, state, post("/check", "text/plain", "2")).await;
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[tokio::test]
+    async fn test_route_max_body_size_overrides_global() {
+        let mut state = AppState { max_body_size: Some(10), ..test_state() };
+        state.route_configs.insert(
+            "POST /upload".to_string(),
+            RouteConfig { max_body_size: Some(100), ..Default::default() },
+        );
+        let routes = ["POST /upload", "wc -c", "POST /small", "wc -c"];
+        let body = "x".repeat(50);
+
+        let response = send(&routes, state.clone(), post("/upload", "text/plain", &body)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = send(&routes, state.clone(), post("/small", "text/plain", &body)).await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let body = "x".repeat(150);
+        let response = send(&routes, state, post("/upload", "text/plain", &body)).await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn test_error_code_header() {
+        let error_code = |response: &Response| {
+            assert!(response.headers().contains_key("x-request-id"));
+            response.headers()[ERROR_CODE_HEADER].to_str().unwrap().to_string()
+        };
+
+        let response = send(&["/fail", "exit 3"], test_state(), request("GET", "/fail")).await;
+        assert_eq!(error_code(&response), "nonzero_exit");
+
+        let state = AppState { command_timeout: Some(Duration::from_millis(50)), ..test_state() };
+        let response = send(&["/slow", "sleep 5"], state, request("GET", "/slow")).await;
+        assert_eq!(error_code(&response), "timeout");
+
+        // The environment can't hold a NUL byte, so the command never starts
+        let response = send(&["/items/:id", "echo"], test_state(), request("GET", "/items/a%00b"));
+        assert_eq!(error_code(&response.await), "spawn_failed");
+
+        let state = AppState { max_body_size: Some(4), ..test_state() };
+        let upload = post("/upload", "text/plain", "too long");
+        let response = send(&["POST /upload", "cat"], state, upload).await;
+        assert_eq!(error_code(&response), "too_large");
+
+        let response = send(&["/ok", "echo ok"], test_state(), request("GET", "/ok")).await;
+        assert!(!response.headers().contains_key(ERROR_CODE_HEADER));
+    }
+
+    #[tokio::test]
+    async fn test_unlimited_body_size() {
+        // Larger than axum's own 2 MiB default limit
+        let body = "x".repeat(3 * 1024 * 1024);
+        let state = AppState { max_body_size: None, ..test_state() };
+        let upload = post("/upload", "text/plain", &body);
+        let response = send(&["POST /upload", "wc -c"], state, upload).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body_string(response).await.trim(), "3145728");
+    }
+
+    #[tokio::test]
+    async fn test_route_when_condition() {
+        let mut state = test_state();
+        state.route_configs.insert(
+            "GET /feature".to_string(),
+            RouteConfig {
+                when: Some(parse_condition("header:X-Beta=true").unwrap()),
+                ..Default::default()
+            },
+        );
+        let routes = ["GET /feature", "echo beta"];
+        let beta = Request::builder()
+            .uri("/feature")
+            .header("x-beta", "true")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = send(&routes, state.clone(), beta).await;
+        assert_eq!(body_string(response).await, "beta\n");
+        let response = send(&routes, state.clone(), request("GET", "/feature")).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        state.route_configs.get_mut("GET /feature").unwrap().otherwise =
+            Some("echo stable".to_string());
+        let response = send(&routes, state, request("GET", "/feature")).await;
+        assert_eq!(body_string(response).await, "stable\n");
+    }
+
+    #[tokio::test]
+    async fn test_route_when_query_presence() {
+        let mut state = test_state();
+        state.route_configs.insert(
+            "GET /report".to_string(),
+            RouteConfig { when: Some(parse_condition("query:debug").unwrap()), ..Default::default() },
+        );
+        let routes = ["GET /report", "echo debug"];
+        let response = send(&routes, state.clone(), request("GET", "/report?debug")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = send(&routes, state, request("GET", "/report")).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    fn rpc_state() -> AppState {
+        let mut state = test_state();
+        state.route_configs.insert(
+            "POST /rpc".to_string(),
+            RouteConfig { rpc: true, ..Default::default() },
+        );
+        state.rpc_commands = HashMap::from([
+            ("getUser".to_string(), "echo user $RPC_METHOD".to_string()),
+            ("listItems".to_string(), "echo items".to_string()),
+        ]);
+        state
+    }
+
+    #[tokio::test]
+    async fn test_rpc_dispatches_on_method() {
+        let routes = ["POST /rpc", "echo unused"];
+        let call = |method: &str| {
+            let body = format!(r#"{{"jsonrpc":"2.0","method":"{}","id":1}}"#, method);
+            post("/rpc", "application/json", &body)
+        };
+
+        let response = send(&routes, rpc_state(), call("getUser")).await;
+        assert_eq!(body_string(response).await, "user getUser\n");
+        let response = send(&routes, rpc_state(), call("listItems")).await;
+        assert_eq!(body_string(response).await, "items\n");
+    }
+
+    #[tokio::test]
+    async fn test_rpc_errors() {
+        let routes = ["POST /rpc", "echo unused"];
+        let body = r#"{"jsonrpc":"2.0","method":"dropTables","id":7}"#;
+        let response = send(&routes, rpc_state(), post("/rpc", "application/json", body)).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let error: serde_json::Value =
+            serde_json::from_str(&body_string(response).await).unwrap();
+        assert_eq!(
+            error,
+            json!({
+                "jsonrpc": "2.0",
+                "error": {"code": -32601, "message": "Method not found"},
+                "id": 7,
+            })
+        );
+
+        let response = send(&routes, rpc_state(), post("/rpc", "application/json", "{")).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(body_string(response).await.contains("-32700"));
+
+        let body = r#"{"id":1}"#;
+        let response = send(&routes, rpc_state(), post("/rpc", "application/json", body)).await;
+        assert!(body_string(response).await.contains("-32600"));
+    }
+
+    #[tokio::test]
+    async fn test_blocked_response_header_is_dropped() {
+        let captured = CapturedMessages::default();
+        let _guard = captured.set_default();
+        let state = AppState {
+            blocked_response_headers: vec!["Strict-Transport-Security".to_string()],
+            ..test_state()
+        };
+        let command = "echo '@header: Strict-Transport-Security: max-age=1'; \
+                       echo '@header: X-Custom: yes'; echo ok";
+        let response = send(&["/page", command], state, request("GET", "/page")).await;
+
+        assert!(!response.headers().contains_key("strict-transport-security"));
+        assert_eq!(response.headers()["x-custom"], "yes");
+        assert_eq!(body_string(response).await, "ok\n");
+        let messages = captured.messages();
+        assert!(messages.iter().any(|m| m.contains("Dropping disallowed header")));
+    }
+
+    #[tokio::test]
+    async fn test_max_response_headers() {
+        let captured = CapturedMessages::default();
+        let _guard = captured.set_default();
+        let state = AppState {
+            max_response_headers: Some(2),
+            ..test_state()
+        };
+        let command = "for i in 1 2 3 4; do echo \"@header: X-N$i: $i\"; done; echo ok";
+        let response = send(&["/page", command], state, request("GET", "/page")).await;
+
+        assert_eq!(response.headers()["x-n1"], "1");
+        assert_eq!(response.headers()["x-n2"], "2");
+        assert!(!response.headers().contains_key("x-n3"));
+        assert!(!response.headers().contains_key("x-n4"));
+        assert_eq!(body_string(response).await, "ok\n");
+        let warnings = captured.messages();
+        let warnings: Vec<_> = warnings.iter().filter(|m| m.contains("more than 2")).collect();
+        assert_eq!(warnings.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_allowed_response_headers() {
+        let state = AppState {
+            allowed_response_headers: vec!["x-custom".to_string()],
+            ..test_state()
+        };
+        let command = "echo '@header: X-Custom: yes'; echo '@header: X-Other: no'; echo ok";
+        let response = send(&["/page", command], state, request("GET", "/page")).await;
+
+        assert_eq!(response.headers()["x-custom"], "yes");
+        assert!(!response.headers().contains_key("x-other"));
+    }
+
+    #[tokio::test]
+    async fn test_header_values_with_colons_and_empty_values() {
+        let command = "echo '@header: Location:  http://x:8080/y '; \
+                       echo '@header: X-Empty:'; \
+                       echo '@header: Bad Name: skipped'; echo ok";
+        let response = send(&["/page", command], test_state(), request("GET", "/page")).await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["location"], "http://x:8080/y");
+        assert_eq!(response.headers()["x-empty"], "");
+        assert!(!response.headers().contains_key("bad name"));
+        assert_eq!(body_string(response).await, "ok\n");
+    }
+
+    #[tokio::test]
+    async fn test_cookie_env() {
+        let state = AppState {
+            cookie_env: vec![
+                ("session".to_string(), "SESSION_ID".to_string()),
+                ("theme".to_string(), "THEME".to_string()),
+            ],
+            ..test_state()
+        };
+        let routes = ["/me", "echo \"${SESSION_ID:-none} ${THEME-unset}\""];
+        let with_cookie = Request::builder()
+            .uri("/me")
+            .header(header::COOKIE, "other=1; session=abc123")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = send(&routes, state.clone(), with_cookie).await;
+        assert_eq!(body_string(response).await, "abc123 unset\n");
+        let response = send(&routes, state, request("GET", "/me")).await;
+        assert_eq!(body_string(response).await, "none unset\n");
+    }
+
+    #[tokio::test]
+    async fn test_cookies_json_and_assoc() {
+        let with_cookies = || {
+            Request::builder()
+                .uri("/me")
+                .header(header::COOKIE, "session=abc; broken; theme=\"dark\"; session=older")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let routes = ["/me", "echo $COOKIES_JSON"];
+        let response = send(&routes, test_state(), with_cookies()).await;
+        let body = body_string(response).await;
+        let cookies: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(cookies, json!({"session": "abc", "theme": "dark"}));
+
+        let state = AppState {
+            shell: ShellType::Bash,
+            cookie_format: HeaderFormat::Assoc,
+            ..test_state()
+        };
+        let routes = ["/me", "echo ${COOKIES[session]} ${COOKIES[theme]} ${#COOKIES[@]}"];
+        let response = send(&routes, state, with_cookies()).await;
+        assert_eq!(body_string(response).await, "abc dark 2\n");
+    }
+
+    #[tokio::test]
+    async fn test_body_format_env() {
+        let routes = ["POST /echo", "echo \"$REQUEST_BODY|$(cat)\""];
+        let state = AppState { body_format: BodyFormat::Env, ..test_state() };
+        let response = send(&routes, state, post("/echo", "text/plain", "hello")).await;
+        assert_eq!(body_string(response).await, "hello|\n");
+
+        let state = AppState { body_format: BodyFormat::Both, ..test_state() };
+        let response = send(&routes, state, post("/echo", "text/plain", "hello")).await;
+        assert_eq!(body_string(response).await, "hello|hello\n");
+
+        let response = send(&routes, test_state(), post("/echo", "text/plain", "hello")).await;
+        assert_eq!(body_string(response).await, "|hello\n");
+    }
+
+    #[tokio::test]
+    async fn test_body_env_size_cap() {
+        let routes = ["POST /echo", "echo \"${REQUEST_BODY-unset}\""];
+        let state = AppState {
+            body_format: BodyFormat::Env,
+            max_body_env: Some(4),
+            ..test_state()
+        };
+        let response = send(&routes, state.clone(), post("/echo", "text/plain", "four")).await;
+        assert_eq!(body_string(response).await, "four\n");
+        let response = send(&routes, state, post("/echo", "text/plain", "five!")).await;
+        assert_eq!(body_string(response).await, "unset\n");
+    }
+
+    #[tokio::test]
+    async fn test_shell_header() {
+        // `; true` keeps bash from exec'ing readlink in place of itself
+        let routes = ["/which", "readlink /proc/$$/exe; true"];
+        let with_shell = |shell| {
+            let mut request = request("GET", "/which");
+            request.headers_mut().insert(SHELL_HEADER, HeaderValue::from_static(shell));
+            request
+        };
+        let state = || AppState { allowed_request_shells: vec![ShellType::Bash], ..test_state() };
+        let default = body_string(send(&routes, state(), request("GET", "/which")).await).await;
+
+        let response = send(&routes, state(), with_shell("bash")).await;
+        assert!(body_string(response).await.trim_end().ends_with("bash"));
+
+        // Neither unlisted nor unknown shells take effect
+        let response = send(&routes, test_state(), with_shell("bash")).await;
+        assert_eq!(body_string(response).await, default);
+        let response = send(&routes, state(), with_shell("python3")).await;
+        assert_eq!(body_string(response).await, default);
+    }
+
+    #[tokio::test]
+    async fn test_routing_context_env() {
+        let routes = ["/users/:id", "echo $REQUEST_METHOD $REQUEST_PATH $MATCHED_ROUTE $PARAM_id"];
+        let response = send(&routes, test_state(), request("DELETE", "/users/42")).await;
+        assert_eq!(body_string(response).await, "DELETE /users/42 /users/{id} 42\n");
+    }
+
+    #[tokio::test]
+    async fn test_request_scheme_env() {
+        let routes = ["/scheme", "echo $REQUEST_SCHEME"];
+        let forwarded = || {
+            let mut request = request("GET", "/scheme");
+            request
+                .headers_mut()
+                .insert("x-forwarded-proto", HeaderValue::from_static("https, http"));
+            request
+        };
+        let response = send(&routes, test_state(), forwarded()).await;
+        assert_eq!(body_string(response).await, "http\n", "X-Forwarded-Proto is untrusted");
+
+        let state = AppState { trust_proxy: true, ..test_state() };
+        let response = send(&routes, state, forwarded()).await;
+        assert_eq!(body_string(response).await, "https\n");
+
+        let state = AppState { tls: true, ..test_state() };
+        let response = send(&routes, state, request("GET", "/scheme")).await;
+        assert_eq!(body_string(response).await, "https\n");
+    }
+
+    #[tokio::test]
+    async fn test_deprecated_route_headers() {
+        let mut state = test_state();
+        state.route_configs.insert(
+            "GET /old".to_string(),
+            RouteConfig {
+                deprecated: true,
+                sunset: Some(HeaderValue::from_static("Wed, 31 Dec 2025 23:59:59 GMT")),
+                ..Default::default()
+            },
+        );
+        let routes = ["GET /old", "echo old", "GET /new", "echo new"];
+
+        let response = send(&routes, state.clone(), request("GET", "/old")).await;
+        assert_eq!(response.headers()["deprecation"], "true");
+        assert_eq!(response.headers()["sunset"], "Wed, 31 Dec 2025 23:59:59 GMT");
+
+        let response = send(&routes, state, request("GET", "/new")).await;
+        assert!(!response.headers().contains_key("deprecation"));
+        assert!(!response.headers().contains_key("sunset"));
+    }
+
+    #[tokio::test]
+    async fn test_nonce_replay_is_rejected() {
+        let state = AppState {
+            nonces: Some(Arc::new(NonceStore::new(
+                "X-Nonce".to_string(),
+                Duration::from_secs(60),
+            ))),
+            ..test_state()
+        };
+        let app = router(&["POST /hook", "echo ok"], state);
+        let hook = |nonce: Option<&str>| {
+            let mut request = Request::builder().method("POST").uri("/hook");
+            if let Some(nonce) = nonce {
+                request = request.header("x-nonce", nonce);
+            }
+            request.body(Body::empty()).unwrap()
+        };
+
+        let response = app.clone().oneshot(hook(Some("n-1"))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app.clone().oneshot(hook(Some("n-2"))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = app.clone().oneshot(hook(Some("n-1"))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let response = app.oneshot(hook(None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_rejected_request_keeps_its_nonce() {
+        let state = AppState {
+            nonces: Some(Arc::new(NonceStore::new(
+                "X-Nonce".to_string(),
+                Duration::from_secs(60),
+            ))),
+            concurrency: Some(Arc::new(Scheduler::new(1, Scheduling::Fifo))),
+            on_overload: Overload::Reject,
+            ..test_state()
+        };
+        let app = router(&["/slow", "sleep 0.3; echo done", "/hook", "echo ok"], state);
+        let with_nonce = |uri: &str, nonce: &str| {
+            let request = Request::builder().uri(uri).header("x-nonce", nonce);
+            request.body(Body::empty()).unwrap()
+        };
+
+        let busy = tokio::spawn(app.clone().oneshot(with_nonce("/slow", "n-0")));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let response = app.clone().oneshot(with_nonce("/hook", "n-1")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        let response = busy.await.unwrap().unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app.oneshot(with_nonce("/hook", "n-1")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_uuid_validator() {
+        let state = AppState {
+            validators: vec![("id".to_string(), Validator::Uuid)],
+            ..test_state()
+        };
+        let routes = ["/users/:id", "echo ok"];
+        let uri = "/users/123e4567-e89b-12d3-a456-426614174000";
+        let response = send(&routes, state.clone(), request("GET", uri)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = send(&routes, state, request("GET", "/users/42")).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(body_string(response).await, "Invalid parameter 'id': expected uuid");
+    }
+
+    #[tokio::test]
+    async fn test_int_validator_on_query_param() {
+        let state = AppState {
+            validators: vec![("limit".to_string(), Validator::Int)],
+            ..test_state()
+        };
+        let routes = ["/items", "echo ok"];
+        let response = send(&routes, state.clone(), request("GET", "/items?limit=10")).await;
+        assert_eq!(response.status(), StatusCode::OK);
 
-use crate::shell::{build_shell_script, HeaderFormat};
-use crate::state::AppState;
+        let response = send(&routes, state, request("GET", "/items?limit=ten")).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(body_string(response).await.contains("'limit'"));
+    }
 
-pub async fn handler(
-    Extension(state): Extension<Arc<AppState>>,
-    method: Method,
-    matched_path: MatchedPath,
-    Path(params): Path<HashMap<String, String>>,
-    Query(query_params): Query<HashMap<String, String>>,
-    headers: HeaderMap,
-    body: Bytes,
-) -> Response {
-    let route_pattern = matched_path.as_str();
-    let method_str = method.as_str();
+    #[tokio::test]
+    async fn test_server_timing_header() {
+        let state = AppState { server_timing: true, ..test_state() };
+        let response = send(&["/t", "echo hi"], state, request("GET", "/t")).await;
+        let timing = response.headers()["server-timing"].to_str().unwrap();
+        let phases: Vec<_> = timing.split(", ").filter_map(|m| m.split_once(";dur=")).collect();
+        let names: Vec<_> = phases.iter().map(|(name, _)| *name).collect();
+        assert_eq!(names, ["queue", "spawn", "exec", "body"]);
+        assert!(phases.iter().all(|(_, dur)| dur.parse::<f64>().is_ok()));
+    }
 
-    debug!(
-        "Handling {} request for: {} (body: {} bytes)",
-        method_str,
-        route_pattern,
-        body.len()
-    );
+    #[tokio::test]
+    async fn test_flush_sends_output_before_command_finishes() {
+        // The command only finishes once the test has seen the flushed part
+        let done = std::env::temp_dir().join(format!("sherut-flush-{}", std::process::id()));
+        let command = format!(
+            "echo '@header: X-Stage: early'; echo working; echo @flush:; \
+             while [ ! -e '{}' ]; do sleep 0.05; done; echo finished",
+            done.display()
+        );
+        let state = AppState { command_timeout: Some(Duration::from_secs(10)), ..test_state() };
+        let response = send(&["/slow", &command], state, request("GET", "/slow")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["x-stage"], "early");
+        assert!(!response.headers().contains_key(EXIT_CODE_HEADER));
 
-    // Try method-specific key first, then fall back to ANY
-    let method_key = format!("{} {}", method_str, route_pattern);
-    let any_key = format!("ANY {}", route_pattern);
+        let mut body = response.into_body();
+        let frame = std::future::poll_fn(|cx| std::pin::Pin::new(&mut body).poll_frame(cx)).await;
+        let first = frame.unwrap().unwrap().into_data().unwrap();
+        assert_eq!(&first[..], b"working\n");
+        std::fs::write(&done, "").unwrap();
+        let rest = axum::body::to_bytes(body, usize::MAX).await.unwrap();
+        assert_eq!(&rest[..], b"finished\n");
+        std::fs::remove_file(&done).unwrap();
+    }
 
-    let command_template = state
-        .commands
-        .get(&method_key)
-        .or_else(|| state.commands.get(&any_key));
+    #[tokio::test]
+    async fn test_flushed_command_keeps_its_slot() {
+        let state = AppState {
+            concurrency: Some(Arc::new(Scheduler::new(1, Scheduling::Fifo))),
+            on_overload: Overload::Reject,
+            ..test_state()
+        };
+        let app = router(&["/slow", "echo @flush:; sleep 0.3; echo done"], state);
 
-    let command_template = match command_template {
-        Some(cmd) => cmd,
-        None => {
-            error!(
-                "Route config missing for: {} {}",
-                method_str, route_pattern
-            );
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Config Error".to_string(),
-            )
-                .into_response();
-        }
-    };
+        let flushed = app.clone().oneshot(request("GET", "/slow")).await.unwrap();
+        assert_eq!(flushed.status(), StatusCode::OK);
+        let response = app.clone().oneshot(request("GET", "/slow")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
 
-    // Replace :param placeholders in command with actual values
-    let mut command_with_params = command_template.clone();
-    for (key, value) in &params {
-        // Escape single quotes in the value for shell safety
-        let safe_value = value.replace("'", "'\\''");
-        command_with_params = command_with_params.replace(&format!(":{}", key), &safe_value);
+        assert_eq!(body_string(flushed).await, "done\n");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let response = app.oneshot(request("GET", "/slow")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
     }
 
-    // Collect headers into a map
-    let mut headers_map: HashMap<String, String> = HashMap::new();
-    for (key, value) in headers.iter() {
-        if let Ok(val_str) = value.to_str() {
-            headers_map.insert(key.to_string(), val_str.to_string());
+    #[tokio::test]
+    async fn test_flushed_exit_status_reaches_circuit_breaker() {
+        let state = AppState {
+            circuit_breaker: Some(Arc::new(CircuitBreaker::new(1, Duration::from_secs(60)))),
+            ..test_state()
+        };
+        let app = router(&["/flaky", "echo @flush:; echo partial; exit 1"], state);
+
+        let response = app.clone().oneshot(request("GET", "/flaky")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body_string(response).await, "partial\n");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let response = app.oneshot(request("GET", "/flaky")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn test_post_processed_routes_ignore_flush() {
+        let mut state = test_state();
+        let split = RouteConfig { split_delimiter: Some("\n".to_string()), ..Default::default() };
+        state.route_configs.insert("ANY /split".to_string(), split);
+        let command = "echo a; echo @flush:; sleep 0.1; echo b";
+        let response = send(&["/split", command], state, request("GET", "/split")).await;
+        assert_eq!(response.headers()[EXIT_CODE_HEADER], "0");
+        assert_eq!(body_string(response).await, r#"["a","b"]"#);
+    }
+
+    #[tokio::test]
+    async fn test_exit_code_headers() {
+        let routes = ["/ok", "echo fine", "/fail", "exit 3", "/killed", "kill -TERM $$"];
+        let response = send(&routes, test_state(), request("GET", "/ok")).await;
+        assert_eq!(response.headers()[EXIT_CODE_HEADER], "0");
+        assert!(!response.headers().contains_key(SIGNAL_HEADER));
+
+        let response = send(&routes, test_state(), request("GET", "/fail")).await;
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(response.headers()[EXIT_CODE_HEADER], "3");
+
+        let response = send(&routes, test_state(), request("GET", "/killed")).await;
+        assert!(!response.headers().contains_key(EXIT_CODE_HEADER));
+        if cfg!(unix) {
+            assert_eq!(response.headers()[SIGNAL_HEADER], "15");
         }
     }
 
-    // Build the shell script based on shell type and header format
-    let shell_script = build_shell_script(
-        &state.shell,
-        &state.header_format,
-        &headers_map,
-        &state.query_format,
-        &query_params,
-        &command_with_params,
-    );
+    #[tokio::test]
+    async fn test_debug_echo_script() {
+        let routes = ["/users/:id", "echo :id"];
+        let response = send(&routes, test_state(), request("GET", "/users/7")).await;
+        assert!(!response.headers().contains_key(DEBUG_SCRIPT_HEADER));
 
-    // Build command with environment inheritance
-    let mut cmd = Command::new(state.shell.executable());
-    cmd.arg("-c").arg(&shell_script);
-    cmd.stdin(Stdio::piped());
-    cmd.stdout(Stdio::piped());
-    cmd.stderr(Stdio::piped());
+        let state = AppState { debug_echo_script: true, ..test_state() };
+        let response = send(&routes, state, request("GET", "/users/7")).await;
+        let encoded = response.headers()[DEBUG_SCRIPT_HEADER].to_str().unwrap();
+        let script = String::from_utf8(BASE64.decode(encoded).unwrap()).unwrap();
+        assert_eq!(script, "echo 7");
+        assert_eq!(body_string(response).await, "7\n");
 
-    // For JSON header format, also set as environment variable
-    if state.header_format == HeaderFormat::Json {
-        let headers_json = json!(headers_map).to_string();
-        cmd.env("HEADERS_JSON", &headers_json);
+        // Long scripts are cut to DEBUG_SCRIPT_MAX bytes
+        let long = format!("echo {}", "x".repeat(DEBUG_SCRIPT_MAX));
+        let state = AppState { debug_echo_script: true, ..test_state() };
+        let response = send(&["/long", &long], state, request("GET", "/long")).await;
+        let encoded = response.headers()[DEBUG_SCRIPT_HEADER].to_str().unwrap();
+        assert_eq!(BASE64.decode(encoded).unwrap(), long.as_bytes()[..DEBUG_SCRIPT_MAX]);
     }
 
-    // For JSON query format, also set as environment variable
-    if state.query_format == HeaderFormat::Json {
-        let query_json = json!(query_params).to_string();
-        cmd.env("QUERY_JSON", &query_json);
+    #[tokio::test]
+    async fn test_server_timing_off_by_default() {
+        let response = send(&["/t", "echo hi"], test_state(), request("GET", "/t")).await;
+        assert!(!response.headers().contains_key("server-timing"));
     }
 
-    // Spawn process and write body to stdin
-    let child = cmd.spawn();
-
-    let output = match child {
-        Ok(mut child) => {
-            // Write request body to stdin
-            if let Some(mut stdin) = child.stdin.take() {
-                if let Err(e) = stdin.write_all(&body).await {
-                    warn!("Failed to write to stdin: {}", e);
-                }
-                drop(stdin); // Close stdin to signal EOF
-            }
-            child.wait_with_output().await
-        }
-        Err(e) => Err(e),
-    };
+    #[tokio::test]
+    async fn test_request_filter_transforms_body() {
+        let state = AppState {
+            request_filter: Some("tr a-z A-Z".to_string()),
+            ..test_state()
+        };
+        let response = send(&["POST /up", "cat"], state, post("/up", "text/plain", "hello")).await;
+        assert_eq!(body_string(response).await, "HELLO\n");
+    }
 
-    match output {
-        Ok(out) => {
-            let stdout = String::from_utf8_lossy(&out.stdout).to_string();
-            let stderr = String::from_utf8_lossy(&out.stderr).to_string();
+    #[tokio::test]
+    async fn test_request_filter_failure_is_400() {
+        let state = AppState {
+            request_filter: Some("echo invalid signature >&2; exit 1".to_string()),
+            ..test_state()
+        };
+        let response = send(&["POST /up", "cat"], state, post("/up", "text/plain", "x")).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(body_string(response).await.contains("invalid signature"));
+    }
 
-            if !out.status.success() {
-                warn!("Command failed. Stderr: {}", stderr);
-                return (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    format!("Error:\n{}", stderr),
-                )
-                    .into_response();
-            }
+    #[tokio::test]
+    async fn test_whitespace_output_as_empty_is_204() {
+        let state = AppState { treat_whitespace_as_empty: true, ..test_state() };
+        let response = send(&["/x", "printf ' \\n\\t\\n'"], state, request("GET", "/x")).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(body_string(response).await, "");
+    }
 
-            // --- MAGIC PREFIX PARSING START ---
-            let mut builder = Response::builder().status(StatusCode::OK);
-            let mut body_accum = String::new();
-            let mut content_type_set = false;
+    #[tokio::test]
+    async fn test_whitespace_output_uses_empty_status() {
+        let state = AppState {
+            treat_whitespace_as_empty: true,
+            empty_status: Some(StatusCode::NOT_FOUND),
+            ..test_state()
+        };
+        let response = send(&["/x", "echo"], state.clone(), request("GET", "/x")).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
 
-            for line in stdout.lines() {
-                if let Some(val) = line.strip_prefix("@header:") {
-                    // Syntax: @header: Content-Type: application/json
-                    if let Some((k, v)) = val.split_once(':') {
-                        let header_name = k.trim().to_lowercase();
-                        if header_name == "content-type" {
-                            content_type_set = true;
-                        }
-                        builder = builder.header(k.trim(), v.trim());
-                        debug!("Set Header: {} -> {}", k.trim(), v.trim());
-                    }
-                } else if let Some(val) = line.strip_prefix("@status:") {
-                    // Syntax: @status: 404
-                    if let Ok(code) = val.trim().parse::<u16>() {
-                        if let Ok(status_code) = StatusCode::from_u16(code) {
-                            builder = builder.status(status_code);
-                            debug!("Set Status: {}", status_code);
-                        }
-                    }
-                } else {
-                    // Normal content
-                    body_accum.push_str(line);
-                    body_accum.push('\n');
-                }
-            }
+        // An explicit status wins
+        let command = "echo '@status: 200'; echo ' '";
+        let response = send(&["/x", command], state, request("GET", "/x")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
 
-            // Auto-detect Content-Type if not explicitly set
-            if !content_type_set {
-                let detected = detect_content_type(&body_accum);
-                builder = builder.header("Content-Type", detected);
-                debug!("Auto-detected Content-Type: {}", detected);
-            }
+    #[tokio::test]
+    async fn test_whitespace_output_without_flag_is_kept() {
+        let response = send(&["/x", "echo ' '"], test_state(), request("GET", "/x")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body_string(response).await, " \n");
+    }
 
-            // Return the built response
-            builder.body(body_accum).unwrap().into_response()
-            // --- MAGIC PREFIX PARSING END ---
-        }
-        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    #[tokio::test]
+    async fn test_csrf_double_submit() {
+        let state = AppState { csrf_cookie: Some("csrf_token".to_string()), ..test_state() };
+        let routes = ["POST /items", "echo ok", "GET /items", "echo ok"];
+        let post_with = |token: &str| {
+            Request::builder()
+                .method("POST")
+                .uri("/items")
+                .header(header::COOKIE, "csrf_token=abc")
+                .header("x-csrf-token", token)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = send(&routes, state.clone(), post_with("abc")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = send(&routes, state.clone(), post_with("nope")).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let response = send(&routes, state.clone(), request("POST", "/items")).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let response = send(&routes, state, request("GET", "/items")).await;
+        assert_eq!(response.status(), StatusCode::OK);
     }
-}
 
-/// Auto-detect content type based on body content
-fn detect_content_type(body: &str) -> &'static str {
-    let trimmed = body.trim();
+    #[tokio::test]
+    async fn test_as_sse_frames_each_line() {
+        let mut state = test_state();
+        state.route_configs.insert(
+            "ANY /events".to_string(),
+            RouteConfig { as_sse: true, ..Default::default() },
+        );
+        let command = "echo one; echo '@header: X-Source: test'; echo two; echo three";
+        let response = send(&["/events", command], state, request("GET", "/events")).await;
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "text/event-stream");
+        assert_eq!(response.headers()["x-source"], "test");
+        assert_eq!(
+            body_string(response).await,
+            "data: one\n\ndata: two\n\ndata: three\n\n"
+        );
+    }
 
-    // Check for JSON: starts with { or [
-    if (trimmed.starts_with('{') && trimmed.ends_with('}'))
-        || (trimmed.starts_with('[') && trimmed.ends_with(']'))
-    {
-        // Verify it's valid JSON
-        if serde_json::from_str::<serde_json::Value>(trimmed).is_ok() {
-            return "application/json";
-        }
+    #[tokio::test]
+    async fn test_request_id_env() {
+        let request = Request::builder()
+            .uri("/id")
+            .header("x-request-id", "req-42")
+            .body(Body::empty())
+            .unwrap();
+        let response = send(&["/id", "echo \"$REQUEST_ID\""], test_state(), request).await;
+        assert_eq!(response.headers()["x-request-id"], "req-42");
+        assert_eq!(body_string(response).await, "req-42\n");
     }
 
-    // Check for HTML/XML: starts with <
-    if trimmed.starts_with('<') {
-        let lower = trimmed.to_lowercase();
-        
-        // Check for HTML first (more specific)
-        if lower.starts_with("<!doctype html") || lower.starts_with("<html") {
-            return "text/html";
+    #[tokio::test]
+    async fn test_server_start_time_and_uptime_env() {
+        let response = send(
+            &["/uptime", "echo \"$SERVER_START_TIME $SERVER_UPTIME_SECONDS\""],
+            test_state(),
+            request("GET", "/uptime"),
+        )
+        .await;
+        let body = body_string(response).await;
+        let (start, uptime) = body.trim().split_once(' ').unwrap();
+        assert!(start.parse::<u64>().unwrap() > 0);
+        assert!(uptime.parse::<u64>().is_ok(), "uptime: {:?}", uptime);
+    }
+
+    #[test]
+    fn test_render_header_template() {
+        let rendered = render_header_template(
+            ":id/:query.n/:header.x-user/:query.missing/12:30",
+            &params(&[("id", "7")]),
+            &params(&[("n", "3")]),
+            &params(&[("x-user", "bob")]),
+        );
+        assert_eq!(rendered, "7/3/bob//12:30");
+    }
+
+    fn response_header_state() -> AppState {
+        let mut state = test_state();
+        state.route_configs.insert(
+            "GET /items/{id}".to_string(),
+            RouteConfig {
+                response_headers: vec![
+                    ("X-Count".to_string(), ":query.n".to_string()),
+                    ("X-Item".to_string(), "item-:id".to_string()),
+                ],
+                ..Default::default()
+            },
+        );
+        state
+    }
+
+    #[tokio::test]
+    async fn test_response_header_template() {
+        let response = send(
+            &["GET /items/:id", "echo ok"],
+            response_header_state(),
+            request("GET", "/items/42?n=5"),
+        )
+        .await;
+        assert_eq!(response.headers()["x-count"], "5");
+        assert_eq!(response.headers()["x-item"], "item-42");
+    }
+
+    #[tokio::test]
+    async fn test_command_header_overrides_template() {
+        let response = send(
+            &["GET /items/:id", "echo '@header: X-Count: 99'"],
+            response_header_state(),
+            request("GET", "/items/42?n=5"),
+        )
+        .await;
+        let counts: Vec<_> = response.headers().get_all("x-count").iter().collect();
+        assert_eq!(counts, vec!["99"]);
+    }
+
+    #[tokio::test]
+    async fn test_queue_timeout_rejects_with_503() {
+        let state = AppState {
+            concurrency: Some(Arc::new(Scheduler::new(1, Scheduling::Fifo))),
+            queue_timeout: Some(Duration::from_millis(50)),
+            ..test_state()
+        };
+        let app = router(&["/slow", "sleep 0.5; echo done"], state);
+
+        let busy = tokio::spawn(app.clone().oneshot(request("GET", "/slow")));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let response = app.oneshot(request("GET", "/slow")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "1");
+
+        let response = busy.await.unwrap().unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_on_overload_reject() {
+        let state = AppState {
+            concurrency: Some(Arc::new(Scheduler::new(1, Scheduling::Fifo))),
+            on_overload: Overload::Reject,
+            ..test_state()
+        };
+        let app = router(&["/slow", "sleep 0.3; echo done"], state);
+
+        let busy = tokio::spawn(app.clone().oneshot(request("GET", "/slow")));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let started = Instant::now();
+        let response = app.clone().oneshot(request("GET", "/slow")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(started.elapsed() < Duration::from_millis(150), "rejected without waiting");
+
+        let response = busy.await.unwrap().unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app.oneshot(request("GET", "/slow")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_shell_concurrency_serializes_commands() {
+        let state = AppState {
+            shell_concurrency: HashMap::from([(
+                ShellType::Sh,
+                Arc::new(Scheduler::new(1, Scheduling::Fifo)),
+            )]),
+            ..test_state()
+        };
+        let app = router(&["/slow", "sleep 0.2; echo done"], state);
+
+        let started = Instant::now();
+        let first = tokio::spawn(app.clone().oneshot(request("GET", "/slow")));
+        let second = tokio::spawn(app.oneshot(request("GET", "/slow")));
+        for task in [first, second] {
+            assert_eq!(task.await.unwrap().unwrap().status(), StatusCode::OK);
         }
-        
-        // Check for XML declaration or common XML patterns
-        if trimmed.starts_with("<?xml")
-            || trimmed.starts_with("<!DOCTYPE")
-            || (trimmed.starts_with('<') && trimmed.ends_with('>') && trimmed.contains("</"))
-        {
-            return "application/xml";
+        assert!(started.elapsed() >= Duration::from_millis(400));
+    }
+
+    #[tokio::test]
+    async fn test_shell_concurrency_ignores_other_shells() {
+        let state = AppState {
+            shell_concurrency: HashMap::from([(
+                ShellType::Zsh,
+                Arc::new(Scheduler::new(1, Scheduling::Fifo)),
+            )]),
+            ..test_state()
+        };
+        let app = router(&["/slow", "sleep 0.2; echo done"], state);
+
+        let started = Instant::now();
+        let first = tokio::spawn(app.clone().oneshot(request("GET", "/slow")));
+        let second = tokio::spawn(app.oneshot(request("GET", "/slow")));
+        for task in [first, second] {
+            assert_eq!(task.await.unwrap().unwrap().status(), StatusCode::OK);
         }
+        assert!(started.elapsed() < Duration::from_millis(400));
     }
 
-    // Default to plain text
-    "text/plain"
-}
+    fn jq_state(expression: &str) -> AppState {
+        let mut state = test_state();
+        state.route_configs.insert(
+            "ANY /users".to_string(),
+            RouteConfig {
+                jq: Some(Arc::new(JqFilter::new(expression).unwrap())),
+                ..Default::default()
+            },
+        );
+        state
+    }
 
-pub async fn fallback_handler() -> (StatusCode, String) {
-    (StatusCode::NOT_FOUND, "Route not found".to_string())
-}
+    #[tokio::test]
+    async fn test_jq_filters_json_output() {
+        let routes = ["/users", r#"echo '{"data": [{"id": 1}], "total": 1}'"#];
+        let response = send(&routes, jq_state(".data"), request("GET", "/users")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/json");
+        assert_eq!(body_string(response).await, "[{\"id\":1}]\n");
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[tokio::test]
+    async fn test_jq_on_invalid_json_is_500() {
+        let routes = ["/users", "echo not json"];
+        let response = send(&routes, jq_state(".data"), request("GET", "/users")).await;
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[test]
+    fn test_is_binary_content_type() {
+        assert!(is_binary_content_type("image/png"));
+        assert!(is_binary_content_type("application/octet-stream"));
+        assert!(!is_binary_content_type("text/plain; charset=utf-8"));
+        assert!(!is_binary_content_type("application/json"));
+        assert!(!is_binary_content_type("application/problem+json"));
+    }
+
+    #[tokio::test]
+    async fn test_binary_content_type_preserves_bytes() {
+        // PNG signature (with \r\n and \n) followed by invalid UTF-8 and a lone \r
+        let command = "printf '@header: Content-Type: image/png\\n@status: 201\\n'; \
+                       printf '\\211PNG\\r\\n\\032\\n\\377\\000@x\\r'";
+        let response = send(&["/logo", command], test_state(), request("GET", "/logo")).await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "image/png");
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&bytes[..], b"\x89PNG\r\n\x1a\n\xff\x00@x\r");
+    }
+
+    #[tokio::test]
+    async fn test_cookie_lines_add_set_cookie_headers() {
+        let command = "echo '@cookie: session=abc; Path=/; HttpOnly'; \
+                       echo '@cookie: theme=dark'; echo ok";
+        let response = send(&["/login", command], test_state(), request("GET", "/login")).await;
+        let cookies: Vec<_> = response.headers().get_all(header::SET_COOKIE).iter().collect();
+        assert_eq!(cookies, ["session=abc; Path=/; HttpOnly", "theme=dark"]);
+        assert_eq!(body_string(response).await, "ok\n");
+    }
+
+    #[tokio::test]
+    async fn test_non_utf8_output_without_magic_lines_is_passed_through() {
+        let command = "printf '\\037\\213\\010\\000\\377\\r\\n@x\\n'";
+        let response = send(&["/data.gz", command], test_state(), request("GET", "/data.gz")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/octet-stream");
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&bytes[..], b"\x1f\x8b\x08\x00\xff\r\n@x\n");
+    }
+
+    #[tokio::test]
+    async fn test_non_utf8_output_with_magic_lines_is_parsed() {
+        let command = "printf '@status: 202\\n\\377\\n'";
+        let response = send(&["/x", command], test_state(), request("GET", "/x")).await;
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        assert_eq!(body_string(response).await, "\u{fffd}\n");
+    }
+
+    #[test]
+    fn test_has_magic_lines() {
+        assert!(has_magic_lines(b"\xff\n@status: 200\n"));
+        assert!(!has_magic_lines(b"\xff\n@x\n"));
+    }
+
+    fn split_state(delimiter: &str) -> AppState {
+        let mut state = test_state();
+        state.route_configs.insert(
+            "ANY /list".to_string(),
+            RouteConfig {
+                split_delimiter: Some(delimiter.to_string()),
+                ..Default::default()
+            },
+        );
+        state
+    }
+
+    #[tokio::test]
+    async fn test_split_lines_to_json() {
+        let routes = ["/list", "printf 'a\\n@status: 404\\n\"c\"\\n'"];
+        let response = send(&routes, split_state("\n"), request("GET", "/list")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/json");
+        assert_eq!(body_string(response).await, r#"["a","@status: 404","\"c\""]"#);
+    }
+
+    #[tokio::test]
+    async fn test_split_custom_delimiter_to_json() {
+        let routes = ["/list", "echo '1,2,3,'"];
+        let response = send(&routes, split_state(","), request("GET", "/list")).await;
+        assert_eq!(body_string(response).await, r#"["1","2","3"]"#);
+    }
+
+    #[tokio::test]
+    async fn test_split_empty_output_to_json() {
+        let response = send(&["/list", "true"], split_state("\n"), request("GET", "/list")).await;
+        assert_eq!(body_string(response).await, "[]");
+    }
+
+    fn wrap_html_state() -> AppState {
+        let mut state = test_state();
+        state.route_configs.insert(
+            "ANY /status".to_string(),
+            RouteConfig {
+                wrap_html: Some("<html><pre>{{body}}</pre></html>".to_string()),
+                ..Default::default()
+            },
+        );
+        state
+    }
+
+    #[tokio::test]
+    async fn test_wrap_html_embeds_escaped_output() {
+        let response = send(
+            &["/status", "echo '<b>load</b> & uptime'"],
+            wrap_html_state(),
+            request("GET", "/status"),
+        )
+        .await;
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "text/html");
+        assert_eq!(
+            body_string(response).await,
+            "<html><pre>&lt;b&gt;load&lt;/b&gt; &amp; uptime\n</pre></html>"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_wrap_html_without_escaping() {
+        let state = AppState { no_escape: true, ..wrap_html_state() };
+        let response = send(&["/status", "echo '<b>ok</b>'"], state, request("GET", "/status")).await;
+        assert_eq!(body_string(response).await, "<html><pre><b>ok</b>\n</pre></html>");
+    }
+
+    #[tokio::test]
+    async fn test_stdin_encoding_base64() {
+        let mut state = test_state();
+        state.route_configs.insert(
+            "POST /upload".to_string(),
+            RouteConfig {
+                stdin_encoding: StdinEncoding::Base64,
+                ..Default::default()
+            },
+        );
+        let request = Request::builder()
+            .method("POST")
+            .uri("/upload")
+            .body(Body::from(vec![0u8, 159, 146, 150]))
+            .unwrap();
+        let response = send(&["POST /upload", "cat"], state, request).await;
+        assert_eq!(body_string(response).await, "AJ+Slg==\n");
+    }
+
+    fn request_to_host(uri: &str, host: &str) -> Request<Body> {
+        Request::builder()
+            .uri(uri)
+            .header(header::HOST, host)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_host_scoped_routes() {
+        let routes = [
+            "GET api.example.com/x", "echo api",
+            "GET admin.example.com/x", "echo admin",
+            "GET /x", "echo default",
+        ];
+
+        let request = request_to_host("/x", "API.example.com:8080");
+        let response = send(&routes, test_state(), request).await;
+        assert_eq!(body_string(response).await, "api\n");
+
+        let request = request_to_host("/x", "admin.example.com");
+        let response = send(&routes, test_state(), request).await;
+        assert_eq!(body_string(response).await, "admin\n");
+
+        // Host-agnostic fallback for other hosts
+        let request = request_to_host("/x", "other.com");
+        let response = send(&routes, test_state(), request).await;
+        assert_eq!(body_string(response).await, "default\n");
+    }
+
+    #[tokio::test]
+    async fn test_host_scoped_route_does_not_match_other_hosts() {
+        let routes = ["GET api.example.com/x", "echo api"];
+        let response = send(&routes, test_state(), request_to_host("/x", "other.com")).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_only_on_other_hosts() {
+        let mut state = test_state();
+        for key in ["GET api.example.com/a/x", "GET /y", "GET api.example.com/users/{id}"] {
+            state.commands.insert(key.to_string(), "echo".to_string());
+        }
+        assert!(only_on_other_hosts(&state, "/users/{id}"));
+        // Paths that merely end the same way are different routes
+        assert!(!only_on_other_hosts(&state, "/x"));
+        assert!(!only_on_other_hosts(&state, "/{id}"));
+        assert!(!only_on_other_hosts(&state, "/y"));
+    }
 
     #[test]
     fn test_detect_content_type_json_object() {
@@ -305,8 +4123,46 @@ mod tests {
 
     #[tokio::test]
     async fn test_fallback_handler() {
-        let (status, body) = fallback_handler().await;
-        assert_eq!(status, StatusCode::NOT_FOUND);
-        assert_eq!(body, "Route not found");
+        let response = fallback_handler(Extension(Arc::new(AppState::default()))).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(body_string(response).await, "Route not found");
+    }
+
+    fn error_page_state() -> AppState {
+        let mut state = test_state();
+        state.error_pages.insert(404, "<h1>Custom 404</h1>".to_string());
+        state
+    }
+
+    #[tokio::test]
+    async fn test_error_page_replaces_fallback_404() {
+        let response = send(&[], error_page_state(), request("GET", "/missing")).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "text/html");
+        assert_eq!(body_string(response).await, "<h1>Custom 404</h1>");
+    }
+
+    #[tokio::test]
+    async fn test_error_page_fills_empty_magic_status_body() {
+        let response = send(
+            &["/item", "echo '@status: 404'"],
+            error_page_state(),
+            request("GET", "/item"),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(body_string(response).await, "<h1>Custom 404</h1>");
+    }
+
+    #[tokio::test]
+    async fn test_error_page_keeps_script_body_unless_always() {
+        let routes = ["/item", "echo '@status: 404'; echo 'no such item'"];
+
+        let response = send(&routes, error_page_state(), request("GET", "/item")).await;
+        assert_eq!(body_string(response).await, "no such item\n");
+
+        let state = AppState { error_page_always: true, ..error_page_state() };
+        let response = send(&routes, state, request("GET", "/item")).await;
+        assert_eq!(body_string(response).await, "<h1>Custom 404</h1>");
     }
 }
@@ -0,0 +1,345 @@
+use axum::{
+    extract::Extension,
+    http::StatusCode,
+    response::{IntoResponse, Json, Response},
+};
+use serde_json::json;
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tracing::{debug, info, warn};
+
+use crate::runner::{run, shell_command};
+use crate::shell::ShellType;
+use crate::state::AppState;
+
+/// Path the health command is served on
+pub const HEALTH_PATH: &str = "/healthz";
+
+/// A command whose exit status backs the health endpoint
+#[derive(Debug)]
+pub struct HealthCheck {
+    pub command: String,
+    /// How long a result is reused before the command runs again
+    pub ttl: Duration,
+    last: Mutex<Option<(Instant, bool)>>,
+}
+
+impl HealthCheck {
+    pub fn new(command: String, ttl: Duration) -> Self {
+        HealthCheck {
+            command,
+            ttl,
+            last: Mutex::new(None),
+        }
+    }
+
+    /// Run the health command, or reuse the last result while it is fresh
+    pub async fn check(&self, shell: &ShellType) -> bool {
+        if let Some((at, healthy)) = *self.last.lock().unwrap()
+            && at.elapsed() < self.ttl
+        {
+            return healthy;
+        }
+
+        let mut cmd = shell_command(shell, &self.command);
+        let healthy = match run(&mut cmd, &[]).await {
+            Ok(out) if out.status.success() => true,
+            Ok(out) => {
+                warn!(
+                    "Health command failed. Stderr: {}",
+                    String::from_utf8_lossy(&out.stderr)
+                );
+                false
+            }
+            Err(e) => {
+                warn!("Health command could not be started: {}", e);
+                false
+            }
+        };
+        debug!("Health command result: healthy={}", healthy);
+
+        *self.last.lock().unwrap() = Some((Instant::now(), healthy));
+        healthy
+    }
+}
+
+/// A dependency checked in the background by running `command` every `interval`
+#[derive(Debug)]
+pub struct Probe {
+    pub name: String,
+    pub command: String,
+    pub interval: Duration,
+    /// None until the first run finishes
+    healthy: Mutex<Option<bool>>,
+}
+
+impl Probe {
+    pub fn healthy(&self) -> Option<bool> {
+        *self.healthy.lock().unwrap()
+    }
+
+    /// Run the probe command every interval, for as long as the server runs
+    pub async fn run(self: Arc<Self>, shell: ShellType) {
+        let mut ticks = tokio::time::interval(self.interval);
+        ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            ticks.tick().await;
+            let mut cmd = shell_command(&shell, &self.command);
+            let healthy = match run(&mut cmd, &[]).await {
+                Ok(out) => out.status.success(),
+                Err(e) => {
+                    warn!("Probe '{}' could not be started: {}", self.name, e);
+                    false
+                }
+            };
+            let previous = self.healthy.lock().unwrap().replace(healthy);
+            if previous != Some(healthy) {
+                let state = if healthy { "healthy" } else { "unhealthy" };
+                info!("Probe '{}' is {}", self.name, state);
+            }
+        }
+    }
+}
+
+/// Parse a --probe value like "db:pg_isready:10s". The command may contain colons;
+/// the interval takes an `ms`, `s` or `m` suffix.
+pub fn parse_probe(spec: &str) -> Result<Probe, String> {
+    let invalid = || format!("expected NAME:COMMAND:INTERVAL, got '{}'", spec);
+    let (name, rest) = spec.split_once(':').ok_or_else(invalid)?;
+    let (command, interval) = rest.rsplit_once(':').ok_or_else(invalid)?;
+    if name.trim().is_empty() || command.trim().is_empty() {
+        return Err(invalid());
+    }
+    let interval = parse_interval(interval.trim())
+        .filter(|d| !d.is_zero())
+        .ok_or_else(|| format!("invalid probe interval '{}' (e.g. 500ms, 10s, 1m)", interval))?;
+    Ok(Probe {
+        name: name.trim().to_string(),
+        command: command.to_string(),
+        interval,
+        healthy: Mutex::new(None),
+    })
+}
+
+fn parse_interval(value: &str) -> Option<Duration> {
+    if let Some(ms) = value.strip_suffix("ms") {
+        return ms.parse().ok().map(Duration::from_millis);
+    }
+    if let Some(secs) = value.strip_suffix('s') {
+        return secs.parse().ok().map(Duration::from_secs);
+    }
+    if let Some(mins) = value.strip_suffix('m') {
+        return mins.parse::<u64>().ok().map(|m| Duration::from_secs(m * 60));
+    }
+    None
+}
+
+/// Overall health and a per-probe breakdown. Probes that haven't run yet count
+/// as unhealthy.
+pub fn probe_report(probes: &[Arc<Probe>]) -> (bool, serde_json::Value) {
+    let mut healthy = true;
+    let mut breakdown = serde_json::Map::new();
+    for probe in probes {
+        let status = match probe.healthy() {
+            Some(true) => "ok",
+            Some(false) => "failing",
+            None => "pending",
+        };
+        healthy &= status == "ok";
+        breakdown.insert(probe.name.clone(), json!(status));
+    }
+    (healthy, serde_json::Value::Object(breakdown))
+}
+
+/// Path the health check is served on: --health-path, or HEALTH_PATH when
+/// there is a health command or probe to report
+pub fn health_path(state: &AppState) -> Option<&str> {
+    match &state.health_path {
+        Some(path) => Some(path.as_str()),
+        None => (state.health_check.is_some() || !state.probes.is_empty()).then_some(HEALTH_PATH),
+    }
+}
+
+pub async fn health_handler(Extension(state): Extension<Arc<AppState>>) -> Response {
+    let mut healthy = match &state.health_check {
+        Some(check) => check.check(&state.shell).await,
+        None => true,
+    };
+
+    let status = |healthy| {
+        if healthy {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        }
+    };
+    if !state.probes.is_empty() {
+        let (probes_healthy, breakdown) = probe_report(&state.probes);
+        healthy &= probes_healthy;
+        let body = json!({
+            "status": if healthy { "ok" } else { "unavailable" },
+            "probes": breakdown,
+        });
+        return (status(healthy), Json(body)).into_response();
+    }
+
+    let body = if healthy { "ok" } else { "unavailable" };
+    (status(healthy), body).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::routes::{build_router, parse_routes};
+    use axum::{body::Body, http::Request};
+    use std::collections::HashMap;
+    use tower::ServiceExt;
+
+    async fn get_health(command: &str) -> StatusCode {
+        let state = AppState {
+            shell: ShellType::Sh,
+            health_check: Some(Arc::new(HealthCheck::new(
+                command.to_string(),
+                Duration::from_secs(5),
+            ))),
+            ..Default::default()
+        };
+        let request = Request::builder()
+            .uri(HEALTH_PATH)
+            .body(Body::empty())
+            .unwrap();
+        build_router(&[], Arc::new(state)).unwrap()
+            .oneshot(request)
+            .await
+            .unwrap()
+            .status()
+    }
+
+    #[tokio::test]
+    async fn test_health_command_success_is_200() {
+        assert_eq!(get_health("exit 0").await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_health_command_failure_is_503() {
+        assert_eq!(get_health("exit 1").await, StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn test_health_path_without_command() {
+        let state = Arc::new(AppState {
+            health_path: Some("/livez".to_string()),
+            ..Default::default()
+        });
+        let request = Request::builder().uri("/livez").body(Body::empty()).unwrap();
+        let response = build_router(&[], state).unwrap().oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers()["content-type"].to_str().unwrap().starts_with("text/plain"));
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"ok");
+    }
+
+    #[tokio::test]
+    async fn test_health_path_does_not_shadow_a_route() {
+        let routes = parse_routes(&["/livez".to_string(), "echo mine".to_string()]).unwrap();
+        let state = Arc::new(AppState {
+            shell: ShellType::Sh,
+            commands: HashMap::from([(routes[0].key(), routes[0].command.clone())]),
+            health_path: Some("/livez".to_string()),
+            ..Default::default()
+        });
+        let request = Request::builder().uri("/livez").body(Body::empty()).unwrap();
+        let response = build_router(&routes, state).unwrap().oneshot(request).await.unwrap();
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"mine\n");
+    }
+
+    #[test]
+    fn test_parse_probe() {
+        let probe = parse_probe("db:pg_isready -h localhost:10s").unwrap();
+        assert_eq!(probe.name, "db");
+        assert_eq!(probe.command, "pg_isready -h localhost");
+        assert_eq!(probe.interval, Duration::from_secs(10));
+
+        let probe = parse_probe("api:curl -f http://localhost:8080/ping:500ms").unwrap();
+        assert_eq!(probe.command, "curl -f http://localhost:8080/ping");
+        assert_eq!(probe.interval, Duration::from_millis(500));
+        assert_eq!(parse_probe("q:true:2m").unwrap().interval, Duration::from_secs(120));
+
+        assert!(parse_probe("db:pg_isready").is_err());
+        assert!(parse_probe(":true:10s").is_err());
+        assert!(parse_probe("db:true:10").is_err());
+        assert!(parse_probe("db:true:0s").is_err());
+    }
+
+    fn probe(name: &str, healthy: Option<bool>) -> Arc<Probe> {
+        let probe = parse_probe(&format!("{}:true:1s", name)).unwrap();
+        *probe.healthy.lock().unwrap() = healthy;
+        Arc::new(probe)
+    }
+
+    #[test]
+    fn test_probe_report() {
+        let (healthy, breakdown) = probe_report(&[probe("db", Some(true))]);
+        assert!(healthy);
+        assert_eq!(breakdown, json!({"db": "ok"}));
+
+        let probes = [probe("db", Some(true)), probe("cache", Some(false)), probe("q", None)];
+        let (healthy, breakdown) = probe_report(&probes);
+        assert!(!healthy);
+        assert_eq!(breakdown, json!({"db": "ok", "cache": "failing", "q": "pending"}));
+    }
+
+    #[tokio::test]
+    async fn test_probes_are_served_as_json() {
+        let state = Arc::new(AppState {
+            shell: ShellType::Sh,
+            probes: vec![probe("db", Some(true)), probe("cache", Some(false))],
+            ..Default::default()
+        });
+        let request = Request::builder().uri(HEALTH_PATH).body(Body::empty()).unwrap();
+        let response = build_router(&[], state).unwrap().oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body,
+            json!({"status": "unavailable", "probes": {"db": "ok", "cache": "failing"}})
+        );
+    }
+
+    #[tokio::test]
+    async fn test_probe_runs_in_background() {
+        let probe = Arc::new(parse_probe("ok:exit 0:1s").unwrap());
+        tokio::spawn(probe.clone().run(ShellType::Sh));
+        for _ in 0..100 {
+            if probe.healthy().is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(probe.healthy(), Some(true));
+    }
+
+    #[tokio::test]
+    async fn test_health_result_is_cached() {
+        let marker = std::env::temp_dir().join(format!("sherut_health_{}", std::process::id()));
+        let _ = std::fs::remove_file(&marker);
+        let check = HealthCheck::new(
+            format!("echo run >> {}", marker.display()),
+            Duration::from_secs(60),
+        );
+
+        assert!(check.check(&ShellType::Sh).await);
+        assert!(check.check(&ShellType::Sh).await);
+
+        let runs = std::fs::read_to_string(&marker).unwrap();
+        let _ = std::fs::remove_file(&marker);
+        assert_eq!(runs.lines().count(), 1);
+    }
+}
@@ -0,0 +1,74 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+use tracing::info;
+
+/// Tracks when the last request arrived, for shutting down idle servers
+#[derive(Debug)]
+pub struct IdleTracker {
+    started: Instant,
+    /// Milliseconds after `started` of the last request
+    last_request_ms: AtomicU64,
+}
+
+impl IdleTracker {
+    pub fn new() -> Self {
+        IdleTracker {
+            started: Instant::now(),
+            last_request_ms: AtomicU64::new(0),
+        }
+    }
+
+    /// Record a request arriving now
+    pub fn touch(&self) {
+        let now_ms = self.started.elapsed().as_millis() as u64;
+        self.last_request_ms.fetch_max(now_ms, Ordering::Relaxed);
+    }
+
+    /// Time since the last request (or since startup, before the first one)
+    pub fn idle_for(&self) -> Duration {
+        let last = Duration::from_millis(self.last_request_ms.load(Ordering::Relaxed));
+        self.started.elapsed().saturating_sub(last)
+    }
+
+    /// Resolve once no request has arrived for `period`
+    pub async fn wait_idle(&self, period: Duration) {
+        loop {
+            let idle = self.idle_for();
+            if idle >= period {
+                info!("No requests for {:?}, shutting down", period);
+                return;
+            }
+            tokio::time::sleep(period - idle).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_wait_idle_resolves_after_inactivity() {
+        let tracker = IdleTracker::new();
+        let waited = tokio::time::timeout(
+            Duration::from_secs(1),
+            tracker.wait_idle(Duration::from_millis(50)),
+        );
+        assert!(waited.await.is_ok());
+        assert!(tracker.idle_for() >= Duration::from_millis(50));
+    }
+
+    #[tokio::test]
+    async fn test_requests_postpone_idle_shutdown() {
+        let tracker = IdleTracker::new();
+        let period = Duration::from_millis(100);
+        let start = Instant::now();
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        tracker.touch();
+
+        tracker.wait_idle(period).await;
+        assert!(start.elapsed() >= Duration::from_millis(160));
+    }
+}
@@ -0,0 +1,81 @@
+use jaq_core::{
+    load::{Arena, File, Loader},
+    Compiler, Ctx, Filter, Native, RcIter,
+};
+use jaq_json::Val;
+
+/// A compiled jq expression applied to a route's JSON output
+pub struct JqFilter {
+    pub expression: String,
+    filter: Filter<Native<Val>>,
+}
+
+impl std::fmt::Debug for JqFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("JqFilter").field(&self.expression).finish()
+    }
+}
+
+impl JqFilter {
+    pub fn new(expression: &str) -> Result<Self, String> {
+        let loader = Loader::new(jaq_std::defs().chain(jaq_json::defs()));
+        let arena = Arena::default();
+        let program = File { code: expression, path: () };
+        let modules = loader
+            .load(&arena, program)
+            .map_err(|_| format!("cannot parse jq expression '{}'", expression))?;
+        let filter = Compiler::default()
+            .with_funs(jaq_std::funs().chain(jaq_json::funs()))
+            .compile(modules)
+            .map_err(|_| format!("cannot compile jq expression '{}'", expression))?;
+        Ok(JqFilter {
+            expression: expression.to_string(),
+            filter,
+        })
+    }
+
+    /// Run the filter over JSON `input`, returning each result as a line of JSON
+    pub fn apply(&self, input: &str) -> Result<String, String> {
+        let value: serde_json::Value =
+            serde_json::from_str(input).map_err(|e| format!("output is not JSON: {}", e))?;
+        let inputs = RcIter::new(core::iter::empty());
+        let mut output = String::new();
+        for result in self.filter.run((Ctx::new([], &inputs), Val::from(value))) {
+            let value = result.map_err(|e| format!("jq error: {}", e))?;
+            output.push_str(&value.to_string());
+            output.push('\n');
+        }
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_jq_filter_object() {
+        let filter = JqFilter::new(".data").unwrap();
+        let output = filter.apply(r#"{"data": {"id": 1}, "meta": {}}"#).unwrap();
+        assert_eq!(output, "{\"id\":1}\n");
+    }
+
+    #[test]
+    fn test_jq_filter_multiple_results() {
+        let filter = JqFilter::new(".[] | .name").unwrap();
+        let output = filter.apply(r#"[{"name": "a"}, {"name": "b"}]"#).unwrap();
+        assert_eq!(output, "\"a\"\n\"b\"\n");
+    }
+
+    #[test]
+    fn test_jq_invalid_expression() {
+        assert!(JqFilter::new(".[").is_err());
+    }
+
+    #[test]
+    fn test_jq_errors() {
+        let filter = JqFilter::new(".data").unwrap();
+        assert!(filter.apply("not json").is_err());
+        assert!(filter.apply("[1, 2]").is_err());
+    }
+}
@@ -0,0 +1,164 @@
+use axum::serve::Listener;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpListener,
+    sync::{OwnedSemaphorePermit, Semaphore},
+};
+
+/// Bind a TCP listener on `addr`. IPv6 addresses also accept IPv4 clients
+/// (as v4-mapped addresses) unless `ipv6_only` is set.
+pub fn bind_tcp(addr: SocketAddr, ipv6_only: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(ipv6_only)?;
+    }
+    // Same as tokio's own bind, so restarts don't trip over TIME_WAIT sockets
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Listener that stops accepting once `max` connections are open.
+/// Excess connections wait in the kernel backlog until one closes.
+pub struct LimitedListener<L> {
+    inner: L,
+    permits: Arc<Semaphore>,
+}
+
+impl<L> LimitedListener<L> {
+    pub fn new(inner: L, max: usize) -> Self {
+        Self {
+            inner,
+            permits: Arc::new(Semaphore::new(max)),
+        }
+    }
+}
+
+impl<L: Listener> Listener for LimitedListener<L> {
+    type Io = LimitedIo<L::Io>;
+    type Addr = L::Addr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        // The semaphore is never closed, so acquiring cannot fail
+        let permit = self.permits.clone().acquire_owned().await.unwrap();
+        let (io, addr) = self.inner.accept().await;
+        (LimitedIo { inner: io, _permit: permit }, addr)
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        self.inner.local_addr()
+    }
+}
+
+/// Connection holding a slot of its `LimitedListener` until dropped
+pub struct LimitedIo<T> {
+    inner: T,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for LimitedIo<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for LimitedIo<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{routing::get, Router};
+    use socket2::SockRef;
+    use std::time::Duration;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+        time::timeout,
+    };
+
+    async fn get_root(stream: &mut TcpStream) -> io::Result<usize> {
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await?;
+        let mut buf = [0u8; 1024];
+        stream.read(&mut buf).await
+    }
+
+    #[tokio::test]
+    async fn test_extra_connection_waits_for_a_free_slot() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = Router::new().route("/", get(|| async { "ok" }));
+        tokio::spawn(axum::serve(LimitedListener::new(listener, 1), app).into_future());
+
+        // First connection is served and kept alive, occupying the only slot
+        let mut first = TcpStream::connect(addr).await.unwrap();
+        assert!(get_root(&mut first).await.unwrap() > 0);
+
+        let mut second = TcpStream::connect(addr).await.unwrap();
+        let waiting = timeout(Duration::from_millis(200), get_root(&mut second)).await;
+        assert!(waiting.is_err(), "second connection should not be served yet");
+
+        drop(first);
+        let mut buf = [0u8; 1024];
+        let served = timeout(Duration::from_secs(2), second.read(&mut buf)).await;
+        assert!(served.unwrap().unwrap() > 0);
+    }
+
+    /// Bind `[::]:0`, or None where the platform has no IPv6
+    fn bind_v6(ipv6_only: bool) -> Option<TcpListener> {
+        bind_tcp("[::]:0".parse().unwrap(), ipv6_only).ok()
+    }
+
+    #[tokio::test]
+    async fn test_ipv6_only_sets_socket_option() {
+        let Some(listener) = bind_v6(true) else { return };
+        assert!(SockRef::from(&listener).only_v6().unwrap());
+
+        let port = listener.local_addr().unwrap().port();
+        assert!(TcpStream::connect(("127.0.0.1", port)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_ipv6_dual_stack_accepts_ipv4() {
+        let Some(listener) = bind_v6(false) else { return };
+        assert!(!SockRef::from(&listener).only_v6().unwrap());
+
+        let port = listener.local_addr().unwrap().port();
+        let client = TcpStream::connect(("127.0.0.1", port)).await;
+        assert!(client.is_ok());
+        let (_, peer) = listener.accept().await.unwrap();
+        assert!(peer.is_ipv6(), "IPv4 clients show up as v4-mapped: {}", peer);
+    }
+}
@@ -1,43 +1,133 @@
+#[cfg(all(target_os = "linux", feature = "cgroup"))]
+mod cgroup;
+mod auth;
+mod checksum;
+mod circuit;
+mod condition;
+mod cors;
+mod csrf;
+mod flush;
 mod cli;
 mod handler;
+mod health;
+mod idle;
+mod jq;
+mod listener;
+mod maintenance;
+mod metrics;
+mod nonce;
+mod rate_limit;
+mod request_id;
 mod routes;
+mod routes_file;
+mod runner;
+mod scheduler;
+mod scripts_dir;
 mod shell;
 mod state;
+mod static_response;
+#[cfg(test)]
+mod test_support;
+mod timing;
+mod tls;
+mod validate;
+mod webhook;
 
-use axum::{
-    extract::Extension,
-    routing::{any, delete, get, patch, post, put},
-    Router,
+use arc_swap::ArcSwap;
+use axum_server::tls_rustls::RustlsConfig;
+use clap::{Parser, ValueEnum};
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
 };
-use clap::Parser;
-use std::{collections::HashMap, net::SocketAddr, sync::Arc};
 use tracing::{error, info, warn, Level};
-use tracing_subscriber::FmtSubscriber;
+use tracing_subscriber::{filter::filter_fn, layer::SubscriberExt, FmtSubscriber};
 
-use cli::{Args, LogLevel};
-use handler::{fallback_handler, handler};
-use routes::parse_routes;
-use shell::{detect_default_shell, HeaderFormat};
-use state::AppState;
+use auth::{require_api_key, ApiKeys};
+use circuit::CircuitBreaker;
+use condition::parse_condition;
+use cors::cors_layer;
+use cli::{
+    parse_cookie_env, parse_error_page, parse_method_content_type, parse_shell_concurrency,
+    split_list, Args, LogFormat, LogLevel, StartupEvent,
+};
+use health::{health_path, parse_probe, HealthCheck};
+use idle::IdleTracker;
+use jq::JqFilter;
+use listener::{bind_tcp, LimitedListener};
+use maintenance::Maintenance;
+use metrics::{metrics_router, track, Metrics, METRICS_PATH};
+use nonce::NonceStore;
+use rate_limit::{parse_rate, Throttle};
+use routes::{
+    build_router, enabled_routes, limit_uri, parse_route_flags, parse_route_options,
+    parse_routes, RouteEntry,
+};
+use routes_file::{load_routes_file, reload_on_change, serve_current};
+use runner::{check_sudo, preflight, StdinEncoding};
+use scheduler::Scheduler;
+use shell::{detect_default_shell, HeaderFormat, ShellType};
+use state::{AppState, Charset, RouteConfig, StartTime};
+use static_response::StaticResponse;
+use tls::serve_tls;
+use validate::parse_validator;
+use webhook::Webhook;
 
 #[tokio::main]
 async fn main() {
+    let started = StartTime::default();
     let args = Args::parse();
 
     // 1. Initialize Logging
-    let trace_level = match args.log_level {
-        LogLevel::Error => Level::ERROR,
-        LogLevel::Warn => Level::WARN,
-        LogLevel::Info => Level::INFO,
-        LogLevel::Debug => Level::DEBUG,
-        LogLevel::Trace => Level::TRACE,
-    };
+    // Logging isn't set up yet, so errors go straight to stderr
+    let route_log_levels = parse_route_options(&args.route_log_levels).unwrap_or_else(|e| {
+        eprintln!("{}. Exiting.", e);
+        std::process::exit(1);
+    });
+    let route_log_levels: Vec<(String, String, Option<LogLevel>)> = route_log_levels
+        .into_iter()
+        .map(|(key, value)| {
+            let level = LogLevel::from_str(&value, true).ok();
+            (key, value, level)
+        })
+        .collect();
+
+    // Routes may log more verbosely than the global level; the handler gates
+    // its own events per route, everything else follows the global level
+    let global_level = args.log_level.as_level();
+    let max_level = route_log_levels
+        .iter()
+        .filter_map(|(_, _, level)| level.map(|l| l.as_level()))
+        .fold(global_level, |max, level| max.max(level));
+
+    let builder = FmtSubscriber::builder().with_max_level(max_level);
+    let filter = filter_fn(move |metadata| {
+        metadata.target() == handler::LOG_TARGET || *metadata.level() <= global_level
+    });
 
-    let subscriber = FmtSubscriber::builder()
-        .with_max_level(trace_level)
-        .finish();
+    match args.log_format {
+        LogFormat::Text => tracing::subscriber::set_global_default(builder.finish().with(filter)),
+        LogFormat::Json => {
+            tracing::subscriber::set_global_default(builder.json().finish().with(filter))
+        }
+    }
+    .expect("setting default subscriber failed");
 
-    tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
+    // Echoed scripts can carry secrets from headers, so keep them to local debugging
+    if args.debug_echo_script {
+        if global_level < Level::DEBUG {
+            error!("--debug-echo-script needs --log-level debug or trace. Exiting.");
+            std::process::exit(1);
+        }
+        if !args.host.is_loopback() {
+            error!("--debug-echo-script is only allowed on a loopback --host. Exiting.");
+            std::process::exit(1);
+        }
+        warn!("Echoing rendered scripts in the X-Debug-Script response header");
+    }
 
     // 2. Determine shell and header format
     let shell = args.shell.unwrap_or_else(detect_default_shell);
@@ -49,6 +139,20 @@ async fn main() {
         }
     });
 
+    if let Some(dir) = &args.workdir
+        && !dir.is_dir()
+    {
+        error!("Working directory '{}' does not exist. Exiting.", dir.display());
+        std::process::exit(1);
+    }
+
+    if let Some(user) = &args.sudo_user
+        && let Err(e) = check_sudo(user).await
+    {
+        error!("{}. Exiting.", e);
+        std::process::exit(1);
+    }
+
     // Warn if using assoc format with a shell that doesn't support it
     if header_format == HeaderFormat::Assoc && !shell.supports_assoc_arrays() {
         warn!(
@@ -72,56 +176,785 @@ async fn main() {
         );
     }
 
+    let cookie_format = args.cookie_format.unwrap_or_else(|| {
+        if shell.supports_assoc_arrays() {
+            HeaderFormat::Assoc
+        } else {
+            HeaderFormat::Json
+        }
+    });
+
+    if cookie_format == HeaderFormat::Assoc && !shell.supports_assoc_arrays() {
+        warn!(
+            "Shell '{}' does not support associative arrays. Consider using --cookie-format json",
+            shell.executable()
+        );
+    }
+
     info!("Using shell: {}", shell.executable());
     info!("Header format: {:?}", header_format);
     info!("Query format: {:?}", query_format);
+    info!("Cookie format: {:?}", cookie_format);
 
     // 3. Parse and Normalize Routes
-    if args.routes.is_empty() {
+    let mut raw_routes = args.routes.clone();
+    if let Some(dir) = &args.scripts_dir {
+        match scripts_dir::discover_routes(dir, &shell) {
+            Ok(discovered) => raw_routes.extend(discovered),
+            Err(e) => {
+                error!("Cannot read scripts directory '{}': {}. Exiting.", dir.display(), e);
+                std::process::exit(1);
+            }
+        }
+    }
+    // Kept apart, so --watch can combine them with the reloaded file's routes
+    let cli_routes = raw_routes.clone();
+    if let Some(path) = &args.routes_file {
+        match load_routes_file(path) {
+            Ok(file_routes) => raw_routes.extend(file_routes),
+            Err(e) => {
+                error!("Cannot load routes file '{}': {}. Exiting.", path.display(), e);
+                std::process::exit(1);
+            }
+        }
+    }
+    if raw_routes.is_empty() {
         warn!("No routes defined via CLI.");
     }
 
-    let routes = parse_routes(&args.routes);
+    let route_conditions = or_exit(parse_route_options(&args.route_enabled_if));
+    let routes = or_exit(parse_routes(&raw_routes));
+    let all_route_keys: HashSet<String> = routes.iter().map(|route| route.key()).collect();
+    for (key, _) in &route_conditions {
+        if !all_route_keys.contains(key) {
+            warn!("Options set for unknown route '{}'", key);
+        }
+    }
+    let routes = apply_conditions(routes, &route_conditions);
+
+    let mut route_configs: HashMap<String, RouteConfig> = HashMap::new();
+    for (key, value, level) in route_log_levels {
+        let Some(level) = level else {
+            error!("Invalid log level '{}' for route '{}'. Exiting.", value, key);
+            std::process::exit(1);
+        };
+        route_configs.entry(key).or_default().log_level = Some(level);
+    }
+
+    for (key, value) in or_exit(parse_route_options(&args.route_consumes)) {
+        route_configs.entry(key).or_default().consumes = Some(split_list(&value));
+    }
+
+    for (key, value) in or_exit(parse_route_options(&args.route_created_locations)) {
+        route_configs.entry(key).or_default().created_location = Some(value);
+    }
+
+    for (key, path) in or_exit(parse_route_options(&args.route_wrap_html)) {
+        match std::fs::read_to_string(&path) {
+            Ok(template) => route_configs.entry(key).or_default().wrap_html = Some(template),
+            Err(e) => {
+                error!("Cannot read HTML template '{}': {}. Exiting.", path, e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    for (key, value) in or_exit(parse_route_options(&args.route_stdin_encodings)) {
+        let Ok(encoding) = StdinEncoding::from_str(&value, true) else {
+            error!("Invalid stdin encoding '{}' for route '{}'. Exiting.", value, key);
+            std::process::exit(1);
+        };
+        route_configs.entry(key).or_default().stdin_encoding = encoding;
+    }
+
+    for (key, label) in or_exit(parse_route_options(&args.route_charsets)) {
+        let Some(encoding) = encoding_rs::Encoding::for_label(label.trim().as_bytes()) else {
+            error!("Unknown charset '{}' for route '{}'. Exiting.", label, key);
+            std::process::exit(1);
+        };
+        let charset = Charset { label: label.trim().to_lowercase(), encoding };
+        route_configs.entry(key).or_default().charset = Some(charset);
+    }
+
+    for key in or_exit(parse_route_flags(&args.route_split_lines_to_json)) {
+        let config = route_configs.entry(key).or_default();
+        config.split_delimiter.get_or_insert_with(|| "\n".to_string());
+    }
+
+    for (key, delimiter) in or_exit(parse_route_options(&args.route_split_delimiters)) {
+        if delimiter.is_empty() {
+            error!("Empty split delimiter for route '{}'. Exiting.", key);
+            std::process::exit(1);
+        }
+        route_configs.entry(key).or_default().split_delimiter = Some(delimiter);
+    }
+
+    for key in or_exit(parse_route_flags(&args.route_require_body)) {
+        route_configs.entry(key).or_default().require_body = true;
+    }
+
+    for key in or_exit(parse_route_flags(&args.route_forbid_body)) {
+        route_configs.entry(key).or_default().forbid_body = true;
+    }
+
+    for (key, spec) in or_exit(parse_route_options(&args.route_response_headers)) {
+        let header = spec
+            .split_once(':')
+            .map(|(name, template)| (name.trim(), template.trim()))
+            .filter(|(name, _)| axum::http::HeaderName::try_from(*name).is_ok());
+        let Some((name, template)) = header else {
+            error!("Invalid response header '{}' for route '{}'. Exiting.", spec, key);
+            std::process::exit(1);
+        };
+        route_configs
+            .entry(key)
+            .or_default()
+            .response_headers
+            .push((name.to_string(), template.to_string()));
+    }
+
+    for key in or_exit(parse_route_flags(&args.route_as_sse)) {
+        route_configs.entry(key).or_default().as_sse = true;
+    }
+
+    for (key, expression) in or_exit(parse_route_options(&args.route_jq)) {
+        match JqFilter::new(&expression) {
+            Ok(filter) => route_configs.entry(key).or_default().jq = Some(Arc::new(filter)),
+            Err(e) => {
+                error!("Invalid --route-jq for route '{}': {}. Exiting.", key, e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    for (key, value) in or_exit(parse_route_options(&args.route_max_body_sizes)) {
+        match value.parse::<usize>() {
+            Ok(limit) => route_configs.entry(key).or_default().max_body_size = Some(limit),
+            Err(_) => {
+                error!("Invalid body size '{}' for route '{}'. Exiting.", value, key);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    for key in or_exit(parse_route_flags(&args.route_rpc)) {
+        route_configs.entry(key).or_default().rpc = true;
+    }
+    let mut rpc_commands = HashMap::new();
+    for spec in &args.rpc_method_commands {
+        match spec.split_once('=') {
+            Some((method, command)) if !method.is_empty() => {
+                rpc_commands.insert(method.to_string(), command.to_string());
+            }
+            _ => {
+                error!("Invalid --rpc-method-command '{}' (want METHOD=COMMAND). Exiting.", spec);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    for key in or_exit(parse_route_flags(&args.route_deprecated)) {
+        route_configs.entry(key).or_default().deprecated = true;
+    }
+
+    // A sunset date implies deprecation
+    for (key, date) in or_exit(parse_route_options(&args.route_sunsets)) {
+        let Ok(sunset) = axum::http::HeaderValue::from_str(&date) else {
+            error!("Invalid sunset date '{}' for route '{}'. Exiting.", date, key);
+            std::process::exit(1);
+        };
+        let config = route_configs.entry(key).or_default();
+        config.deprecated = true;
+        config.sunset = Some(sunset);
+    }
+
+    for (key, spec) in or_exit(parse_route_options(&args.route_success_codes)) {
+        let codes: Result<Vec<i32>, _> = split_list(&spec).iter().map(|c| c.parse()).collect();
+        match codes {
+            Ok(codes) if !codes.is_empty() => {
+                route_configs.entry(key).or_default().success_codes = Some(codes)
+            }
+            _ => {
+                error!("Invalid success codes '{}' for route '{}'. Exiting.", spec, key);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    for (key, spec) in or_exit(parse_route_options(&args.route_when)) {
+        match parse_condition(&spec) {
+            Ok(condition) => route_configs.entry(key).or_default().when = Some(condition),
+            Err(e) => {
+                error!("Invalid condition for route '{}': {}. Exiting.", key, e);
+                std::process::exit(1);
+            }
+        }
+    }
+    for (key, command) in or_exit(parse_route_options(&args.route_otherwise)) {
+        route_configs.entry(key).or_default().otherwise = Some(command);
+    }
+
+    for (key, spec) in or_exit(parse_route_options(&args.route_rate_limits)) {
+        match parse_rate(&spec) {
+            Ok(rate) => route_configs.entry(key).or_default().rate_limit = Some(rate),
+            Err(e) => {
+                error!("Invalid rate limit for route '{}': {}. Exiting.", key, e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    for (key, dir) in or_exit(parse_route_options(&args.route_workdirs)) {
+        let dir = match &args.workdir {
+            Some(base) => base.join(dir),
+            None => PathBuf::from(dir),
+        };
+        if !dir.is_dir() {
+            let dir = dir.display();
+            error!("Working directory '{}' for route '{}' does not exist. Exiting.", dir, key);
+            std::process::exit(1);
+        }
+        route_configs.entry(key).or_default().workdir = Some(dir);
+    }
+
+    for key in route_configs.keys() {
+        if !all_route_keys.contains(key) {
+            warn!("Options set for unknown route '{}'", key);
+        }
+    }
+
+    let mut static_responses: HashMap<String, StaticResponse> = HashMap::new();
+    for (key, body) in or_exit(parse_route_options(&args.static_responses)) {
+        if !key.split_once(' ').is_some_and(|(_, path)| path.starts_with('/')) {
+            error!("Static response for '{}' can't be limited to a host. Exiting.", key);
+            std::process::exit(1);
+        }
+        info!("Registered static response: {}", key);
+        static_responses.entry(key).or_default().body = body;
+    }
+    for (key, status) in or_exit(parse_route_options(&args.static_statuses)) {
+        let Some(response) = static_responses.get_mut(&key) else {
+            error!("--static-status for '{}' without --static-response. Exiting.", key);
+            std::process::exit(1);
+        };
+        match status.parse().ok().and_then(|code| axum::http::StatusCode::from_u16(code).ok()) {
+            Some(status) => response.status = status,
+            None => {
+                error!("Invalid status '{}' for route '{}'. Exiting.", status, key);
+                std::process::exit(1);
+            }
+        }
+    }
+    for (key, content_type) in or_exit(parse_route_options(&args.static_content_types)) {
+        let Some(response) = static_responses.get_mut(&key) else {
+            error!("--static-content-type for '{}' without --static-response. Exiting.", key);
+            std::process::exit(1);
+        };
+        match axum::http::HeaderValue::from_str(&content_type) {
+            Ok(value) => response.content_type = value,
+            Err(_) => {
+                error!("Invalid content type '{}' for route '{}'. Exiting.", content_type, key);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // A `*` entry turns the Host check off
+    let allowed_hosts: Vec<String> = if args.allowed_hosts.iter().any(|host| host.trim() == "*") {
+        Vec::new()
+    } else {
+        args.allowed_hosts.iter().map(|host| host.trim().to_lowercase()).collect()
+    };
+    if !allowed_hosts.is_empty() {
+        info!("Allowed hosts: {}", allowed_hosts.join(", "));
+    }
+
+    let webhook = match &args.webhook_url {
+        Some(url) => match Webhook::new(url, args.webhook_on) {
+            Ok(webhook) => Some(webhook),
+            Err(e) => {
+                error!("Cannot use --webhook-url: {}. Exiting.", e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    let mut error_pages = HashMap::new();
+    for spec in &args.error_pages {
+        let page = parse_error_page(spec).and_then(|(status, path)| {
+            std::fs::read_to_string(&path)
+                .map(|html| (status, html))
+                .map_err(|e| format!("cannot read '{}': {}", path, e))
+        });
+        match page {
+            Ok((status, html)) => {
+                error_pages.insert(status, html);
+            }
+            Err(e) => {
+                error!("Invalid --error-page '{}': {}. Exiting.", spec, e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let mut probes = Vec::new();
+    for spec in &args.probes {
+        match parse_probe(spec) {
+            Ok(probe) => probes.push(Arc::new(probe)),
+            Err(e) => {
+                error!("Invalid --probe: {}. Exiting.", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let mut validators = Vec::new();
+    for spec in &args.validators {
+        match parse_validator(spec) {
+            Ok(validator) => validators.push(validator),
+            Err(e) => {
+                error!("Invalid --validate: {}. Exiting.", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let mut shell_concurrency = HashMap::new();
+    for spec in &args.shell_concurrency {
+        match parse_shell_concurrency(spec) {
+            Ok((shell, max)) => {
+                shell_concurrency.insert(shell, Arc::new(Scheduler::new(max, args.scheduling)));
+            }
+            Err(e) => {
+                error!("Invalid --shell-concurrency: {}. Exiting.", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let mut cookie_env = Vec::new();
+    for spec in &args.cookie_env {
+        match parse_cookie_env(spec) {
+            Ok(mapping) => cookie_env.push(mapping),
+            Err(e) => {
+                error!("Invalid --cookie-env: {}. Exiting.", e);
+                std::process::exit(1);
+            }
+        }
+    }
 
-    // Build command map with method+path as key
-    let mut command_map = HashMap::new();
-    for route in &routes {
-        let key = format!("{} {}", route.method, route.path);
-        command_map.insert(key, route.command.clone());
+    let mut method_content_types = HashMap::new();
+    for spec in &args.default_content_type_by_method {
+        match parse_method_content_type(spec) {
+            Ok((method, content_type)) => {
+                method_content_types.insert(method, content_type);
+            }
+            Err(e) => {
+                error!("Invalid --default-content-type-by-method: {}. Exiting.", e);
+                std::process::exit(1);
+            }
+        }
     }
 
+    #[cfg(all(target_os = "linux", feature = "cgroup"))]
+    let cgroup_procs = match args.cgroup.as_deref().map(cgroup::procs_path) {
+        Some(Ok(path)) if !path.exists() => {
+            error!("cgroup '{}' does not exist. Exiting.", path.display());
+            std::process::exit(1);
+        }
+        Some(Err(e)) => {
+            error!("{}. Exiting.", e);
+            std::process::exit(1);
+        }
+        procs => procs.and_then(Result::ok),
+    };
+
     let shared_state = Arc::new(AppState {
-        commands: command_map,
+        commands: route_commands(&routes),
+        route_configs,
+        log_level: args.log_level,
         shell,
         header_format,
         query_format,
+        cookie_format,
+        param_mode: args.param_mode,
+        workdir: args.workdir,
+        allowed_request_shells: args.allow_shell_header,
+        debug_echo_script: args.debug_echo_script,
+        cookie_env,
+        tls: args.tls_cert.is_some(),
+        trust_proxy: args.trust_proxy,
+        body_format: args.body_format,
+        max_body_env: Some(args.max_body_env),
+        auto_options: args.auto_options,
+        strip_hop_by_hop: args.strip_hop_by_hop,
+        validators,
+        rpc_commands,
+        error_pages,
+        error_page_always: args.error_page_always,
+        allowed_content_types: args.allowed_content_types,
+        allowed_response_headers: args.allowed_response_headers,
+        blocked_response_headers: args.blocked_response_headers,
+        method_content_types,
+        no_escape: args.no_escape,
+        allowed_hosts,
+        max_uri_length: args.max_uri_length,
+        max_query_params: args.max_query_params,
+        query_overflow: args.on_too_many_query_params,
+        max_response_headers: args.max_response_headers,
+        max_body_size: (args.max_body_size > 0).then_some(args.max_body_size),
+        #[cfg(all(target_os = "linux", feature = "cgroup"))]
+        cgroup_procs,
+        request_filter: args.request_filter,
+        sudo_user: args.sudo_user,
+        command_timeout: args.command_timeout_ms.map(Duration::from_millis),
+        concurrency: args
+            .max_concurrency
+            .map(|max| Arc::new(Scheduler::new(max as usize, args.scheduling))),
+        shell_concurrency,
+        queue_timeout: args.queue_timeout_ms.map(Duration::from_millis),
+        on_overload: args.on_overload,
+        success_signals: args.success_signals,
+        empty_status: args
+            .empty_status
+            .map(|code| axum::http::StatusCode::from_u16(code).unwrap()),
+        treat_whitespace_as_empty: args.treat_whitespace_as_empty,
+        status_from_exit: args.status_from_exit,
+        verify_checksum: args.verify_checksum,
+        warn_empty_response: args.warn_empty_response,
+        server_timing: args.server_timing,
+        csrf_cookie: args.csrf.then_some(args.csrf_cookie),
+        nonces: args.nonce_header.map(|header| {
+            Arc::new(NonceStore::new(header, Duration::from_secs(args.nonce_window_secs)))
+        }),
+        circuit_breaker: args.circuit_breaker.map(|threshold| {
+            Arc::new(CircuitBreaker::new(
+                threshold,
+                Duration::from_secs(args.circuit_cooldown_secs),
+            ))
+        }),
+        rate_limiter: Default::default(),
+        static_responses,
+        throttle: Throttle {
+            // Checked by clap to be in 100..=599
+            status: axum::http::StatusCode::from_u16(args.rate_limit_status).unwrap(),
+            body: args.rate_limit_body,
+            command: args.rate_limit_command,
+        },
+        maintenance: Arc::new(Maintenance::new(args.maintenance, args.maintenance_body)),
+        webhook,
+        health_check: args.health_command.map(|command| {
+            Arc::new(HealthCheck::new(
+                command,
+                Duration::from_millis(args.health_cache_ms),
+            ))
+        }),
+        health_path: args.health_path,
+        probes,
+        started,
+        idle: args.idle_shutdown_secs.map(|_| Arc::new(IdleTracker::new())),
     });
 
     // 4. Build Router
-    let mut app: Router = Router::new();
-
-    for route in &routes {
-        app = match route.method.as_str() {
-            "GET" => app.route(&route.path, get(handler)),
-            "POST" => app.route(&route.path, post(handler)),
-            "PUT" => app.route(&route.path, put(handler)),
-            "DELETE" => app.route(&route.path, delete(handler)),
-            "PATCH" => app.route(&route.path, patch(handler)),
-            _ => app.route(&route.path, any(handler)),
+    let mut app = match args.routes_file.clone().filter(|_| args.watch) {
+        Some(path) => {
+            let router = or_exit(build_router(&routes, shared_state.clone()));
+            let current = Arc::new(ArcSwap::from_pointee(router));
+            let base_state = shared_state.clone();
+            let rebuild = move |file_routes: Vec<String>| {
+                let mut raw_routes = cli_routes.clone();
+                raw_routes.extend(file_routes);
+                let routes = apply_conditions(parse_routes(&raw_routes)?, &route_conditions);
+                let mut state = AppState::clone(&base_state);
+                state.commands = route_commands(&routes);
+                build_router(&routes, Arc::new(state))
+            };
+            info!("Watching '{}' for route changes", path.display());
+            tokio::spawn(reload_on_change(path, current.clone(), rebuild));
+            serve_current(current)
+        }
+        None => or_exit(build_router(&routes, shared_state.clone())),
+    };
+    if !args.api_keys.is_empty() {
+        let keys = ApiKeys {
+            header: args.api_key_header.clone(),
+            keys: args.api_keys.clone(),
+            exempt: health_path(&shared_state).map(str::to_string).into_iter().collect(),
         };
+        app = app.layer(axum::middleware::from_fn_with_state(Arc::new(keys), require_api_key));
     }
-
-    // Attach state as an Extension layer
-    let app = app
-        .layer(Extension(shared_state))
-        .fallback(fallback_handler);
+    let metrics = args.metrics_port.map(|_| Arc::new(Metrics::default()));
+    if let Some(metrics) = &metrics {
+        app = app.layer(axum::middleware::from_fn_with_state(metrics.clone(), track));
+    }
+    // Outside the router, so preflights are answered even for GET-only routes
+    if !args.cors_origins.is_empty() {
+        match cors_layer(&args.cors_origins, &args.cors_methods) {
+            Ok(cors) => app = app.layer(cors),
+            Err(e) => {
+                error!("Invalid CORS configuration: {}. Exiting.", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    let app = limit_uri(app, shared_state.max_uri_length);
+    for probe in &shared_state.probes {
+        tokio::spawn(probe.clone().run(shared_state.shell.clone()));
+    }
+    if shared_state.maintenance.is_on() {
+        warn!("Starting in maintenance mode: command routes answer 503");
+    }
+    #[cfg(unix)]
+    tokio::spawn(maintenance::toggle_on_sigusr1(shared_state.maintenance.clone()));
 
     // 5. Start Server
-    let addr = SocketAddr::from(([0, 0, 0, 0], args.port));
-    info!("🚀 Server running on http://{}", addr);
+    let addr = SocketAddr::new(args.host, args.port);
+    if args.host.is_unspecified() && args.api_keys.is_empty() {
+        warn!(
+            "Listening on all interfaces ({}) without --api-key, so anyone who can reach \
+             this port can run its commands",
+            args.host
+        );
+    }
+    let preflight_command = args.preflight.as_deref();
 
-    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
-    if let Err(e) = axum::serve(listener, app).await {
+    let listener = match bind(&shared_state.shell, preflight_command, addr, args.ipv6_only).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("{}. Exiting.", e);
+            std::process::exit(1);
+        }
+    };
+    let tls_config = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert), Some(key)) => match RustlsConfig::from_pem_file(cert, key).await {
+            Ok(config) => Some(config),
+            Err(e) => {
+                error!("Cannot load TLS certificate or key: {}. Exiting.", e);
+                std::process::exit(1);
+            }
+        },
+        _ => None,
+    };
+    // With --port 0 the OS picks the port, so report what was actually bound
+    match report_bound_addr(&listener, args.port_file.as_deref()) {
+        Ok(bound) => {
+            let scheme = if tls_config.is_some() { "https" } else { "http" };
+            info!("🚀 Server running on {}://{}", scheme, bound);
+            if let Some(StartupEvent::Json) = args.startup_event {
+                println!("{}", startup_event_json(bound, routes.len()));
+            }
+        }
+        Err(e) => {
+            error!("Cannot report bound port: {}. Exiting.", e);
+            std::process::exit(1);
+        }
+    }
+    if let Some((metrics, port)) = metrics.zip(args.metrics_port) {
+        let addr = SocketAddr::new(args.host, port);
+        let listener = match bind_tcp(addr, args.ipv6_only) {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!("Cannot bind metrics port {}: {}. Exiting.", addr, e);
+                std::process::exit(1);
+            }
+        };
+        if let Ok(bound) = listener.local_addr() {
+            info!("📈 Metrics on http://{}{}", bound, METRICS_PATH);
+        }
+        tokio::spawn(axum::serve(listener, metrics_router(metrics)).into_future());
+    }
+    let idle_shutdown = shared_state
+        .idle
+        .clone()
+        .zip(args.idle_shutdown_secs.map(Duration::from_secs));
+    let served = match (tls_config, args.max_connections) {
+        (Some(config), _) => serve_tls(listener, config, app, shutdown_signal(idle_shutdown)).await,
+        (None, Some(max)) => {
+            axum::serve(LimitedListener::new(listener, max as usize), app)
+                .with_graceful_shutdown(shutdown_signal(idle_shutdown))
+                .await
+        }
+        (None, None) => {
+            axum::serve(listener, app)
+                .with_graceful_shutdown(shutdown_signal(idle_shutdown))
+                .await
+        }
+    };
+    if let Err(e) = served {
         error!("Server failed to start: {}", e);
     }
-}
\ No newline at end of file
+}
+
+/// Resolve when the server should stop accepting requests and drain in-flight ones:
+/// on SIGINT/SIGTERM, or after the idle window passes without requests
+async fn shutdown_signal(idle: Option<(Arc<IdleTracker>, Duration)>) {
+    let idle = async {
+        match idle {
+            Some((tracker, period)) => tracker.wait_idle(period).await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::select! {
+        _ = idle => {}
+        _ = termination_signal() => info!("Shutting down gracefully"),
+    }
+}
+
+/// Resolve on Ctrl-C, or SIGTERM on Unix
+async fn termination_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("Cannot listen for Ctrl-C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(e) => {
+                error!("Cannot listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
+
+/// Run the preflight command, if any, and only bind `addr` once it succeeds
+async fn bind(
+    shell: &ShellType,
+    preflight_command: Option<&str>,
+    addr: SocketAddr,
+    ipv6_only: bool,
+) -> Result<tokio::net::TcpListener, String> {
+    if let Some(command) = preflight_command {
+        info!("Running preflight command");
+        preflight(shell, command)
+            .await
+            .map_err(|e| format!("Preflight command failed: {}", e))?;
+    }
+    bind_tcp(addr, ipv6_only).map_err(|e| format!("Cannot bind {}: {}", addr, e))
+}
+
+/// The address `listener` is bound to, also written as a bare port number to `port_file`
+fn report_bound_addr(
+    listener: &tokio::net::TcpListener,
+    port_file: Option<&Path>,
+) -> std::io::Result<SocketAddr> {
+    let bound = listener.local_addr()?;
+    if let Some(path) = port_file {
+        std::fs::write(path, format!("{}\n", bound.port()))?;
+    }
+    Ok(bound)
+}
+
+/// Unwrap a startup result, exiting with its error otherwise
+fn or_exit<T>(result: Result<T, String>) -> T {
+    result.unwrap_or_else(|e| {
+        error!("{}. Exiting.", e);
+        std::process::exit(1);
+    })
+}
+
+/// Set the routes' --route-enabled-if conditions and drop the disabled routes
+fn apply_conditions(
+    mut routes: Vec<RouteEntry>,
+    conditions: &[(String, String)],
+) -> Vec<RouteEntry> {
+    for (key, condition) in conditions {
+        for route in routes.iter_mut().filter(|route| &route.key() == key) {
+            route.enabled_if = Some(condition.clone());
+        }
+    }
+    enabled_routes(routes, |var| std::env::var(var).ok())
+}
+
+/// Commands by route key ("METHOD [host]/path")
+fn route_commands(routes: &[RouteEntry]) -> HashMap<String, String> {
+    routes.iter().map(|route| (route.key(), route.command.clone())).collect()
+}
+
+/// Readiness event announcing the bound address and number of routes
+fn startup_event_json(addr: SocketAddr, routes: usize) -> String {
+    serde_json::json!({
+        "event": "listening",
+        "addr": addr.to_string(),
+        "routes": routes,
+    })
+    .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A local address that is currently free
+    fn free_addr() -> SocketAddr {
+        std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_bind_after_successful_preflight() {
+        let listener = bind(&ShellType::Sh, Some("true"), free_addr(), false).await;
+        assert!(listener.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_failed_preflight_prevents_binding() {
+        let addr = free_addr();
+        let result = bind(&ShellType::Sh, Some("exit 1"), addr, false).await;
+        assert!(result.unwrap_err().starts_with("Preflight command failed"));
+        // The port was never taken
+        assert!(std::net::TcpListener::bind(addr).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_port_zero_reports_assigned_port() {
+        let addr = SocketAddr::from(([127, 0, 0, 1], 0));
+        let listener = bind(&ShellType::Sh, None, addr, false).await.unwrap();
+        let port_file = std::env::temp_dir().join(format!("sherut-port-{}", std::process::id()));
+
+        let bound = report_bound_addr(&listener, Some(&port_file)).unwrap();
+        let written = std::fs::read_to_string(&port_file).unwrap();
+        std::fs::remove_file(&port_file).unwrap();
+
+        assert_ne!(bound.port(), 0);
+        assert_eq!(written.trim(), bound.port().to_string());
+    }
+
+    #[tokio::test]
+    async fn test_idle_server_shuts_down() {
+        let state = Arc::new(AppState {
+            idle: Some(Arc::new(IdleTracker::new())),
+            ..Default::default()
+        });
+        let app = build_router(&[], state.clone()).unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let idle = state.idle.clone().map(|t| (t, Duration::from_millis(100)));
+
+        let served = axum::serve(listener, app).with_graceful_shutdown(shutdown_signal(idle));
+        let stopped = tokio::time::timeout(Duration::from_secs(2), served.into_future()).await;
+        assert!(stopped.is_ok(), "server should stop after the idle window");
+    }
+
+    #[test]
+    fn test_startup_event_json() {
+        let addr = SocketAddr::from(([0, 0, 0, 0], 8080));
+        assert_eq!(
+            startup_event_json(addr, 3),
+            r#"{"addr":"0.0.0.0:8080","event":"listening","routes":3}"#
+        );
+    }
+}
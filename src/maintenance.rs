@@ -0,0 +1,71 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use tracing::{error, warn};
+
+/// Switch that makes command routes answer 503 with `body`, e.g. during a
+/// planned maintenance window. The health check keeps answering.
+#[derive(Debug)]
+pub struct Maintenance {
+    on: AtomicBool,
+    pub body: String,
+}
+
+impl Default for Maintenance {
+    fn default() -> Self {
+        Maintenance::new(false, "Down for maintenance".to_string())
+    }
+}
+
+impl Maintenance {
+    pub fn new(on: bool, body: String) -> Self {
+        Maintenance {
+            on: AtomicBool::new(on),
+            body,
+        }
+    }
+
+    pub fn is_on(&self) -> bool {
+        self.on.load(Ordering::Relaxed)
+    }
+
+    /// Flip the switch, returning whether maintenance mode is now on
+    pub fn toggle(&self) -> bool {
+        !self.on.fetch_xor(true, Ordering::Relaxed)
+    }
+}
+
+/// Toggle maintenance mode on every SIGUSR1
+#[cfg(unix)]
+pub async fn toggle_on_sigusr1(maintenance: Arc<Maintenance>) {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut sigusr1 = match signal(SignalKind::user_defined1()) {
+        Ok(sigusr1) => sigusr1,
+        Err(e) => {
+            error!("Cannot listen for SIGUSR1: {}", e);
+            return;
+        }
+    };
+    while sigusr1.recv().await.is_some() {
+        match maintenance.toggle() {
+            true => warn!("Maintenance mode on: command routes answer 503"),
+            false => warn!("Maintenance mode off"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_toggle() {
+        let maintenance = Maintenance::default();
+        assert!(!maintenance.is_on());
+        assert!(maintenance.toggle());
+        assert!(maintenance.is_on());
+        assert!(!maintenance.toggle());
+        assert!(!maintenance.is_on());
+    }
+}
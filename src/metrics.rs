@@ -0,0 +1,156 @@
+use axum::{
+    extract::{MatchedPath, Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+
+/// Path the metrics listener answers on
+pub const METRICS_PATH: &str = "/metrics";
+
+/// Method, route pattern and status a request is counted under
+type Key = (String, String, u16);
+
+/// Request counters kept for the metrics endpoint
+#[derive(Debug, Default)]
+pub struct Metrics {
+    in_flight: AtomicU64,
+    /// Count and total seconds per (method, route, status)
+    requests: Mutex<BTreeMap<Key, (u64, f64)>>,
+}
+
+impl Metrics {
+    fn record(&self, method: &str, route: &str, status: u16, seconds: f64) {
+        let mut requests = self.requests.lock().unwrap();
+        let key = (method.to_string(), route.to_string(), status);
+        let entry = requests.entry(key).or_default();
+        entry.0 += 1;
+        entry.1 += seconds;
+    }
+
+    /// The counters in Prometheus text format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        out.push_str("# HELP sherut_requests_in_flight Requests currently being handled\n");
+        out.push_str("# TYPE sherut_requests_in_flight gauge\n");
+        let in_flight = self.in_flight.load(Ordering::Relaxed);
+        let _ = writeln!(out, "sherut_requests_in_flight {}", in_flight);
+
+        let requests = self.requests.lock().unwrap();
+        out.push_str("# HELP sherut_requests_total Requests handled\n");
+        out.push_str("# TYPE sherut_requests_total counter\n");
+        for ((method, route, status), (count, _)) in requests.iter() {
+            let labels = labels(method, route, *status);
+            let _ = writeln!(out, "sherut_requests_total{{{}}} {}", labels, count);
+        }
+        out.push_str("# HELP sherut_request_duration_seconds_sum Time spent handling requests\n");
+        out.push_str("# TYPE sherut_request_duration_seconds_sum counter\n");
+        for ((method, route, status), (_, seconds)) in requests.iter() {
+            let labels = labels(method, route, *status);
+            let _ = writeln!(out, "sherut_request_duration_seconds_sum{{{}}} {}", labels, seconds);
+        }
+        out
+    }
+}
+
+fn labels(method: &str, route: &str, status: u16) -> String {
+    let route = route.replace('\\', "\\\\").replace('"', "\\\"");
+    format!("method=\"{}\",route=\"{}\",status=\"{}\"", method, route, status)
+}
+
+/// Middleware counting requests by method, route pattern and status
+pub async fn track(State(metrics): State<Arc<Metrics>>, request: Request, next: Next) -> Response {
+    let method = request.method().to_string();
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| "unmatched".to_string());
+    let started = Instant::now();
+    metrics.in_flight.fetch_add(1, Ordering::Relaxed);
+    let response = next.run(request).await;
+    metrics.in_flight.fetch_sub(1, Ordering::Relaxed);
+    let seconds = started.elapsed().as_secs_f64();
+    metrics.record(&method, &route, response.status().as_u16(), seconds);
+    response
+}
+
+/// Router serving only the metrics endpoint, for its own listener
+pub fn metrics_router(metrics: Arc<Metrics>) -> Router {
+    Router::new().route(
+        METRICS_PATH,
+        get(move || async move {
+            (
+                [("content-type", "text/plain; version=0.0.4")],
+                metrics.render(),
+            )
+                .into_response()
+        }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::middleware;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    async fn get(addr: std::net::SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+            path
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn test_metrics_port_is_separate_from_routes() {
+        let metrics = Arc::new(Metrics::default());
+        let app = Router::new()
+            .route("/users/{id}", axum::routing::get(|| async { "user" }))
+            .layer(middleware::from_fn_with_state(metrics.clone(), track));
+
+        let app_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let app_addr = app_listener.local_addr().unwrap();
+        let metrics_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let metrics_addr = metrics_listener.local_addr().unwrap();
+        tokio::spawn(axum::serve(app_listener, app).into_future());
+        tokio::spawn(axum::serve(metrics_listener, metrics_router(metrics)).into_future());
+
+        assert!(get(app_addr, "/users/1").await.starts_with("HTTP/1.1 200"));
+        assert!(get(app_addr, "/metrics").await.starts_with("HTTP/1.1 404"));
+        assert!(get(metrics_addr, "/users/1").await.starts_with("HTTP/1.1 404"));
+
+        let exposed = get(metrics_addr, "/metrics").await;
+        assert!(exposed.starts_with("HTTP/1.1 200"));
+        assert!(exposed.contains(
+            "sherut_requests_total{method=\"GET\",route=\"/users/{id}\",status=\"200\"} 1"
+        ));
+        assert!(exposed.contains("sherut_requests_in_flight 0"));
+    }
+
+    #[test]
+    fn test_labels_are_escaped() {
+        assert_eq!(
+            labels("GET", "/a\"b", 200),
+            "method=\"GET\",route=\"/a\\\"b\",status=\"200\""
+        );
+    }
+}
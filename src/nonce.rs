@@ -0,0 +1,120 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Most nonces remembered at once; new ones are refused while the window is full,
+/// as forgetting a nonce early would let it be replayed
+const CAPACITY: usize = 10_000;
+
+/// Outcome of checking a request's nonce
+#[derive(Debug, PartialEq)]
+pub enum NonceCheck {
+    Fresh,
+    /// Already seen within the window
+    Replayed,
+    /// CAPACITY nonces are within the window, so no other can be remembered
+    Full,
+}
+
+/// Remembers recently seen request nonces to reject replayed requests
+#[derive(Debug)]
+pub struct NonceStore {
+    pub header: String,
+    pub window: Duration,
+    seen: Mutex<Seen>,
+}
+
+#[derive(Debug, Default)]
+struct Seen {
+    /// Nonce -> when it was first seen
+    times: HashMap<String, Instant>,
+    /// Nonces in the order they were seen, oldest first
+    order: VecDeque<String>,
+}
+
+impl NonceStore {
+    pub fn new(header: String, window: Duration) -> Self {
+        NonceStore {
+            header: header.to_lowercase(),
+            window,
+            seen: Mutex::new(Seen::default()),
+        }
+    }
+
+    /// Record `nonce` unless it was already seen within the window or the store is full
+    pub fn check(&self, nonce: &str) -> NonceCheck {
+        self.check_at(nonce, Instant::now(), CAPACITY)
+    }
+
+    fn check_at(&self, nonce: &str, now: Instant, capacity: usize) -> NonceCheck {
+        let mut seen = self.seen.lock().unwrap();
+
+        // Forget nonces that left the window
+        while let Some(oldest) = seen.order.front() {
+            let expired = seen
+                .times
+                .get(oldest)
+                .is_none_or(|at| now.duration_since(*at) >= self.window);
+            if !expired {
+                break;
+            }
+            let oldest = seen.order.pop_front().unwrap();
+            seen.times.remove(&oldest);
+        }
+
+        if seen.times.contains_key(nonce) {
+            return NonceCheck::Replayed;
+        }
+        if seen.order.len() >= capacity {
+            return NonceCheck::Full;
+        }
+        seen.times.insert(nonce.to_string(), now);
+        seen.order.push_back(nonce.to_string());
+        NonceCheck::Fresh
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store() -> NonceStore {
+        NonceStore::new("X-Nonce".to_string(), Duration::from_secs(60))
+    }
+
+    #[test]
+    fn test_replayed_nonce_is_rejected() {
+        let store = store();
+        assert_eq!(store.header, "x-nonce");
+        assert_eq!(store.check("a"), NonceCheck::Fresh);
+        assert_eq!(store.check("b"), NonceCheck::Fresh);
+        assert_eq!(store.check("a"), NonceCheck::Replayed);
+    }
+
+    #[test]
+    fn test_nonce_is_forgotten_after_window() {
+        let store = store();
+        let start = Instant::now();
+        assert_eq!(store.check_at("a", start, CAPACITY), NonceCheck::Fresh);
+        let later = start + Duration::from_secs(59);
+        assert_eq!(store.check_at("a", later, CAPACITY), NonceCheck::Replayed);
+        let later = start + Duration::from_secs(60);
+        assert_eq!(store.check_at("a", later, CAPACITY), NonceCheck::Fresh);
+    }
+
+    #[test]
+    fn test_new_nonces_are_refused_at_capacity() {
+        let store = store();
+        let now = Instant::now();
+        assert_eq!(store.check_at("a", now, 2), NonceCheck::Fresh);
+        assert_eq!(store.check_at("b", now, 2), NonceCheck::Fresh);
+        // Pushing "a" out would let it be replayed
+        assert_eq!(store.check_at("c", now, 2), NonceCheck::Full);
+        assert_eq!(store.check_at("a", now, 2), NonceCheck::Replayed);
+
+        let later = now + Duration::from_secs(60);
+        assert_eq!(store.check_at("c", later, 2), NonceCheck::Fresh);
+    }
+}
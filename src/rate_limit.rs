@@ -0,0 +1,127 @@
+use axum::http::StatusCode;
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Requests allowed per period, e.g. "10/s"
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rate {
+    pub requests: u32,
+    pub period: Duration,
+}
+
+/// Parse a rate like "10/s", "100/m" or "1000/h"
+pub fn parse_rate(spec: &str) -> Result<Rate, String> {
+    let (requests, unit) = spec
+        .split_once('/')
+        .ok_or_else(|| format!("expected REQUESTS/UNIT, got '{}'", spec))?;
+    let requests: u32 = requests
+        .trim()
+        .parse()
+        .ok()
+        .filter(|&n| n > 0)
+        .ok_or_else(|| format!("invalid request count in '{}'", spec))?;
+    let period = match unit.trim() {
+        "s" => Duration::from_secs(1),
+        "m" => Duration::from_secs(60),
+        "h" => Duration::from_secs(3600),
+        other => return Err(format!("unknown unit '{}' (use s, m or h)", other)),
+    };
+    Ok(Rate { requests, period })
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token buckets per route: each holds up to `requests` tokens and refills
+/// at `requests` per `period`, so bursts up to the limit are allowed
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    /// Take a token for `route`, or return how long until one is available
+    pub fn check(&self, route: &str, rate: Rate) -> Result<(), Duration> {
+        let capacity = rate.requests as f64;
+        let per_sec = capacity / rate.period.as_secs_f64();
+        let now = Instant::now();
+
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(route.to_string()).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        let refilled = now.duration_since(bucket.updated).as_secs_f64() * per_sec;
+        bucket.tokens = (bucket.tokens + refilled).min(capacity);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / per_sec))
+        }
+    }
+}
+
+/// Response for requests over their route's rate limit
+#[derive(Clone, Debug)]
+pub struct Throttle {
+    pub status: StatusCode,
+    pub body: String,
+    /// Command whose output replaces `body`
+    pub command: Option<String>,
+}
+
+impl Default for Throttle {
+    fn default() -> Self {
+        Throttle {
+            status: StatusCode::TOO_MANY_REQUESTS,
+            body: "Too Many Requests".to_string(),
+            command: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_rate() {
+        let rate = parse_rate("10/s").unwrap();
+        assert_eq!(rate, Rate { requests: 10, period: Duration::from_secs(1) });
+        assert_eq!(parse_rate(" 100 / m ").unwrap().period, Duration::from_secs(60));
+        assert!(parse_rate("10").is_err());
+        assert!(parse_rate("0/s").is_err());
+        assert!(parse_rate("10/d").is_err());
+    }
+
+    #[test]
+    fn test_limiter_allows_burst_then_throttles() {
+        let limiter = RateLimiter::default();
+        let rate = Rate { requests: 2, period: Duration::from_secs(60) };
+        assert!(limiter.check("GET /a", rate).is_ok());
+        assert!(limiter.check("GET /a", rate).is_ok());
+
+        let retry_after = limiter.check("GET /a", rate).unwrap_err();
+        assert!(retry_after > Duration::from_secs(25) && retry_after <= Duration::from_secs(30));
+        // Routes have their own buckets
+        assert!(limiter.check("GET /b", rate).is_ok());
+    }
+
+    #[test]
+    fn test_limiter_refills() {
+        let limiter = RateLimiter::default();
+        let rate = Rate { requests: 1, period: Duration::from_millis(50) };
+        assert!(limiter.check("GET /a", rate).is_ok());
+        assert!(limiter.check("GET /a", rate).is_err());
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(limiter.check("GET /a", rate).is_ok());
+    }
+}
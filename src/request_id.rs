@@ -0,0 +1,145 @@
+use axum::{
+    body::{Body, Bytes, HttpBody},
+    extract::Request,
+    http::HeaderValue,
+    middleware::Next,
+    response::Response,
+};
+use http_body::{Frame, SizeHint};
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tracing::debug;
+use uuid::Uuid;
+
+/// Header carrying the request id, taken from the client when present
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Id of the current request, available as a request extension
+#[derive(Clone, Debug)]
+pub struct RequestId(pub String);
+
+/// Tag each request with an id (the client's X-Request-Id or a new UUID), echo it
+/// on the response and log when the client goes away before the body was sent
+pub async fn request_id(mut request: Request, next: Next) -> Response {
+    let id = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| !v.is_empty() && v.len() <= 128)
+        .map(str::to_string)
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+    request.extensions_mut().insert(RequestId(id.clone()));
+
+    let (mut parts, body) = next.run(request).await.into_parts();
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        parts.headers.insert(REQUEST_ID_HEADER, value);
+    }
+    let body = DeliveryBody {
+        inner: body,
+        id,
+        finished: false,
+    };
+    Response::from_parts(parts, Body::new(body))
+}
+
+/// Response body that notices when it is dropped before being sent completely,
+/// which is how a client disconnect (e.g. broken pipe) surfaces
+struct DeliveryBody {
+    inner: Body,
+    id: String,
+    finished: bool,
+}
+
+impl HttpBody for DeliveryBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, axum::Error>>> {
+        let frame = Pin::new(&mut self.inner).poll_frame(cx);
+        if let Poll::Ready(None) = frame {
+            self.finished = true;
+        }
+        frame
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for DeliveryBody {
+    fn drop(&mut self) {
+        // hyper never polls past the end of a body whose size it knows
+        if !self.finished && !self.inner.is_end_stream() {
+            debug!(
+                "Client disconnected before the response was sent (request {})",
+                self.id
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::CapturedMessages;
+    use axum::{middleware, routing::get, Router};
+    use tower::ServiceExt;
+
+    fn app() -> Router {
+        Router::new()
+            .route("/", get(|| async { "ok" }))
+            .layer(middleware::from_fn(request_id))
+    }
+
+    fn get_root(id: Option<&str>) -> Request {
+        let mut request = Request::builder().uri("/");
+        if let Some(id) = id {
+            request = request.header(REQUEST_ID_HEADER, id);
+        }
+        request.body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_request_id_is_echoed_or_generated() {
+        let response = app().oneshot(get_root(Some("abc-123"))).await.unwrap();
+        assert_eq!(response.headers()[REQUEST_ID_HEADER], "abc-123");
+
+        let response = app().oneshot(get_root(None)).await.unwrap();
+        let generated = response.headers()[REQUEST_ID_HEADER].to_str().unwrap();
+        assert!(Uuid::parse_str(generated).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_dropped_connection_is_logged() {
+        let captured = CapturedMessages::default();
+        let _guard = captured.set_default();
+
+        // The body is dropped unsent, as hyper does when the client disconnects
+        let response = app().oneshot(get_root(Some("gone-1"))).await.unwrap();
+        drop(response);
+
+        let messages = captured.messages();
+        assert!(messages.iter().any(|m| m.contains("disconnected") && m.contains("gone-1")));
+    }
+
+    #[tokio::test]
+    async fn test_delivered_body_is_not_logged() {
+        let captured = CapturedMessages::default();
+        let _guard = captured.set_default();
+
+        let response = app().oneshot(get_root(None)).await.unwrap();
+        axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+
+        assert!(captured.messages().is_empty());
+    }
+}
@@ -1,125 +1,477 @@
+use axum::{
+    extract::{DefaultBodyLimit, Extension},
+    middleware,
+    routing::{any, delete, get, options, patch, post, put},
+    Router,
+};
 use regex::Regex;
-use tracing::{error, info};
+use std::{
+    collections::HashSet,
+    sync::{Arc, LazyLock},
+};
+use tracing::{info, warn};
+
+use crate::handler::{fallback_handler, handler, limit_uri_length};
+use crate::health::{health_handler, health_path};
+use crate::request_id::request_id;
+use crate::state::AppState;
+use crate::static_response::static_route;
 
 /// Route entry with method and path
 #[derive(Clone, Debug)]
 pub struct RouteEntry {
     pub method: String,
+    /// Host the route is limited to; matches any host when unset
+    pub host: Option<String>,
     pub path: String,
     pub command: String,
+    /// Env condition ("VAR" or "VAR=value") the route is only registered under
+    pub enabled_if: Option<String>,
+}
+
+impl RouteEntry {
+    /// Key used to look up the route's command and config: "METHOD [host]/path"
+    pub fn key(&self) -> String {
+        format!("{} {}{}", self.method, self.host.as_deref().unwrap_or(""), self.path)
+    }
+
+    /// Whether the route's `enabled_if` condition holds, looking variables up with `env`.
+    /// "VAR" needs VAR set and non-empty, "VAR=value" needs that exact value.
+    pub fn enabled(&self, env: impl Fn(&str) -> Option<String>) -> bool {
+        let Some(condition) = &self.enabled_if else {
+            return true;
+        };
+        match condition.split_once('=') {
+            Some((var, expected)) => env(var.trim()).as_deref() == Some(expected.trim()),
+            None => env(condition.trim()).is_some_and(|value| !value.is_empty()),
+        }
+    }
+}
+
+/// Drop the routes whose `enabled_if` condition does not hold, logging each one
+pub fn enabled_routes(
+    routes: Vec<RouteEntry>,
+    env: impl Fn(&str) -> Option<String>,
+) -> Vec<RouteEntry> {
+    routes
+        .into_iter()
+        .filter(|route| {
+            let enabled = route.enabled(&env);
+            if !enabled {
+                info!(
+                    "Skipping route {}: condition '{}' not met",
+                    route.key(),
+                    route.enabled_if.as_deref().unwrap_or_default()
+                );
+            }
+            enabled
+        })
+        .collect()
 }
 
+/// Methods a route spec may start with
+const METHODS: [&str; 8] = ["GET", "POST", "PUT", "DELETE", "PATCH", "HEAD", "OPTIONS", "ANY"];
+
 /// Parse route specification like "GET /hello/:name" or just "/hello/:name"
-pub fn parse_route_spec(spec: &str) -> (String, String) {
+pub fn parse_route_spec(spec: &str) -> Result<(String, String), String> {
     let spec = spec.trim();
-    let parts: Vec<&str> = spec.splitn(2, ' ').collect();
-
-    if parts.len() == 2 {
-        let method = parts[0].to_uppercase();
-        let path = parts[1].to_string();
-        // Validate method
-        match method.as_str() {
-            "GET" | "POST" | "PUT" | "DELETE" | "PATCH" | "HEAD" | "OPTIONS" | "ANY" => {
-                (method, path)
-            }
-            _ => {
-                // Assume it's a path starting with something that looks like a method
-                ("ANY".to_string(), spec.to_string())
-            }
+    if spec.is_empty() {
+        return Err("route is empty".to_string());
+    }
+
+    // No method specified (the spec is just a path), default to ANY
+    let (method, path) = match spec.split_once(char::is_whitespace) {
+        Some((method, path)) if !method.contains('/') => (method.to_uppercase(), path.trim()),
+        _ => ("ANY".to_string(), spec),
+    };
+
+    if !METHODS.contains(&method.as_str()) {
+        return Err(format!(
+            "unknown method '{}' in route '{}' (expected one of {})",
+            method,
+            spec,
+            METHODS.join(", ")
+        ));
+    }
+    if path.contains(char::is_whitespace) {
+        return Err(format!(
+            "path '{}' contains whitespace; encode spaces as %20",
+            path
+        ));
+    }
+    if !split_host(path).1.starts_with('/') {
+        return Err(format!("path '{}' must start with '/'", path));
+    }
+    Ok((method, path.to_string()))
+}
+
+/// Split an optional host prefix from a route path, e.g. "api.example.com/x"
+pub fn split_host(raw_path: &str) -> (Option<String>, String) {
+    match raw_path.find('/') {
+        Some(i) if i > 0 && !raw_path[..i].contains(char::is_whitespace) => {
+            (Some(raw_path[..i].to_lowercase()), raw_path[i..].to_string())
         }
-    } else {
-        // No method specified, default to ANY
-        ("ANY".to_string(), spec.to_string())
+        _ => (None, raw_path.to_string()),
     }
 }
 
+/// `:name` params of a route path
+static PATH_PARAM: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r":([a-zA-Z0-9_]+)").expect("Invalid regex"));
+
+/// `*name` wildcards of a route path
+static PATH_WILDCARD: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"/\*([a-zA-Z0-9_]+)").expect("Invalid regex"));
+
+/// Convert /user/:id to /user/{id} and /files/*path to /files/{*path} for Axum compatibility
+fn normalize_path(raw_path: &str) -> String {
+    let path = PATH_PARAM.replace_all(raw_path, "{$1}");
+    PATH_WILDCARD.replace_all(&path, "/{*$1}").to_string()
+}
+
+/// Build the "METHOD [host]/path" key used to look up a route's command and config
+pub fn route_key(spec: &str) -> Result<String, String> {
+    let (method, raw_path) = parse_route_spec(spec)?;
+    let (host, path) = split_host(&raw_path);
+    Ok(format!("{} {}{}", method, host.unwrap_or_default(), normalize_path(&path)))
+}
+
+/// Route key for a spec given to a per-route option
+fn option_route_key(spec: &str) -> Result<String, String> {
+    route_key(spec).map_err(|e| format!("Invalid route '{}': {}", spec, e))
+}
+
+/// Parse per-route CLI option pairs (ROUTE VALUE) into (route key, value)
+pub fn parse_route_options(raw_options: &[String]) -> Result<Vec<(String, String)>, String> {
+    raw_options
+        .chunks(2)
+        .filter_map(|chunk| match chunk {
+            [spec, value] => Some(option_route_key(spec).map(|key| (key, value.clone()))),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Parse per-route CLI flags (ROUTE) into route keys
+pub fn parse_route_flags(raw_specs: &[String]) -> Result<Vec<String>, String> {
+    raw_specs.iter().map(|spec| option_route_key(spec)).collect()
+}
+
 /// Parse CLI route arguments into RouteEntry structs
-pub fn parse_routes(raw_routes: &[String]) -> Vec<RouteEntry> {
+pub fn parse_routes(raw_routes: &[String]) -> Result<Vec<RouteEntry>, String> {
     let mut routes: Vec<RouteEntry> = Vec::new();
-    let route_regex = Regex::new(r":([a-zA-Z0-9_]+)").expect("Invalid regex");
 
     for chunk in raw_routes.chunks(2) {
         if let [raw_spec, cmd] = chunk {
             if cmd.trim().is_empty() {
-                error!("Command for route '{}' is empty. Exiting.", raw_spec);
-                std::process::exit(1);
+                return Err(format!("Command for route '{}' is empty", raw_spec));
             }
 
-            let (method, raw_path) = parse_route_spec(raw_spec);
+            let (method, raw_path) = parse_route_spec(raw_spec)
+                .map_err(|e| format!("Invalid route '{}': {}", raw_spec, e))?;
+            let (host, path) = split_host(&raw_path);
 
-            // Convert /user/:id to /user/{id} for Axum compatibility
-            let normalized_path = route_regex.replace_all(&raw_path, "{$1}").to_string();
+            let normalized_path = normalize_path(&path);
 
             routes.push(RouteEntry {
                 method: method.clone(),
+                host,
                 path: normalized_path.clone(),
                 command: cmd.clone(),
+                enabled_if: None,
             });
-            info!("Registered route: {} {} -> `{}`", method, raw_path, cmd);
+            // Keep multi-line commands on one log line
+            let shown = cmd.replace('\n', "\\n");
+            info!("Registered route: {} {} -> `{}`", method, raw_path, shown);
         }
     }
 
-    routes
+    Ok(routes)
+}
+
+/// Check that no two of the (description, path) pairs have conflicting paths,
+/// e.g. /a/{id} next to /a/{name}, which axum would panic on
+fn check_paths<'a>(paths: impl IntoIterator<Item = (String, &'a str)>) -> Result<(), String> {
+    let mut router = matchit::Router::new();
+    let mut seen = HashSet::new();
+    for (what, path) in paths {
+        if seen.insert(path) {
+            router.insert(path, ()).map_err(|e| format!("{}: {}", what, e))?;
+        }
+    }
+    Ok(())
+}
+
+/// Build the axum router for the parsed routes, sharing `state` with every handler.
+/// Fails if route, static response or health check paths conflict.
+pub fn build_router(routes: &[RouteEntry], state: Arc<AppState>) -> Result<Router, String> {
+    // Static responses and the health check give way to commands on the same path
+    let mut statics = Vec::new();
+    for (key, response) in &state.static_responses {
+        let Some((method, path)) = key.split_once(' ') else {
+            continue;
+        };
+        let overlaps = routes.iter().any(|r| {
+            r.path == path && (r.method == method || r.method == "ANY" || method == "ANY")
+        });
+        if overlaps {
+            warn!("Route {} has a command, skipping its static response", key);
+        } else {
+            statics.push((key, method, path, response));
+        }
+    }
+    let health = health_path(&state).filter(|health_path| {
+        let overlaps = routes.iter().any(|r| r.path == *health_path);
+        if overlaps {
+            warn!("Route {} is defined explicitly, skipping the health check", health_path);
+        }
+        !overlaps
+    });
+
+    let route_paths = routes
+        .iter()
+        .map(|r| (format!("Route {} {}", r.method, r.path), r.path.as_str()));
+    let static_paths = statics
+        .iter()
+        .map(|(key, _, path, _)| (format!("Static response {}", key), *path));
+    let health_paths = health.map(|path| (format!("Health check {}", path), path));
+    check_paths(route_paths.chain(static_paths).chain(health_paths))?;
+
+    let mut app: Router = Router::new();
+
+    // Routes differing only by host share one axum route; the handler picks the command
+    let mut registered = HashSet::new();
+    for route in routes {
+        if !registered.insert((route.method.as_str(), route.path.as_str())) {
+            continue;
+        }
+        app = match route.method.as_str() {
+            "GET" => app.route(&route.path, get(handler)),
+            "POST" => app.route(&route.path, post(handler)),
+            "PUT" => app.route(&route.path, put(handler)),
+            "DELETE" => app.route(&route.path, delete(handler)),
+            "PATCH" => app.route(&route.path, patch(handler)),
+            _ => app.route(&route.path, any(handler)),
+        };
+    }
+
+    // Paths bound only to specific methods would answer OPTIONS with 405 before
+    // reaching the handler, so route OPTIONS to it explicitly
+    if state.auto_options {
+        let mut seen = HashSet::new();
+        for route in routes {
+            if !seen.insert(route.path.as_str()) {
+                continue;
+            }
+            let catches_options = routes.iter().any(|r| {
+                r.path == route.path
+                    && !matches!(r.method.as_str(), "GET" | "POST" | "PUT" | "DELETE" | "PATCH")
+            });
+            if !catches_options {
+                app = app.route(&route.path, options(handler));
+            }
+        }
+    }
+
+    for (_, method, path, response) in statics {
+        app = app.route(path, static_route(method, response.clone()));
+    }
+
+    if let Some(health_path) = health {
+        app = app.route(health_path, get(health_handler));
+    }
+
+    // The body extractor's limit is the ceiling; the handler enforces each route's own limit.
+    // Without a global limit, routes without their own accept bodies of any size.
+    app = match state.max_body_size {
+        Some(global) => {
+            let limit = state
+                .route_configs
+                .values()
+                .filter_map(|config| config.max_body_size)
+                .fold(global, usize::max);
+            app.layer(DefaultBodyLimit::max(limit))
+        }
+        None => app.layer(DefaultBodyLimit::disable()),
+    };
+
+    Ok(app
+        .fallback(fallback_handler)
+        .layer(Extension(state))
+        .layer(middleware::from_fn(request_id)))
+}
+
+/// Reject URIs longer than `max_uri_length` with 414 before `app` routes them.
+/// Router layers only run once routing is done, so this wraps the whole router.
+pub fn limit_uri(app: Router, max_uri_length: usize) -> Router {
+    Router::new()
+        .fallback_service(app)
+        .layer(middleware::from_fn_with_state(max_uri_length, limit_uri_length))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::collections::HashMap;
+
+    fn gated_route(path: &str, condition: &str) -> RouteEntry {
+        RouteEntry {
+            method: "GET".to_string(),
+            host: None,
+            path: path.to_string(),
+            command: "echo".to_string(),
+            enabled_if: Some(condition.to_string()),
+        }
+    }
+
+    /// Environment with only FEATURE_X=1 set
+    fn feature_env(var: &str) -> Option<String> {
+        (var == "FEATURE_X").then(|| "1".to_string())
+    }
+
+    #[test]
+    fn test_enabled_routes_follow_env() {
+        let routes = vec![
+            gated_route("/x", "FEATURE_X"),
+            gated_route("/y", "FEATURE_Y"),
+            gated_route("/x-on", "FEATURE_X=1"),
+            gated_route("/x-off", "FEATURE_X=0"),
+        ];
+        let enabled: Vec<_> = enabled_routes(routes, feature_env)
+            .into_iter()
+            .map(|route| route.path)
+            .collect();
+        assert_eq!(enabled, vec!["/x", "/x-on"]);
+    }
+
+    #[test]
+    fn test_ungated_route_is_enabled() {
+        let route = RouteEntry { enabled_if: None, ..gated_route("/x", "") };
+        assert!(route.enabled(|_| None));
+    }
 
     #[test]
     fn test_parse_route_spec_with_method() {
-        let (method, path) = parse_route_spec("GET /hello");
+        let (method, path) = parse_route_spec("GET /hello").unwrap();
         assert_eq!(method, "GET");
         assert_eq!(path, "/hello");
     }
 
     #[test]
     fn test_parse_route_spec_post() {
-        let (method, path) = parse_route_spec("POST /users");
+        let (method, path) = parse_route_spec("POST /users").unwrap();
         assert_eq!(method, "POST");
         assert_eq!(path, "/users");
     }
 
     #[test]
     fn test_parse_route_spec_lowercase_method() {
-        let (method, path) = parse_route_spec("get /hello");
+        let (method, path) = parse_route_spec("get /hello").unwrap();
         assert_eq!(method, "GET");
         assert_eq!(path, "/hello");
     }
 
     #[test]
     fn test_parse_route_spec_without_method() {
-        let (method, path) = parse_route_spec("/hello/:name");
+        let (method, path) = parse_route_spec("/hello/:name").unwrap();
         assert_eq!(method, "ANY");
         assert_eq!(path, "/hello/:name");
     }
 
     #[test]
     fn test_parse_route_spec_any_method() {
-        let (method, path) = parse_route_spec("ANY /api");
+        let (method, path) = parse_route_spec("ANY /api").unwrap();
         assert_eq!(method, "ANY");
         assert_eq!(path, "/api");
     }
 
     #[test]
-    fn test_parse_route_spec_invalid_method_becomes_any() {
-        let (method, path) = parse_route_spec("INVALID /path");
-        assert_eq!(method, "ANY");
-        assert_eq!(path, "INVALID /path");
+    fn test_parse_route_spec_invalid_method_is_an_error() {
+        let err = parse_route_spec("INVALID /path").unwrap_err();
+        assert!(err.contains("unknown method 'INVALID'"), "{}", err);
+    }
+
+    #[test]
+    fn test_parse_routes_errors() {
+        let raw = ["GET /ok".to_string(), " ".to_string()];
+        assert_eq!(parse_routes(&raw).unwrap_err(), "Command for route 'GET /ok' is empty");
+        let raw = ["FETCH /x".to_string(), "echo".to_string()];
+        let err = parse_routes(&raw).unwrap_err();
+        assert!(err.starts_with("Invalid route 'FETCH /x': unknown method"), "{}", err);
+        let err = parse_route_options(&["x".to_string(), "v".to_string()]).unwrap_err();
+        assert_eq!(err, "Invalid route 'x': path 'x' must start with '/'");
+        assert!(parse_route_flags(&["GET /ok".to_string()]).is_ok());
+    }
+
+    #[test]
+    fn test_conflicting_paths_are_an_error() {
+        let raw = ["GET /a/:id", "echo", "POST /a/:name", "echo"].map(String::from);
+        let routes = parse_routes(&raw).unwrap();
+        let err = build_router(&routes, Arc::new(AppState::default())).unwrap_err();
+        assert!(err.starts_with("Route POST /a/{name}: "), "{}", err);
+
+        let raw = ["GET /a", "echo", "ANY /a", "echo", "GET /a/:id/x", "echo"].map(String::from);
+        let routes = parse_routes(&raw).unwrap();
+        assert!(build_router(&routes, Arc::new(AppState::default())).is_ok());
+    }
+
+    #[test]
+    fn test_static_and_health_paths_conflicting_with_routes_are_an_error() {
+        let raw = ["GET /u/:id", "echo"].map(String::from);
+        let routes = parse_routes(&raw).unwrap();
+
+        let static_responses = HashMap::from([("GET /u/{name}".to_string(), Default::default())]);
+        let state = AppState { static_responses, ..Default::default() };
+        let err = build_router(&routes, Arc::new(state)).unwrap_err();
+        assert!(err.starts_with("Static response GET /u/{name}: "), "{}", err);
+
+        let state = AppState { health_path: Some("/u/{x}".to_string()), ..Default::default() };
+        let err = build_router(&routes, Arc::new(state)).unwrap_err();
+        assert!(err.starts_with("Health check /u/{x}: "), "{}", err);
+
+        // Same path on another method is no conflict
+        let static_responses = HashMap::from([("POST /u/{id}".to_string(), Default::default())]);
+        let state = AppState { static_responses, ..Default::default() };
+        assert!(build_router(&routes, Arc::new(state)).is_ok());
+    }
+
+    #[test]
+    fn test_parse_route_spec_extra_whitespace() {
+        let (method, path) = parse_route_spec("GET \t  /hello").unwrap();
+        assert_eq!(method, "GET");
+        assert_eq!(path, "/hello");
+    }
+
+    #[test]
+    fn test_parse_route_spec_with_host() {
+        let (method, path) = parse_route_spec("GET api.example.com/x").unwrap();
+        assert_eq!(method, "GET");
+        assert_eq!(path, "api.example.com/x");
+        assert_eq!(parse_route_spec("api.example.com/x").unwrap().0, "ANY");
+    }
+
+    #[test]
+    fn test_parse_route_spec_malformed() {
+        assert_eq!(parse_route_spec("   ").unwrap_err(), "route is empty");
+        assert!(parse_route_spec("GET hello").unwrap_err().contains("must start with '/'"));
+        assert!(parse_route_spec("hello").unwrap_err().contains("must start with '/'"));
+        assert!(parse_route_spec("GET /a b").unwrap_err().contains("encode spaces as %20"));
+        assert!(parse_route_spec("/a b").unwrap_err().contains("whitespace"));
+        assert!(parse_route_spec("GET /a%20b").is_ok());
     }
 
     #[test]
     fn test_parse_route_spec_all_methods() {
         for method in ["GET", "POST", "PUT", "DELETE", "PATCH", "HEAD", "OPTIONS"] {
             let spec = format!("{} /test", method);
-            let (parsed_method, _) = parse_route_spec(&spec);
+            let (parsed_method, _) = parse_route_spec(&spec).unwrap();
             assert_eq!(parsed_method, method);
         }
     }
 
     #[test]
     fn test_parse_route_spec_trims_whitespace() {
-        let (method, path) = parse_route_spec("  GET /hello  ");
+        let (method, path) = parse_route_spec("  GET /hello  ").unwrap();
         assert_eq!(method, "GET");
         assert_eq!(path, "/hello");
     }
@@ -130,7 +482,7 @@ mod tests {
             "GET /user/:id".to_string(),
             "echo :id".to_string(),
         ];
-        let routes = parse_routes(&raw);
+        let routes = parse_routes(&raw).unwrap();
 
         assert_eq!(routes.len(), 1);
         assert_eq!(routes[0].method, "GET");
@@ -146,7 +498,7 @@ mod tests {
             "POST /data".to_string(),
             "cat".to_string(),
         ];
-        let routes = parse_routes(&raw);
+        let routes = parse_routes(&raw).unwrap();
 
         assert_eq!(routes.len(), 2);
         assert_eq!(routes[0].method, "GET");
@@ -161,16 +513,66 @@ mod tests {
             "/users/:user_id/posts/:post_id".to_string(),
             "echo :user_id :post_id".to_string(),
         ];
-        let routes = parse_routes(&raw);
+        let routes = parse_routes(&raw).unwrap();
 
         assert_eq!(routes.len(), 1);
         assert_eq!(routes[0].path, "/users/{user_id}/posts/{post_id}");
     }
 
+    #[test]
+    fn test_route_key_normalizes_spec() {
+        assert_eq!(route_key("get /user/:id").unwrap(), "GET /user/{id}");
+        assert_eq!(route_key("/health").unwrap(), "ANY /health");
+        assert_eq!(route_key("GET /files/*path").unwrap(), "GET /files/{*path}");
+        assert!(route_key("FETCH /health").is_err());
+    }
+
+    #[test]
+    fn test_parse_route_options() {
+        let raw = vec![
+            "GET /user/:id".to_string(),
+            "debug".to_string(),
+            "/health".to_string(),
+            "warn".to_string(),
+        ];
+        let options = parse_route_options(&raw).unwrap();
+        assert_eq!(
+            options,
+            vec![
+                ("GET /user/{id}".to_string(), "debug".to_string()),
+                ("ANY /health".to_string(), "warn".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_split_host() {
+        assert_eq!(
+            split_host("API.example.com/x/:id"),
+            (Some("api.example.com".to_string()), "/x/:id".to_string())
+        );
+        assert_eq!(split_host("/x"), (None, "/x".to_string()));
+        assert_eq!(split_host("INVALID /path"), (None, "INVALID /path".to_string()));
+    }
+
+    #[test]
+    fn test_parse_routes_with_host() {
+        let raw = vec![
+            "GET api.example.com/users/:id".to_string(),
+            "echo :id".to_string(),
+        ];
+        let routes = parse_routes(&raw).unwrap();
+
+        assert_eq!(routes[0].host.as_deref(), Some("api.example.com"));
+        assert_eq!(routes[0].path, "/users/{id}");
+        assert_eq!(routes[0].key(), "GET api.example.com/users/{id}");
+        assert_eq!(route_key("GET api.example.com/users/:id").unwrap(), routes[0].key());
+    }
+
     #[test]
     fn test_parse_routes_empty() {
         let raw: Vec<String> = vec![];
-        let routes = parse_routes(&raw);
+        let routes = parse_routes(&raw).unwrap();
         assert!(routes.is_empty());
     }
 }
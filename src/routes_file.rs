@@ -0,0 +1,194 @@
+use arc_swap::ArcSwap;
+use axum::{extract::Request, Router};
+use notify::{RecursiveMode, Watcher};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::sync::mpsc;
+use tower::ServiceExt;
+use tracing::{error, info};
+
+use crate::routes::parse_route_spec;
+
+/// How long to wait for an editor to finish writing before reloading
+const SETTLE: Duration = Duration::from_millis(100);
+
+/// Parse a routes file into (spec, command) pairs in `--route` order. Each line
+/// is `[METHOD] PATH COMMAND`; blank lines and lines starting with `#` are skipped.
+pub fn parse_routes_file(text: &str) -> Result<Vec<String>, String> {
+    let mut routes = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (first, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let (spec, command) = if first.contains('/') {
+            (first.to_string(), rest.trim())
+        } else {
+            let rest = rest.trim_start();
+            let (path, command) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+            (format!("{} {}", first, path), command.trim())
+        };
+        if let Err(e) = parse_route_spec(&spec) {
+            return Err(format!("line {}: {}", i + 1, e));
+        }
+        if command.is_empty() {
+            return Err(format!("line {}: route '{}' has no command", i + 1, spec));
+        }
+        routes.push(spec);
+        routes.push(command.to_string());
+    }
+    Ok(routes)
+}
+
+/// Read and parse the routes file at `path`
+pub fn load_routes_file(path: &Path) -> Result<Vec<String>, String> {
+    let text = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    parse_routes_file(&text)
+}
+
+/// Router that passes each request to the router currently in `current`. Requests
+/// keep the router they started with, so swapping it doesn't drop in-flight ones.
+pub fn serve_current(current: Arc<ArcSwap<Router>>) -> Router {
+    Router::new().fallback(move |request: Request| {
+        let router = Router::clone(&current.load());
+        async move { router.oneshot(request).await }
+    })
+}
+
+/// Rebuild the router in `current` with `rebuild` whenever the routes file at
+/// `path` changes. Files that fail to load are logged and the old routes kept.
+pub async fn reload_on_change(
+    path: PathBuf,
+    current: Arc<ArcSwap<Router>>,
+    rebuild: impl Fn(Vec<String>) -> Result<Router, String>,
+) {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let mut watcher = match notify::recommended_watcher(move |event| {
+        let _ = tx.send(event);
+    }) {
+        Ok(watcher) => watcher,
+        Err(e) => {
+            error!("Cannot watch routes file '{}': {}", path.display(), e);
+            return;
+        }
+    };
+    // Watch the directory, as editors often replace the file instead of writing to it
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    if let Err(e) = watcher.watch(dir, RecursiveMode::NonRecursive) {
+        error!("Cannot watch routes file '{}': {}", path.display(), e);
+        return;
+    }
+
+    while let Some(event) = rx.recv().await {
+        let changed = match event {
+            // Not access events, which reading the file on reload causes itself
+            Ok(event) if event.kind.is_access() => false,
+            Ok(event) => event.paths.iter().any(|p| p.file_name() == path.file_name()),
+            Err(e) => {
+                error!("Error watching routes file '{}': {}", path.display(), e);
+                false
+            }
+        };
+        if !changed {
+            continue;
+        }
+        tokio::time::sleep(SETTLE).await;
+        while rx.try_recv().is_ok() {}
+
+        match load_routes_file(&path).and_then(&rebuild) {
+            Ok(router) => {
+                current.store(Arc::new(router));
+                info!("Reloaded routes from '{}'", path.display());
+            }
+            Err(e) => error!(
+                "Cannot reload routes file '{}': {}. Keeping the previous routes.",
+                path.display(),
+                e
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::routes::{build_router, parse_routes};
+    use crate::state::AppState;
+    use axum::{body::Body, http::StatusCode, routing::get};
+
+    #[test]
+    fn test_parse_routes_file() {
+        let text = "# routes\n\nGET /hello echo hello world\n/any   cat\n";
+        let routes = parse_routes_file(text).unwrap();
+        assert_eq!(routes, vec!["GET /hello", "echo hello world", "/any", "cat"]);
+
+        let err = parse_routes_file("GET /ok echo\nFETCH /x echo").unwrap_err();
+        assert!(err.starts_with("line 2: unknown method 'FETCH'"), "{}", err);
+        let err = parse_routes_file("GET /x").unwrap_err();
+        assert_eq!(err, "line 1: route 'GET /x' has no command");
+    }
+
+    async fn status(app: &Router, uri: &str) -> StatusCode {
+        let request = Request::builder().uri(uri).body(Body::empty()).unwrap();
+        app.clone().oneshot(request).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn test_serve_current_follows_swaps() {
+        let current = Arc::new(ArcSwap::from_pointee(
+            Router::new().route("/old", get(|| async { "old" })),
+        ));
+        let app = serve_current(current.clone());
+        assert_eq!(status(&app, "/old").await, StatusCode::OK);
+
+        current.store(Arc::new(Router::new().route("/new", get(|| async { "new" }))));
+        assert_eq!(status(&app, "/old").await, StatusCode::NOT_FOUND);
+        assert_eq!(status(&app, "/new").await, StatusCode::OK);
+    }
+
+    /// Router for `raw` routes, as main rebuilds it on reload
+    fn rebuild(raw: Vec<String>) -> Result<Router, String> {
+        let routes = parse_routes(&raw)?;
+        let commands = routes.iter().map(|r| (r.key(), r.command.clone())).collect();
+        build_router(&routes, Arc::new(AppState { commands, ..Default::default() }))
+    }
+
+    #[tokio::test]
+    async fn test_reload_keeps_routes_when_paths_conflict() {
+        let dir = std::env::temp_dir().join(format!("sherut-reload-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("routes.txt");
+        fs::write(&path, "GET /a echo a\n").unwrap();
+        let router = rebuild(load_routes_file(&path).unwrap()).unwrap();
+        let current = Arc::new(ArcSwap::from_pointee(router));
+        let app = serve_current(current.clone());
+        let watcher = tokio::spawn(reload_on_change(path.clone(), current, rebuild));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        fs::write(&path, "GET /b/:id echo\nPOST /b/:name echo\n").unwrap();
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        assert_eq!(status(&app, "/a").await, StatusCode::OK);
+
+        // The watcher is still running and picks up the next edit
+        fs::write(&path, "GET /c echo c\n").unwrap();
+        for _ in 0..40 {
+            if status(&app, "/c").await == StatusCode::OK {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert_eq!(status(&app, "/c").await, StatusCode::OK);
+        assert_eq!(status(&app, "/a").await, StatusCode::NOT_FOUND);
+        assert!(!watcher.is_finished());
+        watcher.abort();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
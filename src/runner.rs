@@ -0,0 +1,297 @@
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use clap::ValueEnum;
+use std::{
+    borrow::Cow,
+    io,
+    process::{ExitStatus, Output, Stdio},
+    time::Duration,
+};
+use tokio::{
+    io::AsyncWriteExt,
+    process::{Child, Command},
+};
+use tracing::warn;
+
+use crate::shell::ShellType;
+
+/// How the request body is written to a command's stdin
+#[derive(Clone, Copy, Debug, Default, ValueEnum, PartialEq)]
+pub enum StdinEncoding {
+    /// The body bytes unchanged
+    #[default]
+    Raw,
+    /// Base64 text of the body
+    Base64,
+    /// Lowercase hex text of the body
+    Hex,
+}
+
+impl StdinEncoding {
+    pub fn encode<'a>(&self, body: &'a [u8]) -> Cow<'a, [u8]> {
+        match self {
+            StdinEncoding::Raw => Cow::Borrowed(body),
+            StdinEncoding::Base64 => Cow::Owned(BASE64.encode(body).into_bytes()),
+            StdinEncoding::Hex => Cow::Owned(
+                body.iter()
+                    .map(|byte| format!("{:02x}", byte))
+                    .collect::<String>()
+                    .into_bytes(),
+            ),
+        }
+    }
+}
+
+/// How the request body reaches a command
+#[derive(Clone, Copy, Debug, Default, ValueEnum, PartialEq)]
+pub enum BodyFormat {
+    /// Written to stdin
+    #[default]
+    Stdin,
+    /// Set as REQUEST_BODY, or REQUEST_BODY_BASE64 when it isn't text
+    Env,
+    /// Written to stdin and set in the environment
+    Both,
+}
+
+impl BodyFormat {
+    pub fn uses_stdin(&self) -> bool {
+        matches!(self, BodyFormat::Stdin | BodyFormat::Both)
+    }
+
+    pub fn uses_env(&self) -> bool {
+        matches!(self, BodyFormat::Env | BodyFormat::Both)
+    }
+}
+
+/// Environment variable carrying `body`: REQUEST_BODY for UTF-8 text, otherwise
+/// (including text with NUL bytes, which the environment can't hold) REQUEST_BODY_BASE64
+pub fn body_env(body: &[u8]) -> (&'static str, String) {
+    match std::str::from_utf8(body) {
+        Ok(text) if !text.contains('\0') => ("REQUEST_BODY", text.to_string()),
+        _ => ("REQUEST_BODY_BASE64", BASE64.encode(body)),
+    }
+}
+
+/// Build a command that runs `script` through `shell` with piped stdio,
+/// inheriting sherut's environment
+pub fn shell_command(shell: &ShellType, script: &str) -> Command {
+    let mut cmd = Command::new(shell.executable());
+    cmd.args(shell.command_args());
+    script_arg(&mut cmd, shell, script);
+    piped(cmd)
+}
+
+/// Like `shell_command`, but run as `user` through `sudo -u`. The script stays a
+/// single argument, so it needs no extra escaping. sudo must allow this without a
+/// password (-n) and keep the environment the command is given (--preserve-env).
+pub fn sudo_command(user: &str, shell: &ShellType, script: &str) -> Command {
+    let mut cmd = Command::new("sudo");
+    cmd.args(["-n", "--preserve-env", "-u", user, "--"]);
+    cmd.arg(shell.executable()).args(shell.command_args());
+    script_arg(&mut cmd, shell, script);
+    piped(cmd)
+}
+
+/// Append `script` to the shell invocation. cmd doesn't parse the quoting
+/// Windows uses for program arguments, so it gets the script as-is.
+#[cfg_attr(not(windows), allow(unused_variables))]
+fn script_arg(cmd: &mut Command, shell: &ShellType, script: &str) {
+    #[cfg(windows)]
+    if *shell == ShellType::Cmd {
+        cmd.raw_arg(script);
+        return;
+    }
+    cmd.arg(script);
+}
+
+/// Check at startup that commands can be run as `user` through sudo
+pub async fn check_sudo(user: &str) -> Result<(), String> {
+    let mut cmd = piped(Command::new("sudo"));
+    cmd.args(["-n", "--preserve-env", "-u", user, "--", "true"]);
+    match run(&mut cmd, &[]).await {
+        Ok(out) if out.status.success() => Ok(()),
+        Ok(out) => Err(format!(
+            "sudo cannot run commands as '{}': {}",
+            user,
+            String::from_utf8_lossy(&out.stderr).trim_end()
+        )),
+        Err(e) => Err(format!("sudo is not available: {}", e)),
+    }
+}
+
+/// Pipe the command's stdio and kill it when dropped
+fn piped(mut cmd: Command) -> Command {
+    cmd.stdin(Stdio::piped());
+    cmd.stdout(Stdio::piped());
+    cmd.stderr(Stdio::piped());
+    // Abandoned commands (e.g. timed out) must not keep running
+    cmd.kill_on_drop(true);
+    cmd
+}
+
+/// Spawn `cmd`, write `input` to its stdin and wait for it to finish
+pub async fn run(cmd: &mut Command, input: &[u8]) -> io::Result<Output> {
+    finish(cmd.spawn()?, input).await
+}
+
+/// Write `input` to a spawned command's stdin and wait for it to finish
+pub async fn finish(mut child: Child, input: &[u8]) -> io::Result<Output> {
+    if let Some(mut stdin) = child.stdin.take() {
+        if let Err(e) = stdin.write_all(input).await {
+            warn!("Failed to write to stdin: {}", e);
+        }
+        drop(stdin); // Close stdin to signal EOF
+    }
+
+    child.wait_with_output().await
+}
+
+/// Like `run`, but kills the command and fails with `TimedOut` once `timeout` passes
+pub async fn run_with_timeout(
+    cmd: &mut Command,
+    input: &[u8],
+    timeout: Option<Duration>,
+) -> io::Result<Output> {
+    finish_with_timeout(cmd.spawn()?, input, timeout).await
+}
+
+/// Like `finish`, but kills the command and fails with `TimedOut` once `timeout` passes
+pub async fn finish_with_timeout(
+    child: Child,
+    input: &[u8],
+    timeout: Option<Duration>,
+) -> io::Result<Output> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, finish(child, input))
+            .await
+            .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "command timed out"))),
+        None => finish(child, input).await,
+    }
+}
+
+/// Run a one-off setup command, failing with its stderr if it does not succeed
+pub async fn preflight(shell: &ShellType, command: &str) -> Result<(), String> {
+    let mut cmd = shell_command(shell, command);
+    match run(&mut cmd, &[]).await {
+        Ok(out) if out.status.success() => Ok(()),
+        Ok(out) => Err(format!(
+            "exited with {}: {}",
+            out.status,
+            String::from_utf8_lossy(&out.stderr).trim_end()
+        )),
+        Err(e) => Err(format!("could not be started: {}", e)),
+    }
+}
+
+/// Whether the command was killed by one of `signals`, either directly or as
+/// the last command of the script (reported by the shell as exit code 128+N)
+pub fn killed_by(status: &ExitStatus, signals: &[i32]) -> bool {
+    if let Some(signal) = exit_signal(status) {
+        return signals.contains(&signal);
+    }
+    status
+        .code()
+        .is_some_and(|code| code > 128 && signals.contains(&(code - 128)))
+}
+
+/// Signal that terminated the command, if any (always None off Unix)
+pub fn exit_signal(status: &ExitStatus) -> Option<i32> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        status.signal()
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = status;
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_run_passes_stdin() {
+        let mut cmd = shell_command(&ShellType::Sh, "cat");
+        let output = run(&mut cmd, b"hello").await.unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"hello");
+    }
+
+    #[tokio::test]
+    async fn test_run_reports_exit_status() {
+        let mut cmd = shell_command(&ShellType::Sh, "echo oops >&2; exit 3");
+        let output = run(&mut cmd, b"").await.unwrap();
+        assert_eq!(output.status.code(), Some(3));
+        assert_eq!(output.stderr, b"oops\n");
+    }
+
+    #[test]
+    fn test_sudo_command_keeps_script_one_argument() {
+        let script = "echo 'it'\''s' \"$HOME\"; rm -rf /tmp/x";
+        let cmd = sudo_command("www-data", &ShellType::Bash, script);
+        let cmd = cmd.as_std();
+        assert_eq!(cmd.get_program(), "sudo");
+        let args: Vec<_> = cmd.get_args().collect();
+        assert_eq!(
+            args,
+            ["-n", "--preserve-env", "-u", "www-data", "--", "bash", "-c", script]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_check_sudo_fails_for_unknown_user() {
+        assert!(check_sudo("no-such-user-sherut").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_preflight() {
+        assert!(preflight(&ShellType::Sh, "true").await.is_ok());
+        let err = preflight(&ShellType::Sh, "echo no creds >&2; exit 2").await.unwrap_err();
+        assert!(err.contains("no creds"), "{}", err);
+    }
+
+    #[test]
+    fn test_stdin_encoding() {
+        let body = b"\xffhi";
+        assert_eq!(StdinEncoding::Raw.encode(body).as_ref(), body);
+        assert_eq!(StdinEncoding::Base64.encode(body).as_ref(), b"/2hp");
+        assert_eq!(StdinEncoding::Hex.encode(body).as_ref(), b"ff6869");
+    }
+
+    #[test]
+    fn test_body_env() {
+        assert_eq!(body_env(b"{\"a\":1}"), ("REQUEST_BODY", "{\"a\":1}".to_string()));
+        assert_eq!(body_env(b"\xffhi"), ("REQUEST_BODY_BASE64", "/2hp".to_string()));
+        assert_eq!(body_env(b"a\0b"), ("REQUEST_BODY_BASE64", "YQBi".to_string()));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_killed_by_signal() {
+        let mut cmd = shell_command(&ShellType::Sh, "kill -PIPE $$");
+        let output = run(&mut cmd, b"").await.unwrap();
+        assert!(killed_by(&output.status, &[13]));
+        assert!(!killed_by(&output.status, &[15]));
+        assert_eq!(exit_signal(&output.status), Some(13));
+    }
+
+    #[tokio::test]
+    async fn test_killed_by_shell_reported_exit_code() {
+        let mut cmd = shell_command(&ShellType::Sh, "exit 141");
+        let output = run(&mut cmd, b"").await.unwrap();
+        assert!(killed_by(&output.status, &[13]));
+        assert!(!killed_by(&output.status, &[]));
+    }
+
+    #[tokio::test]
+    async fn test_run_with_timeout_kills_slow_command() {
+        let mut cmd = shell_command(&ShellType::Sh, "sleep 5");
+        let started = std::time::Instant::now();
+        let result = run_with_timeout(&mut cmd, b"", Some(Duration::from_millis(100))).await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::TimedOut);
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+}
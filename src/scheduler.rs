@@ -0,0 +1,246 @@
+use axum::http::Method;
+use clap::ValueEnum;
+use std::{
+    cmp::Ordering,
+    collections::BinaryHeap,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::oneshot;
+
+/// Order in which queued requests get a free command slot
+#[derive(Clone, Copy, Debug, Default, ValueEnum, PartialEq)]
+pub enum Scheduling {
+    /// First come, first served
+    #[default]
+    Fifo,
+    /// Reads (GET, HEAD, OPTIONS) go before writes, first come first served within each
+    Priority,
+}
+
+impl Scheduling {
+    fn priority(&self, method: &Method) -> u8 {
+        match self {
+            Scheduling::Fifo => 0,
+            Scheduling::Priority => match *method {
+                Method::GET | Method::HEAD | Method::OPTIONS => 1,
+                _ => 0,
+            },
+        }
+    }
+}
+
+/// What a request does when every command slot is taken
+#[derive(Clone, Copy, Debug, Default, ValueEnum, PartialEq)]
+pub enum Overload {
+    /// Wait for a free slot (bounded by --queue-timeout-ms)
+    #[default]
+    Queue,
+    /// Respond 503 right away
+    Reject,
+}
+
+/// Limits how many commands run at once, handing freed slots to waiting
+/// requests in `Scheduling` order
+#[derive(Debug)]
+pub struct Scheduler {
+    max: usize,
+    scheduling: Scheduling,
+    state: Mutex<Queue>,
+}
+
+#[derive(Debug)]
+struct Queue {
+    available: usize,
+    waiting: BinaryHeap<Waiter>,
+    next_seq: u64,
+}
+
+#[derive(Debug)]
+struct Waiter {
+    priority: u8,
+    seq: u64,
+    wake: oneshot::Sender<Slot>,
+}
+
+impl Ord for Waiter {
+    // Highest priority first, then earliest arrival
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority
+            .cmp(&other.priority)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+impl PartialOrd for Waiter {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Waiter {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Waiter {}
+
+/// A running command's slot, handed to the next waiter when dropped
+#[derive(Debug)]
+pub struct Slot {
+    scheduler: Option<Arc<Scheduler>>,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        if let Some(scheduler) = self.scheduler.take() {
+            scheduler.release();
+        }
+    }
+}
+
+impl Scheduler {
+    pub fn new(max: usize, scheduling: Scheduling) -> Self {
+        Scheduler {
+            max,
+            scheduling,
+            state: Mutex::new(Queue {
+                available: max,
+                waiting: BinaryHeap::new(),
+                next_seq: 0,
+            }),
+        }
+    }
+
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    /// Number of slots currently held by running commands
+    pub fn in_flight(&self) -> usize {
+        self.max - self.state.lock().unwrap().available
+    }
+
+    /// Take a free slot without waiting; None when all are taken or others are queued
+    pub fn try_acquire(self: &Arc<Self>) -> Option<Slot> {
+        let mut queue = self.state.lock().unwrap();
+        if queue.available > 0 && queue.waiting.is_empty() {
+            queue.available -= 1;
+            return Some(Slot { scheduler: Some(self.clone()) });
+        }
+        None
+    }
+
+    /// Wait for a free slot. Dropping the future gives up the place in the queue.
+    pub async fn acquire(self: &Arc<Self>, method: &Method) -> Slot {
+        let slot = {
+            let mut queue = self.state.lock().unwrap();
+            if queue.available > 0 && queue.waiting.is_empty() {
+                queue.available -= 1;
+                return Slot { scheduler: Some(self.clone()) };
+            }
+            let (wake, slot) = oneshot::channel();
+            let seq = queue.next_seq;
+            queue.next_seq += 1;
+            queue.waiting.push(Waiter {
+                priority: self.scheduling.priority(method),
+                seq,
+                wake,
+            });
+            slot
+        };
+        // The releasing request only drops its sender after handing over a slot
+        slot.await.expect("scheduler dropped a waiter")
+    }
+
+    /// Hand a freed slot to the best waiter still waiting, or make it available
+    fn release(self: &Arc<Self>) {
+        loop {
+            let waiter = {
+                let mut queue = self.state.lock().unwrap();
+                match queue.waiting.pop() {
+                    Some(waiter) => waiter,
+                    None => {
+                        queue.available += 1;
+                        return;
+                    }
+                }
+            };
+            let slot = Slot { scheduler: Some(self.clone()) };
+            match waiter.wake.send(slot) {
+                Ok(()) => return,
+                // The waiter gave up; disarm the slot and try the next one
+                Err(mut slot) => {
+                    slot.scheduler = None;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// Queue POST then GET behind a held slot and return the order they ran in
+    async fn run_order(scheduling: Scheduling) -> Vec<&'static str> {
+        let scheduler = Arc::new(Scheduler::new(1, scheduling));
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let held = scheduler.acquire(&Method::GET).await;
+
+        let mut tasks = Vec::new();
+        for (method, name) in [(Method::POST, "POST"), (Method::GET, "GET")] {
+            let (scheduler, order) = (scheduler.clone(), order.clone());
+            tasks.push(tokio::spawn(async move {
+                let _slot = scheduler.acquire(&method).await;
+                order.lock().unwrap().push(name);
+            }));
+            // Make sure the waiters queue up in a known order
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        drop(held);
+        for task in tasks {
+            task.await.unwrap();
+        }
+        Arc::try_unwrap(order).unwrap().into_inner().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_fifo_runs_in_arrival_order() {
+        assert_eq!(run_order(Scheduling::Fifo).await, vec!["POST", "GET"]);
+    }
+
+    #[tokio::test]
+    async fn test_priority_runs_reads_first() {
+        assert_eq!(run_order(Scheduling::Priority).await, vec!["GET", "POST"]);
+    }
+
+    #[tokio::test]
+    async fn test_try_acquire_and_in_flight() {
+        let scheduler = Arc::new(Scheduler::new(2, Scheduling::Fifo));
+        let first = scheduler.try_acquire().unwrap();
+        let _second = scheduler.try_acquire().unwrap();
+        assert_eq!(scheduler.in_flight(), 2);
+        assert!(scheduler.try_acquire().is_none());
+
+        drop(first);
+        assert_eq!(scheduler.in_flight(), 1);
+        assert!(scheduler.try_acquire().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_abandoned_waiter_does_not_lose_the_slot() {
+        let scheduler = Arc::new(Scheduler::new(1, Scheduling::Fifo));
+        let held = scheduler.acquire(&Method::GET).await;
+
+        let gave_up =
+            tokio::time::timeout(Duration::from_millis(20), scheduler.acquire(&Method::GET)).await;
+        assert!(gave_up.is_err());
+
+        drop(held);
+        let next = tokio::time::timeout(Duration::from_secs(1), scheduler.acquire(&Method::GET));
+        assert!(next.await.is_ok());
+    }
+}
@@ -0,0 +1,145 @@
+use std::{
+    fs, io,
+    path::{Component, Path, PathBuf},
+};
+
+use crate::shell::{shell_escape, ShellType};
+
+/// File stems that name the method a script handles, e.g. `get.sh`
+const METHODS: [&str; 8] = ["get", "post", "put", "delete", "patch", "head", "options", "any"];
+
+/// Route spec for a script at `relative` to the scripts directory:
+/// `users/[id]/get.sh` becomes "GET /users/:id". None if the file name isn't a method.
+pub fn route_spec(relative: &Path) -> Option<String> {
+    let method = relative.file_stem()?.to_str()?.to_lowercase();
+    if !METHODS.contains(&method.as_str()) {
+        return None;
+    }
+
+    let mut path = String::new();
+    for component in relative.parent()?.components() {
+        let Component::Normal(segment) = component else {
+            return None;
+        };
+        let segment = segment.to_str()?;
+        path.push('/');
+        match segment.strip_prefix('[').and_then(|s| s.strip_suffix(']')) {
+            Some(param) => {
+                path.push(':');
+                path.push_str(param);
+            }
+            None => path.push_str(segment),
+        }
+    }
+    if path.is_empty() {
+        path.push('/');
+    }
+    Some(format!("{} {}", method.to_uppercase(), path))
+}
+
+/// Walk `dir` and return its scripts as (spec, command) pairs in `--route` order.
+/// Commands run the script with the route params as arguments, except under
+/// PowerShell and cmd, where scripts read them from PARAM_* instead.
+pub fn discover_routes(dir: &Path, shell: &ShellType) -> io::Result<Vec<String>> {
+    let mut scripts = Vec::new();
+    collect_files(dir, &mut scripts)?;
+    scripts.sort();
+
+    let mut routes = Vec::new();
+    for script in scripts {
+        let relative = script.strip_prefix(dir).unwrap_or(&script);
+        let Some(spec) = route_spec(relative) else {
+            continue;
+        };
+        let path = script.canonicalize()?;
+        let path = path.to_string_lossy();
+        let command = match shell {
+            ShellType::Fish => format!("'{}' $argv", shell_escape(&path, shell)),
+            ShellType::Powershell => format!("& '{}'", shell_escape(&path, shell)),
+            ShellType::Cmd => format!("\"{}\"", path),
+            ShellType::Bash | ShellType::Zsh | ShellType::Sh => {
+                format!("'{}' \"$@\"", shell_escape(&path, shell))
+            }
+        };
+        routes.push(spec);
+        routes.push(command);
+    }
+    Ok(routes)
+}
+
+/// Recursively collect the non-hidden files below `dir`
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with('.'))
+        {
+            continue;
+        }
+        if path.is_dir() {
+            collect_files(&path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_route_spec() {
+        assert_eq!(route_spec(Path::new("users/get.sh")).as_deref(), Some("GET /users"));
+        assert_eq!(route_spec(Path::new("POST.sh")).as_deref(), Some("POST /"));
+        assert_eq!(route_spec(Path::new("users/any")).as_deref(), Some("ANY /users"));
+        assert_eq!(route_spec(Path::new("users/helper.sh")), None);
+    }
+
+    #[test]
+    fn test_route_spec_param_directories() {
+        assert_eq!(
+            route_spec(Path::new("users/[id]/get.sh")).as_deref(),
+            Some("GET /users/:id")
+        );
+        assert_eq!(
+            route_spec(Path::new("orgs/[org]/repos/[repo]/delete.sh")).as_deref(),
+            Some("DELETE /orgs/:org/repos/:repo")
+        );
+    }
+
+    #[test]
+    fn test_discover_routes() {
+        let dir = std::env::temp_dir().join(format!("sherut-scripts-{}", std::process::id()));
+        fs::create_dir_all(dir.join("users/[id]")).unwrap();
+        fs::write(dir.join("users/get.sh"), "").unwrap();
+        fs::write(dir.join("users/[id]/get.sh"), "").unwrap();
+        fs::write(dir.join("users/[id]/.put.sh.swp"), "").unwrap();
+        fs::write(dir.join("README.md"), "").unwrap();
+
+        let routes = discover_routes(&dir, &ShellType::Bash).unwrap();
+        let root = dir.canonicalize().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(
+            routes,
+            vec![
+                "GET /users/:id".to_string(),
+                format!("'{}/users/[id]/get.sh' \"$@\"", root.display()),
+                "GET /users".to_string(),
+                format!("'{}/users/get.sh' \"$@\"", root.display()),
+            ]
+        );
+
+        let dir = std::env::temp_dir().join(format!("sherut-ps-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("get.ps1"), "").unwrap();
+        let routes = discover_routes(&dir, &ShellType::Powershell).unwrap();
+        let root = dir.canonicalize().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(routes[1], format!("& '{}/get.ps1'", root.display()));
+    }
+}
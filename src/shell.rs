@@ -2,12 +2,15 @@ use clap::ValueEnum;
 use std::{collections::HashMap, env};
 use tracing::warn;
 
-#[derive(Clone, Debug, ValueEnum, PartialEq)]
+#[derive(Clone, Debug, Default, ValueEnum, PartialEq, Eq, Hash)]
 pub enum ShellType {
+    #[default]
     Bash,
     Zsh,
     Fish,
     Sh,
+    Powershell,
+    Cmd,
 }
 
 impl ShellType {
@@ -17,88 +20,227 @@ impl ShellType {
             ShellType::Zsh => "zsh",
             ShellType::Fish => "fish",
             ShellType::Sh => "sh",
+            ShellType::Powershell => "powershell",
+            ShellType::Cmd => "cmd",
         }
     }
 
+    /// Arguments preceding the script in the shell's invocation
+    pub fn command_args(&self) -> &'static [&'static str] {
+        match self {
+            ShellType::Powershell => &["-NoProfile", "-NonInteractive", "-Command"],
+            ShellType::Cmd => &["/C"],
+            ShellType::Bash | ShellType::Zsh | ShellType::Fish | ShellType::Sh => &["-c"],
+        }
+    }
+
+    /// Whether arguments after the script reach it as positional parameters.
+    /// PowerShell and cmd would append them to the command text instead.
+    pub fn takes_positional_args(&self) -> bool {
+        !matches!(self, ShellType::Powershell | ShellType::Cmd)
+    }
+
     pub fn supports_assoc_arrays(&self) -> bool {
         matches!(self, ShellType::Bash | ShellType::Zsh)
     }
 }
 
-#[derive(Clone, Debug, ValueEnum, PartialEq)]
+#[derive(Clone, Debug, Default, ValueEnum, PartialEq)]
 pub enum HeaderFormat {
     /// Associative array (for bash/zsh)
+    #[default]
     Assoc,
     /// JSON string in HEADERS_JSON env var
     Json,
 }
 
+/// How `:name` placeholders in commands receive param values
+#[derive(Clone, Copy, Debug, Default, ValueEnum, PartialEq)]
+pub enum ParamMode {
+    /// Insert the shell-escaped value into the command text
+    #[default]
+    Substitute,
+    /// Replace placeholders with "$PARAM_name" references
+    Env,
+    /// Replace placeholders with positional argument references ("$1", "$2", ...)
+    Positional,
+}
+
+/// Double-quoted reference to the environment variable `name`
+pub fn var_ref(shell: &ShellType, name: &str) -> String {
+    match shell {
+        ShellType::Powershell => format!("\"$env:{}\"", name),
+        ShellType::Cmd => format!("\"%{}%\"", name),
+        ShellType::Bash | ShellType::Zsh | ShellType::Fish | ShellType::Sh => {
+            format!("\"${}\"", name)
+        }
+    }
+}
+
+/// Double-quoted reference to the positional argument at `position` (from 1).
+/// None for shells that don't take positional arguments.
+pub fn positional_ref(shell: &ShellType, position: usize) -> Option<String> {
+    match shell {
+        ShellType::Fish => Some(format!("\"$argv[{}]\"", position)),
+        ShellType::Bash | ShellType::Zsh | ShellType::Sh => Some(format!("\"${{{}}}\"", position)),
+        ShellType::Powershell | ShellType::Cmd => None,
+    }
+}
+
 /// Detect system default shell from $SHELL environment variable
 pub fn detect_default_shell() -> ShellType {
     if let Ok(shell_path) = env::var("SHELL") {
-        let shell_name = shell_path.rsplit('/').next().unwrap_or("");
-        match shell_name {
+        let shell_name = shell_path.rsplit(['/', '\\']).next().unwrap_or("");
+        match shell_name.trim_end_matches(".exe") {
             "bash" => ShellType::Bash,
             "zsh" => ShellType::Zsh,
             "fish" => ShellType::Fish,
             "sh" => ShellType::Sh,
+            "powershell" | "pwsh" => ShellType::Powershell,
+            "cmd" => ShellType::Cmd,
             _ => {
                 warn!("Unknown shell '{}', defaulting to bash", shell_name);
                 ShellType::Bash
             }
         }
+    } else if cfg!(windows) {
+        // Windows doesn't set $SHELL
+        ShellType::Powershell
     } else {
         warn!("$SHELL not set, defaulting to bash");
         ShellType::Bash
     }
 }
 
-/// Build the shell script with headers and query params in the appropriate format
+/// Escape a value for use inside a single-quoted string in the given shell
+pub fn shell_escape(value: &str, shell: &ShellType) -> String {
+    match shell {
+        // Fish honours \\ and \' inside single quotes
+        ShellType::Fish => value.replace('\\', "\\\\").replace('\'', "\\'"),
+        // POSIX single quotes are fully literal, so close, emit an escaped quote, reopen
+        ShellType::Bash | ShellType::Zsh | ShellType::Sh => value.replace('\'', "'\\''"),
+        // PowerShell single quotes are literal apart from a doubled quote; the
+        // typographic quotes ‘ ’ ‚ ‛ delimit strings too, so they are doubled as well
+        ShellType::Powershell => value
+            .chars()
+            .flat_map(|c| match c {
+                '\'' | '\u{2018}' | '\u{2019}' | '\u{201A}' | '\u{201B}' => vec![c, c],
+                _ => vec![c],
+            })
+            .collect(),
+        // cmd has no quoting that makes a value literal; caret-escape its
+        // metacharacters (% is still expanded, so prefer --param-mode env)
+        ShellType::Cmd => value
+            .chars()
+            .flat_map(|c| match c {
+                '^' | '&' | '|' | '<' | '>' | '(' | ')' | '"' => vec!['^', c],
+                _ => vec![c],
+            })
+            .collect(),
+    }
+}
+
+/// Define `name` as an array of `values` (bash/zsh arrays, fish lists,
+/// PowerShell arrays) on a line of its own. None for sh and cmd, which have no arrays.
+pub fn array_definition(shell: &ShellType, name: &str, values: &[&str]) -> Option<String> {
+    let items: Vec<String> = values
+        .iter()
+        .map(|value| format!("'{}'", shell_escape(value, shell)))
+        .collect();
+    match shell {
+        ShellType::Bash | ShellType::Zsh => Some(format!("{}=({})\n", name, items.join(" "))),
+        ShellType::Fish => Some(format!("set {} {}\n", name, items.join(" "))),
+        ShellType::Powershell => Some(format!("${} = @({})\n", name, items.join(", "))),
+        ShellType::Sh | ShellType::Cmd => None,
+    }
+}
+
+/// Whether `key` can be used unquoted as an associative array subscript.
+/// Other keys (e.g. with `]`, quotes or spaces) would break the `declare -A` line.
+pub fn is_assoc_key(key: &str) -> bool {
+    !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+/// Define the associative array `name` holding `entries` on a line of its own.
+/// None for shells without associative arrays. Keys must pass `is_assoc_key`.
+pub fn assoc_definition<'a>(
+    shell: &ShellType,
+    name: &str,
+    entries: impl IntoIterator<Item = (&'a str, &'a str)>,
+) -> Option<String> {
+    let defs: String = entries
+        .into_iter()
+        .map(|(key, value)| format!("[{}]='{}' ", key, shell_escape(value, shell)))
+        .collect();
+    match shell {
+        ShellType::Bash => Some(format!("declare -A {}=({})\n", name, defs)),
+        ShellType::Zsh => Some(format!("typeset -A {0}; {0}=({1})\n", name, defs)),
+        _ => None,
+    }
+}
+
+/// Build the shell script with headers and query params in the appropriate format.
+/// Each definition is a line of its own, so the command follows on a fresh line.
 pub fn build_shell_script(
     shell: &ShellType,
     header_format: &HeaderFormat,
     headers: &HashMap<String, String>,
     query_format: &HeaderFormat,
-    query_params: &HashMap<String, String>,
+    query_params: &[(String, String)],
     command: &str,
 ) -> String {
     let mut prefix = String::new();
 
     // Build HEADERS
     if *header_format == HeaderFormat::Assoc {
-        let mut header_defs = String::new();
-        for (key, value) in headers {
-            let safe_val = value.replace("'", "'\\''");
-            header_defs.push_str(&format!("[{}]='{}' ", key, safe_val));
-        }
-
-        match shell {
-            ShellType::Bash => {
-                prefix.push_str(&format!("declare -A HEADERS=({}); ", header_defs));
+        let entries = headers.iter().filter(|(key, _)| {
+            let valid = is_assoc_key(key);
+            if !valid {
+                warn!("Header {:?} is not a valid array key; only in HEADERS_JSON", key);
             }
-            ShellType::Zsh => {
-                prefix.push_str(&format!("typeset -A HEADERS; HEADERS=({}); ", header_defs));
-            }
-            _ => {}
+            valid
+        });
+        let entries = entries.map(|(key, value)| (key.as_str(), value.as_str()));
+        if let Some(definition) = assoc_definition(shell, "HEADERS", entries) {
+            prefix.push_str(&definition);
         }
     }
 
-    // Build QUERY
+    // Build QUERY; a repeated key keeps its last value there and all of its
+    // values in the array QUERY_<key> (assoc arrays can't hold duplicate keys)
     if *query_format == HeaderFormat::Assoc {
-        let mut query_defs = String::new();
+        let mut grouped: Vec<(&str, Vec<&str>)> = Vec::new();
         for (key, value) in query_params {
-            let safe_val = value.replace("'", "'\\''");
-            query_defs.push_str(&format!("[{}]='{}' ", key, safe_val));
+            match grouped.iter_mut().find(|(k, _)| k == key) {
+                Some((_, values)) => values.push(value),
+                None => grouped.push((key, vec![value])),
+            }
         }
 
-        match shell {
-            ShellType::Bash => {
-                prefix.push_str(&format!("declare -A QUERY=({}); ", query_defs));
+        let mut entries = Vec::new();
+        let mut arrays = String::new();
+        for (key, values) in &grouped {
+            if !is_assoc_key(key) {
+                warn!("Query key {:?} is not a valid array key; only in QUERY_JSON", key);
+                continue;
             }
-            ShellType::Zsh => {
-                prefix.push_str(&format!("typeset -A QUERY; QUERY=({}); ", query_defs));
+            entries.push((*key, values[values.len() - 1]));
+            // Keys that aren't valid variable names (e.g. with `-`) get no array
+            let is_name = key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+            if values.len() > 1
+                && is_name
+                && let Some(definition) = array_definition(shell, &format!("QUERY_{}", key), values)
+            {
+                arrays.push_str(&definition);
             }
-            _ => {}
+        }
+
+        if let Some(definition) = assoc_definition(shell, "QUERY", entries) {
+            prefix.push_str(&definition);
+            prefix.push_str(&arrays);
         }
     }
 
@@ -115,6 +257,27 @@ mod tests {
         assert_eq!(ShellType::Zsh.executable(), "zsh");
         assert_eq!(ShellType::Fish.executable(), "fish");
         assert_eq!(ShellType::Sh.executable(), "sh");
+        assert_eq!(ShellType::Powershell.executable(), "powershell");
+        assert_eq!(ShellType::Cmd.executable(), "cmd");
+    }
+
+    #[test]
+    fn test_command_args() {
+        assert_eq!(ShellType::Bash.command_args(), ["-c"]);
+        assert_eq!(ShellType::Cmd.command_args(), ["/C"]);
+        assert_eq!(ShellType::Powershell.command_args().last(), Some(&"-Command"));
+        assert!(ShellType::Fish.takes_positional_args());
+        assert!(!ShellType::Powershell.takes_positional_args());
+        assert!(!ShellType::Cmd.takes_positional_args());
+    }
+
+    #[test]
+    fn test_var_and_positional_refs() {
+        assert_eq!(var_ref(&ShellType::Bash, "PARAM_id"), "\"$PARAM_id\"");
+        assert_eq!(var_ref(&ShellType::Powershell, "PARAM_id"), "\"$env:PARAM_id\"");
+        assert_eq!(var_ref(&ShellType::Cmd, "PARAM_id"), "\"%PARAM_id%\"");
+        assert_eq!(positional_ref(&ShellType::Sh, 2).as_deref(), Some("\"${2}\""));
+        assert_eq!(positional_ref(&ShellType::Cmd, 2), None);
     }
 
     #[test]
@@ -123,12 +286,89 @@ mod tests {
         assert!(ShellType::Zsh.supports_assoc_arrays());
         assert!(!ShellType::Fish.supports_assoc_arrays());
         assert!(!ShellType::Sh.supports_assoc_arrays());
+        assert!(!ShellType::Powershell.supports_assoc_arrays());
+        assert!(!ShellType::Cmd.supports_assoc_arrays());
+    }
+
+    #[test]
+    fn test_array_definition() {
+        let values = ["a", "it's"];
+        assert_eq!(
+            array_definition(&ShellType::Bash, "XS", &values).unwrap(),
+            "XS=('a' 'it'\\''s')\n"
+        );
+        assert_eq!(
+            array_definition(&ShellType::Fish, "XS", &values).unwrap(),
+            "set XS 'a' 'it\\'s'\n"
+        );
+        assert_eq!(
+            array_definition(&ShellType::Powershell, "XS", &values).unwrap(),
+            "$XS = @('a', 'it''s')\n"
+        );
+        assert_eq!(array_definition(&ShellType::Sh, "XS", &values), None);
+        assert_eq!(array_definition(&ShellType::Cmd, "XS", &values), None);
+    }
+
+    const TRICKY_VALUES: [&str; 5] =
+        ["it's", "back\\slash", "a\\'b", "line\nbreak", "$(id) `id` \"x\""];
+
+    /// Run `printf %s '<escaped>'` in the shell and return what it printed
+    fn round_trip(shell: &ShellType, value: &str) -> String {
+        let script = format!("printf %s '{}'", shell_escape(value, shell));
+        let output = std::process::Command::new(shell.executable())
+            .arg("-c")
+            .arg(script)
+            .output()
+            .unwrap();
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    #[test]
+    fn test_shell_escape_bash() {
+        for value in TRICKY_VALUES {
+            assert_eq!(round_trip(&ShellType::Bash, value), value);
+        }
+    }
+
+    #[test]
+    fn test_shell_escape_sh() {
+        for value in TRICKY_VALUES {
+            assert_eq!(round_trip(&ShellType::Sh, value), value);
+        }
+    }
+
+    #[test]
+    fn test_shell_escape_zsh() {
+        assert_eq!(shell_escape("it's", &ShellType::Zsh), "it'\\''s");
+        assert_eq!(shell_escape("back\\slash\n", &ShellType::Zsh), "back\\slash\n");
+    }
+
+    #[test]
+    fn test_shell_escape_fish() {
+        assert_eq!(shell_escape("it's", &ShellType::Fish), "it\\'s");
+        assert_eq!(shell_escape("a\\'b", &ShellType::Fish), "a\\\\\\'b");
+        assert_eq!(shell_escape("line\nbreak", &ShellType::Fish), "line\nbreak");
+    }
+
+    #[test]
+    fn test_shell_escape_windows() {
+        assert_eq!(shell_escape("it's", &ShellType::Powershell), "it''s");
+        assert_eq!(shell_escape("$(id) `n", &ShellType::Powershell), "$(id) `n");
+        assert_eq!(
+            shell_escape("a\u{2019}; Remove-Item x", &ShellType::Powershell),
+            "a\u{2019}\u{2019}; Remove-Item x"
+        );
+        assert_eq!(
+            shell_escape("\u{2018}\u{201A}\u{201B}", &ShellType::Powershell),
+            "\u{2018}\u{2018}\u{201A}\u{201A}\u{201B}\u{201B}"
+        );
+        assert_eq!(shell_escape("a & b > \"c\"", &ShellType::Cmd), "a ^& b ^> ^\"c^\"");
     }
 
     #[test]
     fn test_build_shell_script_json_format() {
         let headers = HashMap::new();
-        let query = HashMap::new();
+        let query = Vec::new();
         let script = build_shell_script(
             &ShellType::Bash,
             &HeaderFormat::Json,
@@ -145,7 +385,7 @@ mod tests {
         let mut headers = HashMap::new();
         headers.insert("content-type".to_string(), "application/json".to_string());
 
-        let query = HashMap::new();
+        let query = Vec::new();
         let script = build_shell_script(
             &ShellType::Bash,
             &HeaderFormat::Assoc,
@@ -165,7 +405,7 @@ mod tests {
         let mut headers = HashMap::new();
         headers.insert("x-api-key".to_string(), "secret".to_string());
 
-        let query = HashMap::new();
+        let query = Vec::new();
         let script = build_shell_script(
             &ShellType::Zsh,
             &HeaderFormat::Assoc,
@@ -183,9 +423,10 @@ mod tests {
     #[test]
     fn test_build_shell_script_query_assoc() {
         let headers = HashMap::new();
-        let mut query = HashMap::new();
-        query.insert("page".to_string(), "1".to_string());
-        query.insert("limit".to_string(), "10".to_string());
+        let query = vec![
+            ("page".to_string(), "1".to_string()),
+            ("limit".to_string(), "10".to_string()),
+        ];
 
         let script = build_shell_script(
             &ShellType::Bash,
@@ -206,7 +447,7 @@ mod tests {
         let mut headers = HashMap::new();
         headers.insert("value".to_string(), "it's a test".to_string());
 
-        let query = HashMap::new();
+        let query = Vec::new();
         let script = build_shell_script(
             &ShellType::Bash,
             &HeaderFormat::Assoc,
@@ -219,12 +460,121 @@ mod tests {
         assert!(script.contains("it'\\''s a test"));
     }
 
+    #[test]
+    fn test_build_shell_script_skips_invalid_assoc_keys() {
+        let headers = HashMap::new();
+        let query = vec![
+            ("a]b".to_string(), "x".to_string()),
+            ("it's".to_string(), "y".to_string()),
+            ("page".to_string(), "2".to_string()),
+        ];
+        let script = build_shell_script(
+            &ShellType::Bash,
+            &HeaderFormat::Json,
+            &headers,
+            &HeaderFormat::Assoc,
+            &query,
+            "echo ${#QUERY[@]} ${QUERY[page]}",
+        );
+
+        let output = std::process::Command::new("bash")
+            .arg("-c")
+            .arg(&script)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "invalid script: {}", script);
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "1 2\n");
+    }
+
+    #[test]
+    fn test_build_shell_script_repeated_query_keys() {
+        let query = vec![
+            ("tag".to_string(), "a".to_string()),
+            ("page".to_string(), "1".to_string()),
+            ("tag".to_string(), "b c".to_string()),
+        ];
+        let script = build_shell_script(
+            &ShellType::Bash,
+            &HeaderFormat::Json,
+            &HashMap::new(),
+            &HeaderFormat::Assoc,
+            &query,
+            "echo \"${QUERY[tag]}|${#QUERY_tag[@]}|${QUERY_tag[0]}|${QUERY_tag[1]}\"",
+        );
+
+        let output = std::process::Command::new("bash")
+            .arg("-c")
+            .arg(&script)
+            .output()
+            .unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "b c|2|a|b c\n");
+    }
+
+    #[test]
+    fn test_build_shell_script_multiline_command() {
+        let mut headers = HashMap::new();
+        headers.insert("x-user".to_string(), "ann".to_string());
+        // A heredoc right at the start must survive the prefix on its first line
+        let command = "cat <<EOF\nuser=${HEADERS[x-user]}\nEOF\necho \"lines: $LINENO\"";
+        let script = build_shell_script(
+            &ShellType::Bash,
+            &HeaderFormat::Assoc,
+            &headers,
+            &HeaderFormat::Assoc,
+            &[],
+            command,
+        );
+
+        let output = std::process::Command::new("bash")
+            .arg("-c")
+            .arg(&script)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "invalid script: {}", script);
+        // The HEADERS and QUERY definitions take the first two lines
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "user=ann\nlines: 6\n");
+    }
+
+    #[test]
+    fn test_build_shell_script_command_starting_with_comment() {
+        let mut headers = HashMap::new();
+        headers.insert("x-user".to_string(), "ann".to_string());
+        let build = |shell| {
+            build_shell_script(
+                &shell,
+                &HeaderFormat::Assoc,
+                &headers,
+                &HeaderFormat::Json,
+                &[],
+                "#comment; echo hidden\necho ${HEADERS[x-user]}",
+            )
+        };
+        assert!(build(ShellType::Zsh).contains(")\n#comment"));
+
+        let script = build(ShellType::Bash);
+        let output = std::process::Command::new("bash")
+            .arg("-c")
+            .arg(&script)
+            .output()
+            .unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "ann\n");
+    }
+
+    #[test]
+    fn test_is_assoc_key() {
+        assert!(is_assoc_key("content-type"));
+        assert!(is_assoc_key("user_id.v2"));
+        assert!(!is_assoc_key("a]b"));
+        assert!(!is_assoc_key("a b"));
+        assert!(!is_assoc_key(""));
+    }
+
     #[test]
     fn test_build_shell_script_fish_ignores_assoc() {
         let mut headers = HashMap::new();
         headers.insert("key".to_string(), "value".to_string());
 
-        let query = HashMap::new();
+        let query = Vec::new();
         let script = build_shell_script(
             &ShellType::Fish,
             &HeaderFormat::Assoc,
@@ -237,4 +587,23 @@ mod tests {
         // Fish doesn't support assoc arrays, so prefix should be empty
         assert_eq!(script, "echo hello");
     }
+
+    #[test]
+    fn test_build_shell_script_windows_shells_ignore_assoc() {
+        let mut headers = HashMap::new();
+        headers.insert("key".to_string(), "value".to_string());
+        let query = vec![("tag".to_string(), "a".to_string())];
+
+        for shell in [ShellType::Powershell, ShellType::Cmd] {
+            let script = build_shell_script(
+                &shell,
+                &HeaderFormat::Assoc,
+                &headers,
+                &HeaderFormat::Assoc,
+                &query,
+                "echo hello",
+            );
+            assert_eq!(script, "echo hello");
+        }
+    }
 }
@@ -1,12 +1,219 @@
-use std::collections::HashMap;
+use axum::http::{HeaderValue, StatusCode};
+use encoding_rs::Encoding;
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
 
-use crate::shell::{HeaderFormat, ShellType};
+use crate::circuit::CircuitBreaker;
+use crate::condition::Condition;
+use crate::cli::{LogLevel, QueryOverflow};
+use crate::health::{HealthCheck, Probe};
+use crate::idle::IdleTracker;
+use crate::jq::JqFilter;
+use crate::maintenance::Maintenance;
+use crate::nonce::NonceStore;
+use crate::rate_limit::{Rate, RateLimiter, Throttle};
+use crate::runner::{BodyFormat, StdinEncoding};
+use crate::validate::Validator;
+use crate::scheduler::{Overload, Scheduler};
+use crate::shell::{HeaderFormat, ParamMode, ShellType};
+use crate::static_response::StaticResponse;
+use crate::webhook::Webhook;
 
-#[derive(Clone)]
+/// Settings that apply to a single route
+#[derive(Clone, Debug, Default)]
+pub struct RouteConfig {
+    /// Overrides the global log level for events emitted while handling the route
+    pub log_level: Option<LogLevel>,
+    /// Accepted request content types, overriding `AppState::allowed_content_types`
+    pub consumes: Option<Vec<String>>,
+    /// Location template added to 201 responses, e.g. "/items/:id"
+    pub created_location: Option<String>,
+    /// HTML template whose `{{body}}` placeholder receives the command output
+    pub wrap_html: Option<String>,
+    /// How the request body is written to the command's stdin
+    pub stdin_encoding: StdinEncoding,
+    /// Delimiter that splits the output into a JSON string array, bypassing magic prefixes
+    pub split_delimiter: Option<String>,
+    /// Reject requests with an empty body with 400
+    pub require_body: bool,
+    /// Reject requests carrying a body with 400
+    pub forbid_body: bool,
+    /// Header name -> value template with `:param`, `:query.x` and `:header.x` placeholders
+    pub response_headers: Vec<(String, String)>,
+    /// Respond with each output line as a server-sent event
+    pub as_sse: bool,
+    /// jq expression applied to the command's JSON output
+    pub jq: Option<Arc<JqFilter>>,
+    /// Exit codes treated as success instead of only 0
+    pub success_codes: Option<Vec<i32>>,
+    /// Largest accepted request body in bytes, overriding the global limit
+    pub max_body_size: Option<usize>,
+    /// Announce the route as deprecated in a Deprecation header
+    pub deprecated: bool,
+    /// Sunset header value for deprecated routes
+    pub sunset: Option<HeaderValue>,
+    /// Dispatch on the JSON body's `method` field to `AppState::rpc_commands`
+    pub rpc: bool,
+    /// Only run the route's command when the request matches
+    pub when: Option<Condition>,
+    /// Command run instead when `when` doesn't match; without one the route answers 503
+    pub otherwise: Option<String>,
+    /// How often the route's command may run
+    pub rate_limit: Option<Rate>,
+    /// Directory the route's command runs in, overriding `AppState::workdir`
+    pub workdir: Option<PathBuf>,
+    /// Charset text output is transcoded to and declared in the Content-Type
+    pub charset: Option<Charset>,
+}
+
+/// Charset of a route's text responses
+#[derive(Clone, Debug)]
+pub struct Charset {
+    /// Name as configured, for the Content-Type's charset parameter
+    pub label: String,
+    pub encoding: &'static Encoding,
+}
+
+/// When the server started, as wall-clock time for reporting and a monotonic
+/// instant for measuring uptime
+#[derive(Clone, Copy, Debug)]
+pub struct StartTime {
+    pub wall: SystemTime,
+    pub instant: Instant,
+}
+
+impl Default for StartTime {
+    fn default() -> Self {
+        StartTime {
+            wall: SystemTime::now(),
+            instant: Instant::now(),
+        }
+    }
+}
+
+#[derive(Clone, Default)]
 pub struct AppState {
     /// Key is "METHOD /path", value is command
     pub commands: HashMap<String, String>,
+    /// Key is "METHOD /path", same as `commands`
+    pub route_configs: HashMap<String, RouteConfig>,
+    pub log_level: LogLevel,
     pub shell: ShellType,
     pub header_format: HeaderFormat,
     pub query_format: HeaderFormat,
+    pub cookie_format: HeaderFormat,
+    /// How `:name` placeholders in commands receive param values
+    pub param_mode: ParamMode,
+    /// Directory commands run in; None keeps sherut's working directory
+    pub workdir: Option<PathBuf>,
+    /// Shells a request may pick with the X-Shell header instead of `shell`
+    pub allowed_request_shells: Vec<ShellType>,
+    /// Echo each request's rendered script in an X-Debug-Script header
+    pub debug_echo_script: bool,
+    /// Whether the body goes to stdin, REQUEST_BODY or both
+    pub body_format: BodyFormat,
+    /// Largest body (in bytes, after encoding) set as REQUEST_BODY
+    pub max_body_env: Option<usize>,
+    /// Cookie name -> environment variable its value is exported as
+    pub cookie_env: Vec<(String, String)>,
+    /// Whether the listener terminates TLS, for REQUEST_SCHEME
+    pub tls: bool,
+    /// Take REQUEST_SCHEME from X-Forwarded-Proto
+    pub trust_proxy: bool,
+    /// Answer OPTIONS with 204 + Allow unless an OPTIONS command is registered
+    pub auto_options: bool,
+    /// Hide hop-by-hop headers from commands
+    pub strip_hop_by_hop: bool,
+    /// Checks applied to path and query params before the command runs
+    pub validators: Vec<(String, Validator)>,
+    /// Status code -> HTML page returned instead of an empty body
+    pub error_pages: HashMap<u16, String>,
+    /// Replace bodies with the error page even when the command produced one
+    pub error_page_always: bool,
+    /// Accepted request content types for all routes; empty accepts anything
+    pub allowed_content_types: Vec<String>,
+    /// Headers commands may set with `@header:`; empty allows any
+    pub allowed_response_headers: Vec<String>,
+    /// Headers commands may never set with `@header:`
+    pub blocked_response_headers: Vec<String>,
+    /// HTTP method -> Content-Type used when the command doesn't set one
+    pub method_content_types: HashMap<String, String>,
+    /// Insert output into `wrap_html` templates without HTML-escaping
+    pub no_escape: bool,
+    /// Lowercase hosts a request's Host header must name; empty allows any
+    pub allowed_hosts: Vec<String>,
+    /// Longest accepted request URI in bytes; 0 disables the check
+    pub max_uri_length: usize,
+    /// Most query parameters a request may carry
+    pub max_query_params: Option<usize>,
+    /// Whether requests over `max_query_params` are rejected or truncated
+    pub query_overflow: QueryOverflow,
+    /// How many `@header:` lines a command's output may set
+    pub max_response_headers: Option<usize>,
+    /// Run route commands as this user through sudo
+    pub sudo_user: Option<String>,
+    /// Largest accepted request body in bytes for routes without their own limit;
+    /// None accepts any size
+    pub max_body_size: Option<usize>,
+    /// `cgroup.procs` file commands are placed into
+    #[cfg(all(target_os = "linux", feature = "cgroup"))]
+    pub cgroup_procs: Option<std::path::PathBuf>,
+    /// Command the request body is piped through before reaching the route's command
+    pub request_filter: Option<String>,
+    /// Commands running longer are killed and answered with 504
+    pub command_timeout: Option<Duration>,
+    /// Slots for running commands, when concurrency is limited
+    pub concurrency: Option<Arc<Scheduler>>,
+    /// Slots per shell, limiting how many commands run in that shell at once
+    pub shell_concurrency: HashMap<ShellType, Arc<Scheduler>>,
+    /// How long a request may wait for a concurrency slot before getting 503
+    pub queue_timeout: Option<Duration>,
+    /// Whether requests wait for a concurrency slot or get 503 right away
+    pub on_overload: Overload,
+    /// Signal numbers that count as success when the command produced output
+    pub success_signals: Vec<i32>,
+    /// Status for successful commands without output (and without `@status:`)
+    pub empty_status: Option<StatusCode>,
+    /// Count whitespace-only output as empty, responding 204 unless `empty_status` is set
+    pub treat_whitespace_as_empty: bool,
+    /// Base added to exit codes 1-99 of failed commands to pick the response status
+    pub status_from_exit: Option<u16>,
+    /// Cookie whose value state-changing requests must repeat in X-CSRF-Token
+    pub csrf_cookie: Option<String>,
+    /// Seen nonces, when requests must carry a fresh one
+    pub nonces: Option<Arc<NonceStore>>,
+    /// Log a warning when a command succeeds with an empty 200 body
+    pub warn_empty_response: bool,
+    /// Report queue, spawn, exec and body durations in a Server-Timing header
+    pub server_timing: bool,
+    /// Reject bodies that don't match their Content-MD5 / X-Checksum-SHA256 header
+    pub verify_checksum: bool,
+    /// Opens per route after repeated command failures
+    pub circuit_breaker: Option<Arc<CircuitBreaker>>,
+    /// Buckets for routes with a `RouteConfig::rate_limit`
+    pub rate_limiter: Arc<RateLimiter>,
+    /// Response for requests over their route's rate limit
+    pub throttle: Throttle,
+    /// Route key ("METHOD /path") -> fixed response served without a command
+    pub static_responses: HashMap<String, StaticResponse>,
+    /// While on, command routes answer 503; toggled by SIGUSR1
+    pub maintenance: Arc<Maintenance>,
+    /// Called with a summary of each request it selects
+    pub webhook: Option<Webhook>,
+    /// Command backing /healthz, if configured
+    pub health_check: Option<Arc<HealthCheck>>,
+    /// Path of the built-in health check, replacing /healthz
+    pub health_path: Option<String>,
+    /// JSON-RPC method -> command template for routes in RPC mode
+    pub rpc_commands: HashMap<String, String>,
+    /// Dependencies probed in the background, reported by the health check
+    pub probes: Vec<Arc<Probe>>,
+    /// Exposed to commands as SERVER_START_TIME and SERVER_UPTIME_SECONDS
+    pub started: StartTime,
+    /// Last-request tracking for --idle-shutdown-secs
+    pub idle: Option<Arc<IdleTracker>>,
 }
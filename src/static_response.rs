@@ -0,0 +1,87 @@
+use axum::{
+    http::{header, HeaderValue, Method, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{any, on, MethodFilter, MethodRouter},
+};
+
+/// Fixed response of a route served without running a command
+#[derive(Clone, Debug)]
+pub struct StaticResponse {
+    pub status: StatusCode,
+    pub content_type: HeaderValue,
+    pub body: String,
+}
+
+impl Default for StaticResponse {
+    fn default() -> Self {
+        StaticResponse {
+            status: StatusCode::OK,
+            content_type: HeaderValue::from_static("text/plain; charset=utf-8"),
+            body: String::new(),
+        }
+    }
+}
+
+impl IntoResponse for StaticResponse {
+    fn into_response(self) -> Response {
+        (self.status, [(header::CONTENT_TYPE, self.content_type)], self.body).into_response()
+    }
+}
+
+/// Method router answering `method` (or every method for "ANY") with `response`
+pub fn static_route(method: &str, response: StaticResponse) -> MethodRouter {
+    let respond = move || {
+        let response = response.clone();
+        async move { response }
+    };
+    let filter = Method::from_bytes(method.as_bytes())
+        .ok()
+        .and_then(|method| MethodFilter::try_from(method).ok());
+    match filter {
+        Some(filter) => on(filter, respond),
+        None => any(respond),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::routes::build_router;
+    use crate::state::AppState;
+    use axum::{body::Body, http::Request};
+    use std::sync::Arc;
+    use tower::ServiceExt;
+
+    async fn send(state: AppState, method: &str, uri: &str) -> Response {
+        let request = Request::builder().method(method).uri(uri).body(Body::empty()).unwrap();
+        build_router(&[], Arc::new(state)).unwrap().oneshot(request).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_static_response_body_and_content_type() {
+        let mut state = AppState::default();
+        let ping = StaticResponse { body: "pong".to_string(), ..Default::default() };
+        state.static_responses.insert("GET /ping".to_string(), ping);
+        let teapot = StaticResponse {
+            status: StatusCode::IM_A_TEAPOT,
+            content_type: HeaderValue::from_static("application/json"),
+            body: "{\"short\":true}".to_string(),
+        };
+        state.static_responses.insert("ANY /tea".to_string(), teapot);
+
+        let response = send(state.clone(), "GET", "/ping").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["content-type"], "text/plain; charset=utf-8");
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"pong");
+
+        let response = send(state.clone(), "POST", "/ping").await;
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+
+        let response = send(state, "DELETE", "/tea").await;
+        assert_eq!(response.status(), StatusCode::IM_A_TEAPOT);
+        assert_eq!(response.headers()["content-type"], "application/json");
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"{\"short\":true}");
+    }
+}
@@ -0,0 +1,57 @@
+use std::time::{Duration, Instant};
+
+/// Durations of the phases of a request, reported in a Server-Timing header
+#[derive(Debug, Default)]
+pub struct ServerTiming {
+    phases: Vec<(&'static str, Duration)>,
+    /// When the command finished and output processing began
+    processing_started: Option<Instant>,
+}
+
+impl ServerTiming {
+    pub fn record(&mut self, phase: &'static str, duration: Duration) {
+        self.phases.push((phase, duration));
+    }
+
+    /// Mark the end of command execution; the time from here on counts as "body"
+    pub fn start_processing(&mut self) {
+        self.processing_started = Some(Instant::now());
+    }
+
+    /// Close the body phase (if the command ran) and render the header value,
+    /// e.g. "queue;dur=0.02, spawn;dur=1.10, exec;dur=5.31, body;dur=0.08"
+    pub fn finish(mut self) -> String {
+        if let Some(started) = self.processing_started.take() {
+            self.record("body", started.elapsed());
+        }
+        self.phases
+            .iter()
+            .map(|(phase, duration)| {
+                format!("{};dur={:.2}", phase, duration.as_secs_f64() * 1000.0)
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header_value_in_milliseconds() {
+        let mut timing = ServerTiming::default();
+        timing.record("queue", Duration::from_micros(250));
+        timing.record("exec", Duration::from_millis(12));
+        assert_eq!(timing.finish(), "queue;dur=0.25, exec;dur=12.00");
+    }
+
+    #[test]
+    fn test_body_phase_only_after_processing_started() {
+        assert_eq!(ServerTiming::default().finish(), "");
+
+        let mut timing = ServerTiming::default();
+        timing.start_processing();
+        assert!(timing.finish().starts_with("body;dur="));
+    }
+}
@@ -0,0 +1,72 @@
+use axum::Router;
+use axum_server::{tls_rustls::RustlsConfig, Handle};
+use std::{future::Future, io, net::SocketAddr};
+use tokio::net::TcpListener;
+
+/// Serve `app` over TLS on an already bound listener. Once `shutdown` resolves,
+/// no new connections are accepted and in-flight requests are drained.
+pub async fn serve_tls(
+    listener: TcpListener,
+    config: RustlsConfig,
+    app: Router,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> io::Result<()> {
+    let handle: Handle<SocketAddr> = Handle::new();
+    let trigger = handle.clone();
+    tokio::spawn(async move {
+        shutdown.await;
+        trigger.graceful_shutdown(None);
+    });
+    axum_server::from_tcp_rustls(listener.into_std()?, config)?
+        .handle(handle)
+        .serve(app.into_make_service())
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::routing::get;
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::{
+        rustls::{pki_types::ServerName, ClientConfig, RootCertStore},
+        TlsConnector,
+    };
+
+    #[tokio::test]
+    async fn test_tls_handshake_with_self_signed_cert() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let dir = std::env::temp_dir().join(format!("sherut-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("cert.pem"), cert.cert.pem()).unwrap();
+        std::fs::write(dir.join("key.pem"), cert.signing_key.serialize_pem()).unwrap();
+        let config = RustlsConfig::from_pem_file(dir.join("cert.pem"), dir.join("key.pem")).await;
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = Router::new().route("/", get(|| async { "secure" }));
+        tokio::spawn(serve_tls(listener, config.unwrap(), app, std::future::pending()));
+
+        let mut roots = RootCertStore::empty();
+        roots.add(cert.cert.der().clone()).unwrap();
+        let client = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let server_name = ServerName::try_from("localhost").unwrap();
+        let mut tls = TlsConnector::from(Arc::new(client))
+            .connect(server_name, stream)
+            .await
+            .unwrap();
+
+        tls.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        tls.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        assert!(response.ends_with("secure"));
+    }
+}
@@ -0,0 +1,138 @@
+use std::collections::HashMap;
+
+/// Built-in checks for path and query param values, declared with --validate
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Validator {
+    Uuid,
+    Email,
+    Int,
+    Alnum,
+}
+
+impl Validator {
+    fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "uuid" => Some(Validator::Uuid),
+            "email" => Some(Validator::Email),
+            "int" => Some(Validator::Int),
+            "alnum" => Some(Validator::Alnum),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Validator::Uuid => "uuid",
+            Validator::Email => "email",
+            Validator::Int => "int",
+            Validator::Alnum => "alnum",
+        }
+    }
+
+    pub fn check(&self, value: &str) -> bool {
+        match self {
+            // Hyphenated form only, e.g. 123e4567-e89b-12d3-a456-426614174000
+            Validator::Uuid => {
+                value.len() == 36
+                    && value.char_indices().all(|(i, c)| match i {
+                        8 | 13 | 18 | 23 => c == '-',
+                        _ => c.is_ascii_hexdigit(),
+                    })
+            }
+            Validator::Email => value.split_once('@').is_some_and(|(local, domain)| {
+                !local.is_empty()
+                    && !domain.contains('@')
+                    && domain.contains('.')
+                    && domain.split('.').all(|label| !label.is_empty())
+                    && !value.chars().any(char::is_whitespace)
+            }),
+            Validator::Int => value.parse::<i64>().is_ok(),
+            Validator::Alnum => {
+                !value.is_empty() && value.chars().all(|c| c.is_ascii_alphanumeric())
+            }
+        }
+    }
+}
+
+/// Parse a --validate value like "id:uuid" into param name and validator
+pub fn parse_validator(spec: &str) -> Result<(String, Validator), String> {
+    let (param, name) = spec
+        .split_once(':')
+        .map(|(p, n)| (p.trim(), n.trim()))
+        .filter(|(p, _)| !p.is_empty())
+        .ok_or_else(|| format!("expected PARAM:TYPE, got '{}'", spec))?;
+    let validator = Validator::from_name(name).ok_or_else(|| {
+        format!("unknown validator '{}' (expected one of uuid, email, int, alnum)", name)
+    })?;
+    Ok((param.to_string(), validator))
+}
+
+/// Check path and query params against their validators, naming the first that fails
+pub fn validate_params(
+    validators: &[(String, Validator)],
+    params: &HashMap<String, String>,
+    query_params: &HashMap<String, String>,
+) -> Result<(), String> {
+    for (param, validator) in validators {
+        for value in [params.get(param), query_params.get(param)].into_iter().flatten() {
+            if !validator.check(value) {
+                return Err(format!(
+                    "Invalid parameter '{}': expected {}",
+                    param,
+                    validator.name()
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_uuid() {
+        assert!(Validator::Uuid.check("123e4567-e89b-12d3-a456-426614174000"));
+        assert!(!Validator::Uuid.check("123e4567e89b12d3a456426614174000"));
+        assert!(!Validator::Uuid.check("123e4567-e89b-12d3-a456-42661417400z"));
+        assert!(!Validator::Uuid.check("42"));
+    }
+
+    #[test]
+    fn test_int() {
+        assert!(Validator::Int.check("42"));
+        assert!(Validator::Int.check("-7"));
+        assert!(!Validator::Int.check("4.2"));
+        assert!(!Validator::Int.check("abc"));
+        assert!(!Validator::Int.check(""));
+    }
+
+    #[test]
+    fn test_email_and_alnum() {
+        assert!(Validator::Email.check("a.b@example.com"));
+        assert!(!Validator::Email.check("a@localhost"));
+        assert!(!Validator::Email.check("a@b@example.com"));
+        assert!(!Validator::Email.check("a b@example.com"));
+        assert!(Validator::Alnum.check("abc123"));
+        assert!(!Validator::Alnum.check("abc-123"));
+    }
+
+    #[test]
+    fn test_parse_validator() {
+        assert_eq!(parse_validator("id:uuid"), Ok(("id".to_string(), Validator::Uuid)));
+        assert_eq!(parse_validator(" n : INT "), Ok(("n".to_string(), Validator::Int)));
+        assert!(parse_validator("id").is_err());
+        assert!(parse_validator("id:date").is_err());
+    }
+
+    #[test]
+    fn test_validate_params_checks_query_too() {
+        let validators = vec![("id".to_string(), Validator::Int)];
+        let params = HashMap::from([("id".to_string(), "42".to_string())]);
+        let query = HashMap::from([("id".to_string(), "x".to_string())]);
+        assert!(validate_params(&validators, &params, &HashMap::new()).is_ok());
+        let err = validate_params(&validators, &HashMap::new(), &query).unwrap_err();
+        assert_eq!(err, "Invalid parameter 'id': expected int");
+    }
+}
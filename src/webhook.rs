@@ -0,0 +1,98 @@
+use clap::ValueEnum;
+use reqwest::{Client, Url};
+use serde::Serialize;
+use std::time::Duration;
+use tracing::warn;
+
+/// How long a webhook call may take before it is abandoned
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Which responses --webhook-url is called for
+#[derive(Clone, Copy, Debug, Default, ValueEnum, PartialEq)]
+pub enum WebhookOn {
+    /// Every response
+    #[default]
+    All,
+    /// Responses with a status below 400
+    Success,
+    /// Responses with a status of 400 or above
+    Error,
+}
+
+impl WebhookOn {
+    fn matches(self, status: u16) -> bool {
+        match self {
+            WebhookOn::All => true,
+            WebhookOn::Success => status < 400,
+            WebhookOn::Error => status >= 400,
+        }
+    }
+}
+
+/// Summary of a request POSTed to the webhook as JSON
+#[derive(Debug, Serialize)]
+pub struct Summary<'a> {
+    pub route: &'a str,
+    pub method: &'a str,
+    pub path: &'a str,
+    pub status: u16,
+    pub duration_ms: f64,
+    /// None when no command ran
+    pub exit_code: Option<i32>,
+}
+
+/// URL that receives a `Summary` after each request `on` selects
+#[derive(Clone, Debug)]
+pub struct Webhook {
+    url: Url,
+    on: WebhookOn,
+    client: Client,
+}
+
+impl Webhook {
+    pub fn new(url: &str, on: WebhookOn) -> Result<Self, String> {
+        let url = Url::parse(url).map_err(|e| format!("invalid URL '{}': {}", url, e))?;
+        // reqwest's TLS needs a process-wide rustls provider; use the one serving TLS uses
+        let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+        let client = Client::builder()
+            .timeout(WEBHOOK_TIMEOUT)
+            .build()
+            .map_err(|e| e.to_string())?;
+        Ok(Webhook { url, on, client })
+    }
+
+    /// POST `summary` in the background if its status is selected, so the
+    /// response isn't held up by the webhook. Failures are only logged.
+    pub fn notify(&self, summary: &Summary) {
+        if !self.on.matches(summary.status) {
+            return;
+        }
+        let request = self.client.post(self.url.clone()).json(summary);
+        let url = self.url.clone();
+        tokio::spawn(async move {
+            if let Err(e) = request.send().await.and_then(|response| response.error_for_status()) {
+                warn!("Webhook {} failed: {}", url, e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_webhook_on_matches() {
+        assert!(WebhookOn::All.matches(200));
+        assert!(WebhookOn::Success.matches(302));
+        assert!(!WebhookOn::Success.matches(404));
+        assert!(WebhookOn::Error.matches(400));
+        assert!(!WebhookOn::Error.matches(200));
+    }
+
+    #[test]
+    fn test_invalid_url() {
+        let err = Webhook::new("not a url", WebhookOn::All).unwrap_err();
+        assert!(err.starts_with("invalid URL 'not a url'"), "{}", err);
+    }
+}